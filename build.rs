@@ -0,0 +1,4 @@
+// Embedded migrations are only picked up on rebuild if cargo knows about them.
+fn main() {
+    println!("cargo:rerun-if-changed=migrations");
+}
@@ -0,0 +1,37 @@
+-- Schema as it was created by hand before migrations were introduced. Every
+-- statement is idempotent, so existing installations adopt it as is.
+
+CREATE TABLE IF NOT EXISTS `alive_hosts` (
+  `id` int(11) NOT NULL AUTO_INCREMENT,
+  `macaddr` varchar(17) DEFAULT NULL,
+  `iplong` int(11) DEFAULT NULL,
+  `erfda` datetime DEFAULT NULL,
+  PRIMARY KEY (`id`),
+  KEY `macaddr` (`macaddr`),
+  KEY `iplong` (`iplong`),
+  KEY `erfda` (`erfda`)
+) ENGINE=MyISAM DEFAULT CHARSET=latin1;
+
+CREATE TABLE IF NOT EXISTS `mac_to_nick` (
+  `id` int(11) NOT NULL AUTO_INCREMENT,
+  `macaddr` varchar(17) DEFAULT NULL,
+  `nickname` varchar(32) DEFAULT NULL,
+  `descr` varchar(64) DEFAULT NULL,
+  `privacy` tinyint(1) DEFAULT NULL,
+  `created` datetime DEFAULT NULL,
+  PRIMARY KEY (`id`),
+  KEY `nickname` (`nickname`),
+  KEY `macaddr` (`macaddr`)
+) ENGINE=MyISAM DEFAULT CHARSET=latin1;
+
+CREATE TABLE IF NOT EXISTS `mac_claims` (
+  `id` int(11) NOT NULL AUTO_INCREMENT,
+  `macaddr` varchar(17) NOT NULL,
+  `nickname` varchar(32) NOT NULL,
+  `descr` varchar(64) NOT NULL,
+  `privacy` tinyint(1) NOT NULL,
+  `created` datetime NOT NULL,
+  PRIMARY KEY (`id`),
+  KEY `nickname` (`nickname`),
+  KEY `macaddr` (`macaddr`)
+) ENGINE=MyISAM DEFAULT CHARSET=latin1;
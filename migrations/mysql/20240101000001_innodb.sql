@@ -0,0 +1,36 @@
+-- Move to InnoDB, make every mac address unique within mac_to_nick and use
+-- non nullable key columns. Addresses registered more than once and rows
+-- missing a key column would make this fail, mac4nick lists them before
+-- migrating so the operator can decide what to keep.
+
+UPDATE `mac_to_nick` SET `macaddr` = LOWER(`macaddr`);
+
+UPDATE `mac_to_nick` SET `descr` = '' WHERE `descr` IS NULL;
+UPDATE `mac_to_nick` SET `privacy` = 2 WHERE `privacy` IS NULL;
+UPDATE `mac_to_nick` SET `created` = CURRENT_TIMESTAMP WHERE `created` IS NULL;
+
+ALTER TABLE `mac_to_nick`
+  ENGINE=InnoDB,
+  CONVERT TO CHARACTER SET utf8mb4 COLLATE utf8mb4_general_ci,
+  MODIFY `id` int NOT NULL AUTO_INCREMENT,
+  MODIFY `macaddr` varchar(17) NOT NULL,
+  MODIFY `nickname` varchar(32) NOT NULL,
+  MODIFY `descr` varchar(64) NOT NULL DEFAULT '',
+  MODIFY `privacy` tinyint NOT NULL DEFAULT 2,
+  MODIFY `created` datetime NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  DROP KEY `macaddr`,
+  ADD UNIQUE KEY `macaddr` (`macaddr`);
+
+ALTER TABLE `alive_hosts`
+  ENGINE=InnoDB,
+  CONVERT TO CHARACTER SET utf8mb4 COLLATE utf8mb4_general_ci,
+  MODIFY `id` bigint NOT NULL AUTO_INCREMENT,
+  MODIFY `macaddr` varchar(17) NOT NULL,
+  MODIFY `erfda` datetime NOT NULL,
+  DROP KEY `macaddr`,
+  ADD KEY `macaddr_erfda` (`macaddr`, `erfda`);
+
+ALTER TABLE `mac_claims`
+  ENGINE=InnoDB,
+  CONVERT TO CHARACTER SET utf8mb4 COLLATE utf8mb4_general_ci,
+  MODIFY `id` int NOT NULL AUTO_INCREMENT;
@@ -0,0 +1,14 @@
+-- Addresses are stored as 4 (IPv4) or 16 (IPv6) bytes in network order.
+-- The scanner never logged devices without an address.
+DELETE FROM `alive_hosts` WHERE `iplong` IS NULL;
+
+ALTER TABLE `alive_hosts`
+  ADD COLUMN `ipaddr` varbinary(16) DEFAULT NULL AFTER `macaddr`;
+
+UPDATE `alive_hosts` SET `ipaddr` = UNHEX(LPAD(HEX(`iplong` & 0xFFFFFFFF), 8, '0'));
+
+ALTER TABLE `alive_hosts`
+  MODIFY `ipaddr` varbinary(16) NOT NULL,
+  DROP KEY `iplong`,
+  DROP COLUMN `iplong`,
+  ADD KEY `ipaddr` (`ipaddr`);
@@ -0,0 +1,8 @@
+CREATE TABLE `status_override` (
+  `id` int NOT NULL AUTO_INCREMENT,
+  `open` tinyint(1) NOT NULL,
+  `nickname` varchar(32) NOT NULL,
+  `created` datetime NOT NULL,
+  `expires` datetime NOT NULL,
+  PRIMARY KEY (`id`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4 COLLATE=utf8mb4_general_ci;
@@ -0,0 +1,10 @@
+CREATE TABLE `infrastructure` (
+  `id` int NOT NULL AUTO_INCREMENT,
+  `macaddr` varchar(17) NOT NULL,
+  `name` varchar(32) NOT NULL,
+  `descr` varchar(64) NOT NULL DEFAULT '',
+  `created` datetime NOT NULL,
+  PRIMARY KEY (`id`),
+  UNIQUE KEY `macaddr` (`macaddr`),
+  UNIQUE KEY `name` (`name`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4 COLLATE=utf8mb4_general_ci;
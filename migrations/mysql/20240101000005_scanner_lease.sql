@@ -0,0 +1,6 @@
+CREATE TABLE `scanner_lease` (
+  `id` int NOT NULL,
+  `holder` varchar(255) NOT NULL,
+  `expires` datetime NOT NULL,
+  PRIMARY KEY (`id`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4 COLLATE=utf8mb4_general_ci;
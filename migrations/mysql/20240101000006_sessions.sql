@@ -0,0 +1,6 @@
+CREATE TABLE `sessions` (
+  `id` char(22) NOT NULL,
+  `data` blob NOT NULL,
+  `expiry_date` timestamp(6) NOT NULL,
+  PRIMARY KEY (`id`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4 COLLATE=utf8mb4_general_ci;
@@ -0,0 +1,3 @@
+-- Set while the owner of a device is not listed as an active member.
+ALTER TABLE `mac_to_nick`
+  ADD COLUMN `departed` datetime DEFAULT NULL;
@@ -0,0 +1,34 @@
+CREATE TABLE mac_to_nick (
+  id SERIAL PRIMARY KEY,
+  macaddr VARCHAR(17) NOT NULL,
+  nickname VARCHAR(32) NOT NULL,
+  descr VARCHAR(64) NOT NULL DEFAULT '',
+  privacy SMALLINT NOT NULL DEFAULT 2,
+  created TIMESTAMP NOT NULL DEFAULT LOCALTIMESTAMP,
+  CONSTRAINT mac_to_nick_macaddr UNIQUE (macaddr)
+);
+
+CREATE INDEX mac_to_nick_nickname ON mac_to_nick (nickname);
+
+CREATE TABLE alive_hosts (
+  id BIGSERIAL PRIMARY KEY,
+  macaddr VARCHAR(17) NOT NULL,
+  iplong INTEGER,
+  erfda TIMESTAMP NOT NULL
+);
+
+CREATE INDEX alive_hosts_macaddr_erfda ON alive_hosts (macaddr, erfda);
+CREATE INDEX alive_hosts_iplong ON alive_hosts (iplong);
+CREATE INDEX alive_hosts_erfda ON alive_hosts (erfda);
+
+CREATE TABLE mac_claims (
+  id SERIAL PRIMARY KEY,
+  macaddr VARCHAR(17) NOT NULL,
+  nickname VARCHAR(32) NOT NULL,
+  descr VARCHAR(64) NOT NULL,
+  privacy SMALLINT NOT NULL,
+  created TIMESTAMP NOT NULL
+);
+
+CREATE INDEX mac_claims_nickname ON mac_claims (nickname);
+CREATE INDEX mac_claims_macaddr ON mac_claims (macaddr);
@@ -0,0 +1,12 @@
+-- Addresses are stored as 4 (IPv4) or 16 (IPv6) bytes in network order.
+-- The scanner never logged devices without an address.
+DELETE FROM alive_hosts WHERE iplong IS NULL;
+
+ALTER TABLE alive_hosts ADD COLUMN ipaddr BYTEA;
+
+UPDATE alive_hosts SET ipaddr = int4send(iplong);
+
+ALTER TABLE alive_hosts ALTER COLUMN ipaddr SET NOT NULL;
+ALTER TABLE alive_hosts DROP COLUMN iplong;
+
+CREATE INDEX alive_hosts_ipaddr ON alive_hosts (ipaddr);
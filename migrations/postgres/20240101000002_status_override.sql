@@ -0,0 +1,7 @@
+CREATE TABLE status_override (
+  id SERIAL PRIMARY KEY,
+  open BOOLEAN NOT NULL,
+  nickname VARCHAR(32) NOT NULL,
+  created TIMESTAMP NOT NULL,
+  expires TIMESTAMP NOT NULL
+);
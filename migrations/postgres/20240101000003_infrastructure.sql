@@ -0,0 +1,9 @@
+CREATE TABLE infrastructure (
+  id SERIAL PRIMARY KEY,
+  macaddr VARCHAR(17) NOT NULL,
+  name VARCHAR(32) NOT NULL,
+  descr VARCHAR(64) NOT NULL DEFAULT '',
+  created TIMESTAMP NOT NULL,
+  CONSTRAINT infrastructure_macaddr UNIQUE (macaddr),
+  CONSTRAINT infrastructure_name UNIQUE (name)
+);
@@ -0,0 +1,5 @@
+CREATE TABLE scanner_lease (
+  id INTEGER PRIMARY KEY,
+  holder VARCHAR(255) NOT NULL,
+  expires TIMESTAMP NOT NULL
+);
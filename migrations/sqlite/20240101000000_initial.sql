@@ -0,0 +1,33 @@
+CREATE TABLE mac_to_nick (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  macaddr TEXT NOT NULL UNIQUE,
+  nickname TEXT NOT NULL,
+  descr TEXT NOT NULL DEFAULT '',
+  privacy INTEGER NOT NULL DEFAULT 2,
+  created DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX mac_to_nick_nickname ON mac_to_nick (nickname);
+
+CREATE TABLE alive_hosts (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  macaddr TEXT NOT NULL,
+  iplong INTEGER,
+  erfda DATETIME NOT NULL
+);
+
+CREATE INDEX alive_hosts_macaddr_erfda ON alive_hosts (macaddr, erfda);
+CREATE INDEX alive_hosts_iplong ON alive_hosts (iplong);
+CREATE INDEX alive_hosts_erfda ON alive_hosts (erfda);
+
+CREATE TABLE mac_claims (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  macaddr TEXT NOT NULL,
+  nickname TEXT NOT NULL,
+  descr TEXT NOT NULL,
+  privacy INTEGER NOT NULL,
+  created DATETIME NOT NULL
+);
+
+CREATE INDEX mac_claims_nickname ON mac_claims (nickname);
+CREATE INDEX mac_claims_macaddr ON mac_claims (macaddr);
@@ -0,0 +1,20 @@
+-- Addresses are stored as 4 (IPv4) or 16 (IPv6) bytes in network order.
+-- The scanner never logged devices without an address.
+CREATE TABLE alive_hosts_ipaddr (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  macaddr TEXT NOT NULL,
+  ipaddr BLOB NOT NULL,
+  erfda DATETIME NOT NULL
+);
+
+INSERT INTO alive_hosts_ipaddr (id, macaddr, ipaddr, erfda)
+SELECT id, macaddr, unhex(printf('%08x', iplong & 0xFFFFFFFF)), erfda
+FROM alive_hosts
+WHERE iplong IS NOT NULL;
+
+DROP TABLE alive_hosts;
+ALTER TABLE alive_hosts_ipaddr RENAME TO alive_hosts;
+
+CREATE INDEX alive_hosts_macaddr_erfda ON alive_hosts (macaddr, erfda);
+CREATE INDEX alive_hosts_ipaddr ON alive_hosts (ipaddr);
+CREATE INDEX alive_hosts_erfda ON alive_hosts (erfda);
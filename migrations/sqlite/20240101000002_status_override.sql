@@ -0,0 +1,7 @@
+CREATE TABLE status_override (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  open BOOLEAN NOT NULL,
+  nickname TEXT NOT NULL,
+  created DATETIME NOT NULL,
+  expires DATETIME NOT NULL
+);
@@ -0,0 +1,7 @@
+CREATE TABLE infrastructure (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  macaddr TEXT NOT NULL UNIQUE,
+  name TEXT NOT NULL UNIQUE,
+  descr TEXT NOT NULL DEFAULT '',
+  created DATETIME NOT NULL
+);
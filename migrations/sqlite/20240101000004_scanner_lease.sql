@@ -0,0 +1,5 @@
+CREATE TABLE scanner_lease (
+  id INTEGER PRIMARY KEY,
+  holder TEXT NOT NULL,
+  expires DATETIME NOT NULL
+);
@@ -0,0 +1,5 @@
+CREATE TABLE sessions (
+  id TEXT PRIMARY KEY NOT NULL,
+  data BLOB NOT NULL,
+  expiry_date INTEGER NOT NULL
+);
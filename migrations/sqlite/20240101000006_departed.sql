@@ -0,0 +1,2 @@
+-- Set while the owner of a device is not listed as an active member.
+ALTER TABLE mac_to_nick ADD COLUMN departed DATETIME;
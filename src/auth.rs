@@ -0,0 +1,249 @@
+//! Verifying the identity of members as established by the auth proxy in
+//! front of the web interface.
+
+use crate::oidc::{self, Provider};
+use crate::{AuthMode, Config};
+use anyhow::{Context, Result, anyhow, bail};
+use axum::extract::ConnectInfo;
+use axum::http::request::Parts;
+use axum_extra::extract::CookieJar;
+use ipnetwork::IpNetwork;
+use jsonwebtoken::jwk::{Jwk, JwkSet};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tower_sessions::Session;
+
+/// Cookie holding the nickname in the `cookie` mode.
+const NAME_COOKIE: &str = "_forward_auth_name";
+
+/// Least time between two fetches of the JWKS, so tokens naming unknown
+/// keys do not make mac4nick hammer the provider.
+const JWKS_REFRESH: Duration = Duration::from_secs(60);
+
+const HMAC: [Algorithm; 3] = [Algorithm::HS256, Algorithm::HS384, Algorithm::HS512];
+
+/// Keys that signed tokens are verified with.
+enum Keys {
+    Secret(DecodingKey),
+    Jwks(Jwks),
+}
+
+/// Public keys of an identity provider, fetched when first needed.
+pub(crate) struct Jwks {
+    url: String,
+    cache: Mutex<Option<(JwkSet, Instant)>>,
+}
+
+/// Checks who sent a request, according to `AUTH_MODE`.
+pub struct Verifier {
+    mode: AuthMode,
+    trusted_proxies: Vec<IpNetwork>,
+    header: String,
+    jwt_header: String,
+    jwt_cookie: Option<String>,
+    claim: String,
+    issuer: Option<String>,
+    audience: Option<String>,
+    keys: Option<Keys>,
+    oidc: Option<Provider>,
+    client: reqwest::Client,
+}
+
+/// Address of the host the request came from, which is the proxy when
+/// there is one.
+pub(crate) fn peer(parts: &Parts) -> Option<IpAddr> {
+    parts
+        .extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_canonical())
+}
+
+fn find<'a>(keys: &'a JwkSet, kid: Option<&str>) -> Option<&'a Jwk> {
+    match kid {
+        Some(kid) => keys.find(kid),
+        None if keys.keys.len() == 1 => keys.keys.first(),
+        None => None,
+    }
+}
+
+impl Verifier {
+    pub fn new(config: &Config) -> Result<Self> {
+        let trusted_proxies = config
+            .trusted_proxies()
+            .map_err(|invalid| anyhow!(invalid.join(", ")))?;
+        let keys = match (&config.auth_jwt_secret, &config.auth_jwks_url) {
+            (Some(secret), _) => Some(Keys::Secret(DecodingKey::from_secret(secret.as_bytes()))),
+            (None, Some(url)) => Some(Keys::Jwks(Jwks::new(url.clone()))),
+            (None, None) => None,
+        };
+        let client = reqwest::ClientBuilder::new()
+            .timeout(Duration::from_secs(10))
+            .build()?;
+        Ok(Self {
+            mode: config.auth_mode,
+            trusted_proxies,
+            header: config.auth_header.clone(),
+            jwt_header: config.auth_jwt_header.clone(),
+            jwt_cookie: config.auth_jwt_cookie.clone(),
+            claim: config.auth_jwt_claim.clone(),
+            issuer: config.auth_jwt_issuer.clone(),
+            audience: config.auth_jwt_audience.clone(),
+            keys,
+            oidc: (config.auth_mode == AuthMode::Oidc)
+                .then(|| Provider::new(config, client.clone())),
+            client,
+        })
+    }
+
+    /// The provider members log in with, if mac4nick handles the login
+    /// itself.
+    pub fn oidc(&self) -> Option<&Provider> {
+        self.oidc.as_ref()
+    }
+
+    /// Whether the request comes straight from one of `TRUSTED_PROXIES`.
+    pub(crate) fn is_trusted_proxy(&self, parts: &Parts) -> bool {
+        peer(parts).is_some_and(|ip| self.trusted_proxies.iter().any(|net| net.contains(ip)))
+    }
+
+    /// Returns the nickname of the member sending the request, or why the
+    /// request is not authenticated.
+    pub async fn verify(&self, parts: &Parts) -> Result<String> {
+        if !self.trusted_proxies.is_empty() {
+            match peer(parts) {
+                Some(ip) if self.trusted_proxies.iter().any(|net| net.contains(ip)) => {}
+                Some(ip) => bail!("{} is not a trusted proxy", ip),
+                None => bail!("the address of the peer is unknown"),
+            }
+        }
+
+        let nickname = match self.mode {
+            AuthMode::Cookie => CookieJar::from_headers(&parts.headers)
+                .get(NAME_COOKIE)
+                .map(|cookie| cookie.value().to_string())
+                .with_context(|| format!("no {} cookie", NAME_COOKIE))?,
+            AuthMode::Header => parts
+                .headers
+                .get(&self.header)
+                .with_context(|| format!("no {} header", self.header))?
+                .to_str()
+                .with_context(|| format!("invalid {} header", self.header))?
+                .to_string(),
+            AuthMode::Jwt => {
+                let claims = self.claims(&self.token(parts)?).await?;
+                match claims.get(&self.claim) {
+                    Some(Value::String(nickname)) => nickname.clone(),
+                    _ => bail!("the token has no {} claim", self.claim),
+                }
+            }
+            AuthMode::Oidc => parts
+                .extensions
+                .get::<Session>()
+                .context("no session")?
+                .get::<String>(oidc::NICKNAME_KEY)
+                .await
+                .context("unable to load the session")?
+                .context("not logged in")?,
+        };
+        if nickname.trim().is_empty() {
+            bail!("the nickname is empty");
+        }
+        Ok(nickname)
+    }
+
+    /// The signed token, taken from the cookie if one is configured and
+    /// otherwise from the header.
+    fn token(&self, parts: &Parts) -> Result<String> {
+        if let Some(name) = &self.jwt_cookie
+            && let Some(cookie) = CookieJar::from_headers(&parts.headers).get(name)
+        {
+            return Ok(cookie.value().to_string());
+        }
+        let value = parts
+            .headers
+            .get(&self.jwt_header)
+            .and_then(|value| value.to_str().ok())
+            .context("no token")?;
+        Ok(value.strip_prefix("Bearer ").unwrap_or(value).to_string())
+    }
+
+    async fn claims(&self, token: &str) -> Result<HashMap<String, Value>> {
+        let header = jsonwebtoken::decode_header(token).context("malformed token")?;
+        let (key, algorithms) = match &self.keys {
+            Some(Keys::Secret(key)) => (key.clone(), HMAC.to_vec()),
+            Some(Keys::Jwks(jwks)) => (jwks.key(&self.client, &header).await?, vec![header.alg]),
+            None => bail!("no key to verify tokens with"),
+        };
+
+        let mut validation = Validation::new(header.alg);
+        validation.algorithms = algorithms;
+        // the configured claims have to be present, not just match if they are
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+            validation.required_spec_claims.insert("iss".to_string());
+        }
+        match &self.audience {
+            Some(audience) => {
+                validation.set_audience(&[audience]);
+                validation.required_spec_claims.insert("aud".to_string());
+            }
+            None => validation.validate_aud = false,
+        }
+        let token = jsonwebtoken::decode(token, &key, &validation).context("invalid token")?;
+        Ok(token.claims)
+    }
+}
+
+impl Jwks {
+    pub(crate) fn new(url: String) -> Self {
+        Self {
+            url,
+            cache: Mutex::new(None),
+        }
+    }
+
+    /// Looks up the key that signed a token with `header`, fetching the
+    /// JWKS again when the key is unknown, e.g. after the provider rotated
+    /// its keys.
+    pub(crate) async fn key(
+        &self,
+        client: &reqwest::Client,
+        header: &jsonwebtoken::Header,
+    ) -> Result<DecodingKey> {
+        // the keys of a JWKS are public, a token signed with one of them as
+        // a shared secret proves nothing
+        if HMAC.contains(&header.alg) {
+            bail!("the token is signed with a shared secret");
+        }
+        let kid = header.kid.as_deref();
+        let mut cache = self.cache.lock().await;
+        if let Some((keys, fetched)) = &*cache {
+            if let Some(jwk) = find(keys, kid) {
+                return Ok(DecodingKey::from_jwk(jwk)?);
+            }
+            if fetched.elapsed() < JWKS_REFRESH {
+                bail!("unknown key {:?}", kid);
+            }
+        }
+
+        let keys: JwkSet = client
+            .get(&self.url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .with_context(|| format!("unable to fetch the JWKS from {}", self.url))?
+            .json()
+            .await
+            .with_context(|| format!("invalid JWKS at {}", self.url))?;
+        let key = find(&keys, kid).map(DecodingKey::from_jwk);
+        *cache = Some((keys, Instant::now()));
+        Ok(key.with_context(|| format!("unknown key {:?}", kid))??)
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,195 @@
+use crate::{router, testing};
+use axum::Router;
+use axum::body::Body;
+use axum::http::{Request, StatusCode, header};
+use axum::routing::get;
+use jsonwebtoken::{EncodingKey, Header};
+use tower::ServiceExt;
+
+const PROXY: &str = "10.0.0.1:40000";
+const DIRECT: &str = "203.0.113.1:40000";
+
+async fn app(overrides: &[(&str, &str)]) -> Router {
+    router(testing::state(testing::config(overrides)).await)
+}
+
+/// Requests the index page from `peer` with the given headers.
+async fn get_from(app: &Router, peer: &str, headers: &[(&str, &str)]) -> (StatusCode, String) {
+    let mut browser = testing::Browser::new(app.clone());
+    browser.peer = Some(peer.parse().unwrap());
+    browser.headers = headers
+        .iter()
+        .map(|(name, value)| (name.parse().unwrap(), value.to_string()))
+        .collect();
+    browser.get("/").await
+}
+
+fn token(claims: serde_json::Value, header: &Header, key: &EncodingKey) -> String {
+    jsonwebtoken::encode(header, &claims, key).unwrap()
+}
+
+fn expires_in(seconds: i64) -> i64 {
+    chrono::Utc::now().timestamp() + seconds
+}
+
+#[tokio::test]
+async fn cookies_are_only_accepted_from_trusted_proxies() {
+    let app = app(&[("TRUSTED_PROXIES", "10.0.0.0/8, 2001:db8::/32")]).await;
+    let cookie = [(header::COOKIE.as_str(), "_forward_auth_name=alice")];
+
+    let (status, body) = get_from(&app, PROXY, &cookie).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains("alice's Devices"));
+    let (status, _) = get_from(&app, "[::ffff:10.0.0.1]:40000", &cookie).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, _) = get_from(&app, DIRECT, &cookie).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let response = app
+        .oneshot(
+            Request::get("/")
+                .header(header::COOKIE, "_forward_auth_name=alice")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn the_header_of_the_proxy_replaces_the_cookie() {
+    let app = app(&[
+        ("AUTH_MODE", "header"),
+        ("TRUSTED_PROXIES", "10.0.0.1/32"),
+        ("AUTH_HEADER", "Remote-User"),
+    ])
+    .await;
+
+    let (status, body) = get_from(&app, PROXY, &[("remote-user", "alice")]).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains("alice's Devices"));
+
+    let (status, _) = get_from(
+        &app,
+        PROXY,
+        &[(header::COOKIE.as_str(), "_forward_auth_name=alice")],
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = get_from(&app, PROXY, &[("remote-user", "")]).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = get_from(&app, DIRECT, &[("remote-user", "alice")]).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn tokens_signed_with_the_secret_are_accepted() {
+    let app = app(&[
+        ("AUTH_MODE", "jwt"),
+        ("AUTH_JWT_SECRET", "shared secret"),
+        ("AUTH_JWT_COOKIE", "_forward_auth_jwt"),
+        ("AUTH_JWT_CLAIM", "nick"),
+        ("AUTH_JWT_AUDIENCE", "mac4nick"),
+    ])
+    .await;
+    let key = EncodingKey::from_secret(b"shared secret");
+    let valid = serde_json::json!({"nick": "alice", "aud": "mac4nick", "exp": expires_in(60)});
+
+    let bearer = format!("Bearer {}", token(valid.clone(), &Header::default(), &key));
+    let (status, body) = get_from(&app, DIRECT, &[(header::AUTHORIZATION.as_str(), &bearer)]).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains("alice's Devices"));
+    let cookie = format!(
+        "_forward_auth_jwt={}",
+        token(valid.clone(), &Header::default(), &key)
+    );
+    let (status, _) = get_from(&app, DIRECT, &[(header::COOKIE.as_str(), &cookie)]).await;
+    assert_eq!(status, StatusCode::OK);
+
+    for (claims, key) in [
+        (valid.clone(), EncodingKey::from_secret(b"guessed secret")),
+        (
+            serde_json::json!({"nick": "alice", "aud": "mac4nick", "exp": expires_in(-3600)}),
+            key.clone(),
+        ),
+        (
+            serde_json::json!({"nick": "alice", "aud": "other", "exp": expires_in(60)}),
+            key.clone(),
+        ),
+        (
+            serde_json::json!({"nick": "alice", "exp": expires_in(60)}),
+            key.clone(),
+        ),
+        (
+            serde_json::json!({"sub": "alice", "aud": "mac4nick", "exp": expires_in(60)}),
+            key.clone(),
+        ),
+    ] {
+        let bearer = format!("Bearer {}", token(claims, &Header::default(), &key));
+        let (status, _) =
+            get_from(&app, DIRECT, &[(header::AUTHORIZATION.as_str(), &bearer)]).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+    let (status, _) = get_from(
+        &app,
+        DIRECT,
+        &[(header::COOKIE.as_str(), "_forward_auth_name=alice")],
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn tokens_are_verified_with_the_jwks() {
+    let jwks = testing::jwks();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/jwks", listener.local_addr().unwrap());
+    let provider = Router::new().route("/jwks", get(move || async move { axum::Json(jwks) }));
+    tokio::spawn(async move { axum::serve(listener, provider).await });
+
+    let app = app(&[
+        ("AUTH_MODE", "jwt"),
+        ("AUTH_JWKS_URL", &url),
+        ("AUTH_JWT_ISSUER", "https://proxy.example.org"),
+    ])
+    .await;
+    let claims = serde_json::json!({
+        "preferred_username": "alice",
+        "iss": "https://proxy.example.org",
+        "exp": expires_in(60),
+    });
+    let mut signed = Header::new(jsonwebtoken::Algorithm::ES256);
+    signed.kid = Some("proxy".to_string());
+    let key = EncodingKey::from_ec_pem(testing::EC_KEY.as_bytes()).unwrap();
+
+    let bearer = format!("Bearer {}", token(claims.clone(), &signed, &key));
+    let (status, body) = get_from(&app, DIRECT, &[(header::AUTHORIZATION.as_str(), &bearer)]).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains("alice's Devices"));
+
+    let mut unknown = signed.clone();
+    unknown.kid = Some("rotated".to_string());
+    let forged = Header {
+        kid: Some("proxy".to_string()),
+        ..Header::default()
+    };
+    for token in [
+        token(claims.clone(), &unknown, &key),
+        token(
+            claims.clone(),
+            &forged,
+            &EncodingKey::from_secret(b"1HS1-sHo9undMhYLv9-guIHpxlzzMal5ulIM3QwqOTo"),
+        ),
+        token(
+            serde_json::json!({"preferred_username": "alice", "exp": expires_in(60)}),
+            &signed,
+            &key,
+        ),
+    ] {
+        let bearer = format!("Bearer {}", token);
+        let (status, _) =
+            get_from(&app, DIRECT, &[(header::AUTHORIZATION.as_str(), &bearer)]).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,369 @@
+//! Command line interface for running mac4nick and for managing the
+//! registrations from a shell.
+
+use crate::health::Health;
+use crate::{Config, db, mac, scan, supervisor};
+use anyhow::{Context, Result, anyhow};
+use argh::FromArgs;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::io::Read;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Manage MAC address assignments of Hackerspace members. Configuration is
+/// read from the environment and an optional TOML file.
+#[derive(FromArgs)]
+pub struct Args {
+    /// TOML configuration file, the environment overrides its settings
+    /// (default: $CONFIG_FILE)
+    #[argh(option)]
+    pub config: Option<String>,
+
+    #[argh(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum Command {
+    Serve(ServeCommand),
+    Scan(ScanCommand),
+    Devices(DevicesCommand),
+    Export(ExportCommand),
+    Import(ImportCommand),
+    Migrate(MigrateCommand),
+}
+
+/// Run the web interface and the scanner (default).
+#[derive(FromArgs)]
+#[argh(subcommand, name = "serve")]
+pub struct ServeCommand {}
+
+/// Run the scanner without the web interface.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "scan")]
+pub struct ScanCommand {
+    /// scan a single time and exit
+    #[argh(switch)]
+    once: bool,
+
+    /// scan a single time, print what would be published and exit without
+    /// recording or publishing anything
+    #[argh(switch)]
+    dry_run: bool,
+}
+
+/// Manage registered devices.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "devices")]
+pub struct DevicesCommand {
+    #[argh(subcommand)]
+    command: DevicesSubcommand,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum DevicesSubcommand {
+    List(ListCommand),
+    Add(AddCommand),
+    Remove(RemoveCommand),
+}
+
+/// List registered devices.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "list")]
+struct ListCommand {
+    /// only list the devices of this member
+    #[argh(option)]
+    nickname: Option<String>,
+}
+
+/// Register a device for a member.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "add")]
+struct AddCommand {
+    /// mac address of the device
+    #[argh(positional)]
+    macaddr: String,
+
+    /// nickname of the member
+    #[argh(positional)]
+    nickname: String,
+
+    /// description of the device
+    #[argh(option, default = "String::new()")]
+    descr: String,
+
+    /// privacy level from 0 (show user and device) to 4 (dont log),
+    /// defaults to 2 (show anonymous)
+    #[argh(option, default = "2")]
+    privacy: i8,
+}
+
+/// Remove the registration of a device.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "remove")]
+struct RemoveCommand {
+    /// mac address of the device
+    #[argh(positional)]
+    macaddr: String,
+}
+
+/// Write all registrations and the infrastructure as JSON to stdout.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "export")]
+pub struct ExportCommand {}
+
+/// Register the devices and infrastructure of an export, skipping the ones
+/// that already exist.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "import")]
+pub struct ImportCommand {
+    /// file written by export, stdin if omitted
+    #[argh(positional)]
+    file: Option<String>,
+}
+
+/// Create or upgrade the database schema and exit.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "migrate")]
+pub struct MigrateCommand {}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+struct Export {
+    devices: Vec<ExportedDevice>,
+    #[serde(default)]
+    infrastructure: Vec<ExportedInfrastructure>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct ExportedDevice {
+    macaddr: String,
+    nickname: String,
+    #[serde(default)]
+    descr: String,
+    privacy: i8,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct ExportedInfrastructure {
+    macaddr: String,
+    name: String,
+    #[serde(default)]
+    descr: String,
+}
+
+/// Opens the database and migrates it unless `migrate` is false, like
+/// `serve` does with `DATABASE_MIGRATE`.
+async fn connect(config: &Config, migrate: bool) -> Result<Arc<dyn db::Repository>> {
+    let repo = db::connect(&config.dsn, config.pool_size(), config.windows()).await?;
+    if migrate {
+        repo.migrate().await?;
+    }
+    Ok(repo)
+}
+
+pub async fn scan(config: Config, file: Option<String>, command: ScanCommand) -> Result<()> {
+    let repo = connect(&config, config.migrate && !command.dry_run).await?;
+    let health = Arc::new(Health::default());
+    if command.dry_run {
+        let scanner = scan::Scanner::offline(&config, repo, Default::default(), health);
+        for (topic, payload) in scanner.dry_run().await? {
+            println!("{} {}", topic, payload);
+        }
+        return Ok(());
+    }
+    let scanner = scan::Scanner::new(&config, repo, Default::default(), health.clone());
+    if command.once {
+        scanner.scan_once().await
+    } else {
+        let (shutdown, stopping) = watch::channel(false);
+        let updates = crate::config::watch(file, config, health);
+        let job = tokio::spawn(scanner.clone().supervise(stopping, updates));
+        supervisor::shutdown_signal().await;
+        shutdown.send_replace(true);
+        if let Err(err) = job.await {
+            tracing::error!("scanner task failed: {}", err);
+        }
+        scanner.stop().await;
+        Ok(())
+    }
+}
+
+pub async fn devices(config: Config, command: DevicesCommand) -> Result<()> {
+    let repo = connect(&config, config.migrate).await?;
+    match command.command {
+        DevicesSubcommand::List(list) => {
+            for device in repo.devices().await? {
+                if list
+                    .nickname
+                    .as_ref()
+                    .is_some_and(|nickname| !nickname.eq_ignore_ascii_case(&device.nickname))
+                {
+                    continue;
+                }
+                println!(
+                    "{}  {:<16} {:<18} {}{}",
+                    device.macaddr,
+                    device.nickname,
+                    format!("{:?}", device.privacy),
+                    device.descr,
+                    if device.departed.is_some() {
+                        "  (departed)"
+                    } else {
+                        ""
+                    }
+                );
+            }
+            Ok(())
+        }
+        DevicesSubcommand::Add(add) => {
+            let device = register(
+                repo.as_ref(),
+                ExportedDevice {
+                    macaddr: add.macaddr,
+                    nickname: add.nickname,
+                    descr: add.descr,
+                    privacy: add.privacy,
+                },
+            )
+            .await?;
+            println!("assigned {} to {}", device.macaddr, device.nickname);
+            Ok(())
+        }
+        DevicesSubcommand::Remove(remove) => {
+            let macaddr = mac::normalize(&remove.macaddr)?;
+            let device = repo
+                .device_for_mac(&macaddr)
+                .await?
+                .ok_or_else(|| anyhow!("device {} is not registered", macaddr))?;
+            repo.delete_device(&device).await?;
+            println!("removed {} of {}", device.macaddr, device.nickname);
+            Ok(())
+        }
+    }
+}
+
+/// Registers a device after the same checks the web interface does.
+async fn register(repo: &dyn db::Repository, device: ExportedDevice) -> Result<db::Device> {
+    let privacy = db::PrivacyLevel::try_from(device.privacy).map_err(|err| anyhow!(err))?;
+    let macaddr = mac::normalize(&device.macaddr)?;
+    if device.nickname.trim().is_empty() {
+        return Err(anyhow!("device {} has no nickname", macaddr));
+    }
+    if repo.device_for_mac(&macaddr).await?.is_some() {
+        return Err(anyhow!("device {} is already registered", macaddr));
+    }
+    if repo.infrastructure_for_mac(&macaddr).await?.is_some() {
+        return Err(anyhow!(
+            "device {} belongs to the space infrastructure",
+            macaddr
+        ));
+    }
+    let device = db::Device::new(macaddr, device.nickname, device.descr, privacy);
+    repo.create_device(&device).await?;
+    Ok(device)
+}
+
+async fn export_all(repo: &dyn db::Repository) -> Result<Export> {
+    let devices = repo
+        .devices()
+        .await?
+        .into_iter()
+        .map(|device| ExportedDevice {
+            macaddr: device.macaddr,
+            nickname: device.nickname,
+            descr: device.descr,
+            privacy: device.privacy as i8,
+        })
+        .collect();
+    let infrastructure = repo
+        .infrastructure()
+        .await?
+        .into_iter()
+        .map(|infrastructure| ExportedInfrastructure {
+            macaddr: infrastructure.macaddr,
+            name: infrastructure.name,
+            descr: infrastructure.descr,
+        })
+        .collect();
+    Ok(Export {
+        devices,
+        infrastructure,
+    })
+}
+
+pub async fn export(config: Config) -> Result<()> {
+    let repo = connect(&config, config.migrate).await?;
+    let export = export_all(repo.as_ref()).await?;
+    println!("{}", serde_json::to_string_pretty(&export)?);
+    Ok(())
+}
+
+async fn add_infrastructure(
+    repo: &dyn db::Repository,
+    infrastructure: ExportedInfrastructure,
+) -> Result<()> {
+    let macaddr = mac::normalize(&infrastructure.macaddr)?;
+    if repo.infrastructure_for_mac(&macaddr).await?.is_some() {
+        return Err(anyhow!("device {} is already infrastructure", macaddr));
+    }
+    repo.create_infrastructure(&db::Infrastructure::new(
+        macaddr,
+        infrastructure.name,
+        infrastructure.descr,
+    ))
+    .await
+}
+
+/// Imports everything that does not exist yet and returns how many devices
+/// and infrastructure devices were imported.
+async fn import_all(repo: &dyn db::Repository, export: Export) -> (usize, usize) {
+    // infrastructure first, so devices of the space are not registered for
+    // members
+    let mut infrastructure = 0;
+    for exported in export.infrastructure {
+        let macaddr = exported.macaddr.clone();
+        match add_infrastructure(repo, exported).await {
+            Ok(()) => infrastructure += 1,
+            Err(err) => tracing::warn!("skipping {}: {}", macaddr, err),
+        }
+    }
+
+    let mut devices = 0;
+    for exported in export.devices {
+        let macaddr = exported.macaddr.clone();
+        match register(repo, exported).await {
+            Ok(_) => devices += 1,
+            Err(err) => tracing::warn!("skipping {}: {}", macaddr, err),
+        }
+    }
+    (devices, infrastructure)
+}
+
+pub async fn import(config: Config, command: ImportCommand) -> Result<()> {
+    let mut input = String::new();
+    match &command.file {
+        Some(file) => {
+            input =
+                std::fs::read_to_string(file).with_context(|| format!("unable to read {}", file))?
+        }
+        None => {
+            std::io::stdin()
+                .read_to_string(&mut input)
+                .context("unable to read stdin")?;
+        }
+    }
+    let export: Export = serde_json::from_str(&input).context("unable to parse export")?;
+    let repo = connect(&config, config.migrate).await?;
+    let (devices, infrastructure) = import_all(repo.as_ref(), export).await;
+    println!(
+        "imported {} devices and {} infrastructure devices",
+        devices, infrastructure
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,97 @@
+use super::*;
+use crate::testing;
+
+async fn repo() -> Arc<dyn db::Repository> {
+    testing::state(testing::config(&[])).await.repo
+}
+
+fn device(macaddr: &str, nickname: &str, privacy: i8) -> ExportedDevice {
+    ExportedDevice {
+        macaddr: macaddr.to_string(),
+        nickname: nickname.to_string(),
+        descr: "laptop".to_string(),
+        privacy,
+    }
+}
+
+#[tokio::test]
+async fn register_checks_like_the_web_interface() {
+    let repo = repo().await;
+    repo.create_infrastructure(&db::Infrastructure::new(
+        "00:11:22:00:00:09".to_string(),
+        "printer".to_string(),
+        String::new(),
+    ))
+    .await
+    .unwrap();
+
+    let registered = register(repo.as_ref(), device("00-11-22-00-00-01", "alice", 1))
+        .await
+        .unwrap();
+    assert_eq!(registered.macaddr, "00:11:22:00:00:01");
+    assert_eq!(registered.privacy, db::PrivacyLevel::ShowUser);
+
+    for (invalid, error) in [
+        (device("00:11:22:00:00:01", "bob", 1), "already registered"),
+        (device("00:11:22:00:00:09", "bob", 1), "infrastructure"),
+        (device("not-a-mac", "bob", 1), "not a valid mac address"),
+        (
+            device("00:11:22:00:00:02", "bob", 7),
+            "invalid privacy level",
+        ),
+        (device("00:11:22:00:00:02", " ", 1), "no nickname"),
+    ] {
+        let err = register(repo.as_ref(), invalid).await.unwrap_err();
+        assert!(err.to_string().contains(error), "{}", err);
+    }
+    assert_eq!(repo.devices().await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn export_and_import_round_trip() {
+    let source = repo().await;
+    for exported in [
+        device("00:11:22:00:00:02", "bob", 4),
+        device("00:11:22:00:00:01", "alice", 0),
+    ] {
+        register(source.as_ref(), exported).await.unwrap();
+    }
+    source
+        .create_infrastructure(&db::Infrastructure::new(
+            "00:11:22:00:00:09".to_string(),
+            "printer".to_string(),
+            "laser".to_string(),
+        ))
+        .await
+        .unwrap();
+
+    let export = export_all(source.as_ref()).await.unwrap();
+    assert_eq!(export.devices[0].nickname, "alice");
+    let json = serde_json::to_string(&export).unwrap();
+
+    let target = repo().await;
+    let imported = serde_json::from_str(&json).unwrap();
+    assert_eq!(import_all(target.as_ref(), imported).await, (2, 1));
+    assert_eq!(export_all(target.as_ref()).await.unwrap(), export);
+
+    // importing again skips everything that already exists
+    let imported = serde_json::from_str(&json).unwrap();
+    assert_eq!(import_all(target.as_ref(), imported).await, (0, 0));
+}
+
+#[tokio::test]
+async fn management_commands_migrate_a_fresh_database() {
+    let config = testing::config(&[("DATABASE_DSN", "sqlite::memory:")]);
+    let repo = connect(&config, config.migrate).await.unwrap();
+    register(repo.as_ref(), device("00:11:22:00:00:01", "alice", 1))
+        .await
+        .unwrap();
+    assert_eq!(export_all(repo.as_ref()).await.unwrap().devices.len(), 1);
+
+    let config = testing::config(&[
+        ("DATABASE_DSN", "sqlite::memory:"),
+        ("DATABASE_MIGRATE", "false"),
+    ]);
+    let repo = connect(&config, config.migrate).await.unwrap();
+    assert!(repo.devices().await.is_err());
+}
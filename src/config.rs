@@ -0,0 +1,474 @@
+//! Loading and validating the configuration from the environment, an
+//! optional TOML file and files holding secrets.
+
+use crate::health::Health;
+use crate::supervisor;
+use crate::{AuthMode, Config};
+use anyhow::{Context, Result, anyhow};
+use envconfig::Envconfig;
+use ipnetwork::IpNetwork;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::watch;
+
+/// Settings that may also be read from the file named by `<name>_FILE`,
+/// like Docker secrets.
+const SECRETS: &[&str] = &[
+    "DATABASE_DSN",
+    "UNIFI_PASSWORD",
+    "AUTH_JWT_SECRET",
+    "OIDC_CLIENT_SECRET",
+    "LDAP_BIND_PASSWORD",
+];
+
+/// Settings of the commands that only touch the database.
+const DATABASE_SETTINGS: &[&str] = &[
+    "DATABASE_DSN",
+    "DATABASE_MIGRATE",
+    "DATABASE_MAX_CONNECTIONS",
+    "DATABASE_MIN_CONNECTIONS",
+    "PRESENT_WINDOW",
+    "UNASSIGNED_WINDOW",
+];
+
+/// Which settings a command needs and checks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scope {
+    All,
+    /// Only the database settings, the others fall back to placeholders.
+    Database,
+}
+
+impl Scope {
+    fn checks(self, name: &str) -> bool {
+        self == Scope::All || DATABASE_SETTINGS.contains(&name)
+    }
+}
+
+/// Reads the settings of a TOML file. The keys are the names of the
+/// environment variables, lists are joined by commas.
+fn from_toml(content: &str) -> Result<HashMap<String, String>> {
+    let table: toml::value::Table = toml::from_str(content)?;
+    table
+        .into_iter()
+        .map(|(key, value)| {
+            let value = match value {
+                toml::Value::Array(values) => values
+                    .into_iter()
+                    .map(|value| scalar(&key, value))
+                    .collect::<Result<Vec<_>>>()?
+                    .join(","),
+                value => scalar(&key, value)?,
+            };
+            Ok((key, value))
+        })
+        .collect()
+}
+
+fn scalar(key: &str, value: toml::Value) -> Result<String> {
+    match value {
+        toml::Value::String(value) => Ok(value),
+        toml::Value::Integer(value) => Ok(value.to_string()),
+        toml::Value::Boolean(value) => Ok(value.to_string()),
+        _ => Err(anyhow!("{} must be a string, number or boolean", key)),
+    }
+}
+
+/// Replaces every `<name>_FILE` setting of a secret by the content of the
+/// file it names.
+fn read_secrets(settings: &mut HashMap<String, String>, scope: Scope, problems: &mut Vec<String>) {
+    for secret in SECRETS.iter().filter(|secret| scope.checks(secret)) {
+        let key = format!("{}_FILE", secret);
+        let Some(file) = settings.remove(&key) else {
+            continue;
+        };
+        if settings.contains_key(*secret) {
+            problems.push(format!("only one of {} and {} may be set", secret, key));
+            continue;
+        }
+        match std::fs::read_to_string(&file) {
+            Ok(content) => {
+                settings.insert(secret.to_string(), content.trim_end().to_string());
+            }
+            Err(err) => problems.push(format!("{}: unable to read {}: {}", key, file, err)),
+        }
+    }
+}
+
+/// Builds the configuration, collecting every missing or unparsable
+/// setting instead of stopping at the first one.
+fn parse(
+    mut settings: HashMap<String, String>,
+    scope: Scope,
+    problems: &mut Vec<String>,
+) -> Option<Config> {
+    let mut reported = HashSet::new();
+    loop {
+        match Config::init_from_hashmap(&settings) {
+            Ok(config) => return Some(config),
+            Err(envconfig::Error::EnvVarMissing { name }) => {
+                reported.insert(name);
+                if scope.checks(name) {
+                    problems.push(format!("{} is not set", name));
+                }
+                // all required settings are strings, an empty placeholder
+                // lets the remaining ones be checked
+                settings.insert(name.to_string(), String::new());
+            }
+            Err(envconfig::Error::ParseError { name }) => {
+                let value = settings.remove(name).unwrap_or_default();
+                if !reported.insert(name) {
+                    return None;
+                }
+                if scope.checks(name) {
+                    problems.push(format!("{} has an invalid value \"{}\"", name, value));
+                }
+            }
+        }
+    }
+}
+
+/// Parses the comma separated subnets of the setting `name`, describing
+/// every invalid entry.
+fn subnets(name: &str, value: &str) -> Result<Vec<IpNetwork>, Vec<String>> {
+    let mut subnets = vec![];
+    let mut invalid = vec![];
+    for subnet in value.split(',').map(str::trim) {
+        match IpNetwork::from_str(subnet) {
+            Ok(subnet) => subnets.push(subnet),
+            Err(err) => invalid.push(format!(
+                "{} contains the invalid subnet \"{}\": {}",
+                name, subnet, err
+            )),
+        }
+    }
+    if invalid.is_empty() {
+        Ok(subnets)
+    } else {
+        Err(invalid)
+    }
+}
+
+impl Config {
+    /// Parses `ALLOWED_SUBNETS`, describing every invalid entry.
+    pub(crate) fn allowed_subnets(&self) -> Result<Vec<IpNetwork>, Vec<String>> {
+        subnets("ALLOWED_SUBNETS", &self.allowed_subnets)
+    }
+
+    /// Parses `TRUSTED_PROXIES`, which may be empty.
+    pub(crate) fn trusted_proxies(&self) -> Result<Vec<IpNetwork>, Vec<String>> {
+        if self.trusted_proxies.trim().is_empty() {
+            return Ok(vec![]);
+        }
+        subnets("TRUSTED_PROXIES", &self.trusted_proxies)
+    }
+
+    /// Names the settings that differ from `reloaded` but only take effect
+    /// on a restart.
+    fn restart_required(&self, reloaded: &Config) -> Vec<&'static str> {
+        [
+            ("LISTEN", self.listen != reloaded.listen),
+            ("ROLE", self.role != reloaded.role),
+            (
+                "SESSION_SECURE",
+                self.session_secure != reloaded.session_secure,
+            ),
+            ("AUTH_MODE", self.auth_mode != reloaded.auth_mode),
+            (
+                "TRUSTED_PROXIES",
+                self.trusted_proxies != reloaded.trusted_proxies,
+            ),
+            ("AUTH_HEADER", self.auth_header != reloaded.auth_header),
+            (
+                "AUTH_JWT_*",
+                self.auth_jwt_header != reloaded.auth_jwt_header
+                    || self.auth_jwt_cookie != reloaded.auth_jwt_cookie
+                    || self.auth_jwt_secret != reloaded.auth_jwt_secret
+                    || self.auth_jwt_claim != reloaded.auth_jwt_claim
+                    || self.auth_jwt_issuer != reloaded.auth_jwt_issuer
+                    || self.auth_jwt_audience != reloaded.auth_jwt_audience,
+            ),
+            (
+                "AUTH_JWKS_URL",
+                self.auth_jwks_url != reloaded.auth_jwks_url,
+            ),
+            (
+                "OIDC_*",
+                self.oidc_issuer != reloaded.oidc_issuer
+                    || self.oidc_client_id != reloaded.oidc_client_id
+                    || self.oidc_client_secret != reloaded.oidc_client_secret
+                    || self.oidc_redirect_url != reloaded.oidc_redirect_url
+                    || self.oidc_scopes != reloaded.oidc_scopes
+                    || self.oidc_nickname_claim != reloaded.oidc_nickname_claim,
+            ),
+            (
+                "LDAP_*",
+                self.ldap_url != reloaded.ldap_url
+                    || self.ldap_bind_dn != reloaded.ldap_bind_dn
+                    || self.ldap_bind_password != reloaded.ldap_bind_password
+                    || self.ldap_starttls != reloaded.ldap_starttls
+                    || self.ldap_members_group != reloaded.ldap_members_group
+                    || self.ldap_admins_group != reloaded.ldap_admins_group
+                    || self.ldap_member_attributes != reloaded.ldap_member_attributes
+                    || self.ldap_sync_interval != reloaded.ldap_sync_interval,
+            ),
+            ("DATABASE_DSN", self.dsn != reloaded.dsn),
+            (
+                "DATABASE_MAX_CONNECTIONS",
+                self.db_max_connections != reloaded.db_max_connections,
+            ),
+            (
+                "DATABASE_MIN_CONNECTIONS",
+                self.db_min_connections != reloaded.db_min_connections,
+            ),
+            (
+                "PRESENT_WINDOW",
+                self.present_window != reloaded.present_window,
+            ),
+            (
+                "UNASSIGNED_WINDOW",
+                self.unassigned_window != reloaded.unassigned_window,
+            ),
+            ("MQTT_HOST", self.mqtt_host != reloaded.mqtt_host),
+            ("MQTT_PORT", self.mqtt_port != reloaded.mqtt_port),
+            (
+                "MQTT_STATUS_OVERRIDE_TOPIC",
+                self.mqtt_status_override_topic != reloaded.mqtt_status_override_topic,
+            ),
+            (
+                "MQTT_DOOR_TOPIC",
+                self.mqtt_door_topic != reloaded.mqtt_door_topic,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(name, changed)| changed.then_some(name))
+        .collect()
+    }
+
+    /// Checks the values that parse but make no sense.
+    fn validate(&self, scope: Scope, problems: &mut Vec<String>) {
+        self.validate_database(problems);
+        if scope == Scope::Database {
+            return;
+        }
+        if let Err(invalid) = self.allowed_subnets() {
+            problems.extend(invalid);
+        }
+        match self.trusted_proxies() {
+            Err(invalid) => problems.extend(invalid),
+            Ok(proxies) if proxies.is_empty() => {
+                if self.auth_mode == AuthMode::Header {
+                    problems.push(
+                        "AUTH_MODE header requires TRUSTED_PROXIES, anyone could set the header"
+                            .to_string(),
+                    );
+                }
+                if self.trust_forwarded_for {
+                    problems.push(
+                        "TRUST_FORWARDED_FOR requires TRUSTED_PROXIES, anyone could set the header"
+                            .to_string(),
+                    );
+                }
+            }
+            Ok(_) => {}
+        }
+        if self.auth_mode == AuthMode::Jwt {
+            match (&self.auth_jwt_secret, &self.auth_jwks_url) {
+                (None, None) => problems
+                    .push("AUTH_MODE jwt requires AUTH_JWT_SECRET or AUTH_JWKS_URL".to_string()),
+                (Some(_), Some(_)) => problems
+                    .push("only one of AUTH_JWT_SECRET and AUTH_JWKS_URL may be set".to_string()),
+                _ => {}
+            }
+        }
+        if self.auth_mode == AuthMode::Oidc {
+            for (name, value) in [
+                ("OIDC_ISSUER", &self.oidc_issuer),
+                ("OIDC_CLIENT_ID", &self.oidc_client_id),
+                ("OIDC_REDIRECT_URL", &self.oidc_redirect_url),
+            ] {
+                if value.is_none() {
+                    problems.push(format!("AUTH_MODE oidc requires {}", name));
+                }
+            }
+            if let Some(url) = &self.oidc_redirect_url
+                && !url.ends_with("/auth/callback")
+            {
+                problems.push(format!(
+                    "OIDC_REDIRECT_URL must point to /auth/callback, not \"{}\"",
+                    url
+                ));
+            }
+        }
+        match &self.ldap_url {
+            Some(url) if !url.starts_with("ldap://") && !url.starts_with("ldaps://") => problems
+                .push(format!(
+                    "LDAP_URL must be an ldap:// or ldaps:// URL, not \"{}\"",
+                    url
+                )),
+            Some(_) if self.ldap_members_group.is_none() && self.ldap_admins_group.is_none() => {
+                problems
+                    .push("LDAP_URL requires LDAP_MEMBERS_GROUP or LDAP_ADMINS_GROUP".to_string())
+            }
+            None if self.ldap_members_group.is_some() || self.ldap_admins_group.is_some() => {
+                problems.push("the LDAP groups require LDAP_URL".to_string())
+            }
+            _ => {}
+        }
+        if let Some(url) = &self.ldap_url
+            && url.starts_with("ldap://")
+            && self.ldap_bind_password.is_some()
+            && !self.ldap_starttls
+        {
+            problems.push(
+                "LDAP_BIND_PASSWORD would be sent in the clear, use an ldaps:// URL or LDAP_STARTTLS"
+                    .to_string(),
+            );
+        }
+        if self.ldap_sync_interval == 0 {
+            problems.push("LDAP_SYNC_INTERVAL must be at least one second".to_string());
+        }
+        if !matches!(self.unifi_scheme.as_str(), "http" | "https") {
+            problems.push(format!(
+                "UNIFI_SCHEME must be http or https, not \"{}\"",
+                self.unifi_scheme
+            ));
+        }
+        if self.scan_interval == 0 {
+            problems.push("SCAN_INTERVAL must be at least one second".to_string());
+        }
+    }
+
+    /// Checks the settings of the database.
+    fn validate_database(&self, problems: &mut Vec<String>) {
+        let scheme = self.dsn.split(':').next().unwrap_or_default();
+        if !self.dsn.is_empty()
+            && !matches!(
+                scheme,
+                "mysql" | "mariadb" | "postgres" | "postgresql" | "sqlite"
+            )
+        {
+            problems.push(format!(
+                "DATABASE_DSN uses the unsupported database \"{}\"",
+                scheme
+            ));
+        }
+        if self.db_min_connections > self.db_max_connections {
+            problems.push(
+                "DATABASE_MIN_CONNECTIONS must not exceed DATABASE_MAX_CONNECTIONS".to_string(),
+            );
+        }
+    }
+}
+
+/// Combines the settings of the TOML file `file`, which the environment
+/// overrides, and the secrets read from files.
+fn load_from(env: HashMap<String, String>, file: Option<&str>, scope: Scope) -> Result<Config> {
+    let mut settings = match file {
+        Some(file) => {
+            let content = std::fs::read_to_string(file)
+                .with_context(|| format!("unable to read {}", file))?;
+            from_toml(&content).with_context(|| format!("unable to parse {}", file))?
+        }
+        None => HashMap::new(),
+    };
+    settings.extend(env);
+
+    let mut problems = vec![];
+    read_secrets(&mut settings, scope, &mut problems);
+    if let Some(config) = parse(settings, scope, &mut problems) {
+        config.validate(scope, &mut problems);
+        if problems.is_empty() {
+            return Ok(config);
+        }
+    }
+    Err(anyhow!(
+        "invalid configuration:\n  {}",
+        problems.join("\n  ")
+    ))
+}
+
+/// The TOML file given on the command line, or the one named by
+/// `CONFIG_FILE`.
+pub fn file(arg: Option<&str>) -> Option<String> {
+    arg.map(str::to_string)
+        .or_else(|| std::env::var("CONFIG_FILE").ok())
+}
+
+/// Loads the configuration from the environment and the TOML file `file`,
+/// checking the settings of `scope`.
+pub fn load(file: Option<&str>, scope: Scope) -> Result<Config> {
+    load_from(std::env::vars().collect(), file, scope)
+}
+
+/// Hands a reloaded configuration on, an invalid one leaves the current
+/// configuration in place.
+fn reload(reloaded: Result<Config>, updates: &watch::Sender<Config>) {
+    match reloaded {
+        Ok(config) => {
+            let ignored = updates.borrow().restart_required(&config);
+            if !ignored.is_empty() {
+                tracing::warn!("changes of {} take effect on restart", ignored.join(", "));
+            }
+            updates.send_replace(config);
+            tracing::info!("configuration reloaded");
+        }
+        Err(err) => tracing::error!("keeping the current configuration: {:#}", err),
+    }
+}
+
+fn modified(file: &str) -> Option<SystemTime> {
+    std::fs::metadata(file)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Reloads the configuration on SIGHUP and whenever `file` changes.
+async fn watch_changes(file: Option<String>, updates: Arc<watch::Sender<Config>>) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => Some(hangup),
+        Err(err) => {
+            tracing::error!("unable to listen for SIGHUP: {}", err);
+            None
+        }
+    };
+    let mut last_modified = file.as_deref().and_then(modified);
+    let mut poll = tokio::time::interval(Duration::from_secs(5));
+    loop {
+        tokio::select! {
+            Some(()) = async { hangup.as_mut()?.recv().await } => {
+                tracing::info!("reloading the configuration on SIGHUP");
+            }
+            _ = poll.tick(), if file.is_some() => {
+                let current = file.as_deref().and_then(modified);
+                if current == last_modified {
+                    continue;
+                }
+                last_modified = current;
+                tracing::info!("configuration file changed, reloading");
+            }
+            // neither SIGHUP nor a file to poll
+            else => {
+                tracing::warn!("nothing to watch, the configuration is not reloaded");
+                return;
+            }
+        }
+        reload(load(file.as_deref(), Scope::All), &updates);
+    }
+}
+
+/// Follows the configuration as it is reloaded, starting with `config`.
+pub fn watch(file: Option<String>, config: Config, health: Arc<Health>) -> watch::Receiver<Config> {
+    let updates = Arc::new(watch::Sender::new(config));
+    let receiver = updates.subscribe();
+    tokio::spawn(supervisor::supervise("config", health, move || {
+        watch_changes(file.clone(), updates.clone())
+    }));
+    receiver
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,209 @@
+use super::*;
+
+fn env(settings: &[(&str, &str)]) -> HashMap<String, String> {
+    settings
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+/// Writes `content` to a file that is removed when the test ends.
+struct TempFile(std::path::PathBuf);
+
+impl TempFile {
+    fn new(name: &str, content: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("mac4nick-{}-{}", std::process::id(), name));
+        std::fs::write(&path, content).unwrap();
+        Self(path)
+    }
+
+    fn path(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[test]
+fn file_environment_and_secrets_are_combined() {
+    let file = TempFile::new(
+        "config.toml",
+        r#"
+DATABASE_DSN_FILE = "overridden below"
+UNIFI_HOSTNAME = "unifi.example.org"
+UNIFI_USERNAME = "mac4nick"
+MQTT_HOST = "mqtt.example.org"
+MQTT_PORT = 8883
+SCAN_INTERVAL = 30
+ALLOWED_SUBNETS = ["10.0.0.0/8", "2001:db8::/32"]
+"#,
+    );
+    let dsn = TempFile::new("dsn", "sqlite://mac4nick.db\n");
+    let password = TempFile::new("password", "secret\n");
+
+    let config = load_from(
+        env(&[
+            ("DATABASE_DSN_FILE", dsn.path()),
+            ("UNIFI_PASSWORD_FILE", password.path()),
+            ("SCAN_INTERVAL", "10"),
+        ]),
+        Some(file.path()),
+        Scope::All,
+    )
+    .unwrap();
+    assert_eq!(config.dsn, "sqlite://mac4nick.db");
+    assert_eq!(config.unifi_password, "secret");
+    assert_eq!(config.unifi_hostname, "unifi.example.org");
+    assert_eq!(config.mqtt_port, 8883);
+    assert_eq!(config.scan_interval, 10);
+    assert_eq!(config.allowed_subnets().unwrap().len(), 2);
+}
+
+#[test]
+fn every_problem_is_reported() {
+    let err = load_from(
+        env(&[
+            ("DATABASE_DSN", "sqlite://mac4nick.db"),
+            ("DATABASE_DSN_FILE", "/run/secrets/dsn"),
+            ("UNIFI_HOSTNAME", "unifi.example.org"),
+            ("UNIFI_PASSWORD_FILE", "/nonexistent/password"),
+            ("MQTT_PORT", "many"),
+            ("ROLE", "everything"),
+            ("AUTH_MODE", "header"),
+            ("TRUST_FORWARDED_FOR", "true"),
+            ("ALLOWED_SUBNETS", "10.0.0.0/8, 10.0.0.0/33, lan"),
+            (
+                "LDAP_MEMBERS_GROUP",
+                "cn=members,ou=groups,dc=example,dc=org",
+            ),
+            ("LDAP_SYNC_INTERVAL", "0"),
+        ]),
+        None,
+        Scope::All,
+    )
+    .err()
+    .expect("invalid configuration")
+    .to_string();
+
+    for problem in [
+        "only one of DATABASE_DSN and DATABASE_DSN_FILE may be set",
+        "UNIFI_PASSWORD_FILE: unable to read /nonexistent/password",
+        "UNIFI_USERNAME is not set",
+        "UNIFI_PASSWORD is not set",
+        "MQTT_HOST is not set",
+        "MQTT_PORT has an invalid value \"many\"",
+        "ROLE has an invalid value \"everything\"",
+        "AUTH_MODE header requires TRUSTED_PROXIES",
+        "TRUST_FORWARDED_FOR requires TRUSTED_PROXIES",
+        "invalid subnet \"10.0.0.0/33\"",
+        "invalid subnet \"lan\"",
+        "the LDAP groups require LDAP_URL",
+        "LDAP_SYNC_INTERVAL must be at least one second",
+    ] {
+        assert!(err.contains(problem), "{} missing in {}", problem, err);
+    }
+}
+
+#[test]
+fn ldap_passwords_are_not_sent_in_the_clear() {
+    let ldap = |url: &str, starttls: &str| {
+        load_from(
+            env(&[
+                ("DATABASE_DSN", "sqlite::memory:"),
+                ("UNIFI_HOSTNAME", "unifi.example.org"),
+                ("UNIFI_USERNAME", "mac4nick"),
+                ("UNIFI_PASSWORD", "secret"),
+                ("MQTT_HOST", "mqtt.example.org"),
+                ("LDAP_URL", url),
+                ("LDAP_BIND_DN", "cn=mac4nick,dc=example,dc=org"),
+                ("LDAP_BIND_PASSWORD", "secret"),
+                (
+                    "LDAP_MEMBERS_GROUP",
+                    "cn=members,ou=groups,dc=example,dc=org",
+                ),
+                ("LDAP_STARTTLS", starttls),
+            ]),
+            None,
+            Scope::All,
+        )
+    };
+    let err = ldap("ldap://ldap.example.org", "false")
+        .err()
+        .expect("password in the clear");
+    assert!(err.to_string().contains("sent in the clear"), "{}", err);
+    assert!(ldap("ldap://ldap.example.org", "true").is_ok());
+    assert!(ldap("ldaps://ldap.example.org", "false").is_ok());
+}
+
+#[test]
+fn management_commands_only_check_the_database() {
+    let dsn = TempFile::new("dsn", "sqlite://mac4nick.db\n");
+    let config = load_from(
+        env(&[
+            ("DATABASE_DSN_FILE", dsn.path()),
+            ("UNIFI_PASSWORD_FILE", "/nonexistent/password"),
+            ("MQTT_PORT", "many"),
+            ("AUTH_MODE", "header"),
+        ]),
+        None,
+        Scope::Database,
+    )
+    .unwrap();
+    assert_eq!(config.dsn, "sqlite://mac4nick.db");
+
+    let err = load_from(
+        env(&[("DATABASE_MAX_CONNECTIONS", "none")]),
+        None,
+        Scope::Database,
+    )
+    .err()
+    .expect("invalid configuration")
+    .to_string();
+    assert!(err.contains("DATABASE_DSN is not set"), "{}", err);
+    assert!(err.contains("DATABASE_MAX_CONNECTIONS has an invalid value"));
+    assert!(!err.contains("UNIFI_HOSTNAME"), "{}", err);
+}
+
+#[test]
+fn invalid_reloads_are_ignored() {
+    let required = [
+        ("DATABASE_DSN", "sqlite::memory:"),
+        ("UNIFI_HOSTNAME", "unifi.example.org"),
+        ("UNIFI_USERNAME", "mac4nick"),
+        ("UNIFI_PASSWORD", "secret"),
+        ("MQTT_HOST", "mqtt.example.org"),
+    ];
+    let config = load_from(env(&required), None, Scope::All).unwrap();
+    let updates = watch::Sender::new(config);
+    let mut receiver = updates.subscribe();
+
+    let mut changed = required.to_vec();
+    changed.push(("ALLOWED_SUBNETS", "10.0.0.0/8"));
+    changed.push(("MQTT_PORT", "8883"));
+    reload(load_from(env(&changed), None, Scope::All), &updates);
+    assert!(receiver.has_changed().unwrap());
+    assert_eq!(receiver.borrow_and_update().allowed_subnets, "10.0.0.0/8");
+    assert_eq!(
+        updates
+            .borrow()
+            .restart_required(&load_from(env(&required), None, Scope::All).unwrap()),
+        vec!["MQTT_PORT"]
+    );
+    let mut oidc = required.to_vec();
+    oidc.push(("OIDC_CLIENT_ID", "mac4nick"));
+    assert_eq!(
+        updates
+            .borrow()
+            .restart_required(&load_from(env(&oidc), None, Scope::All).unwrap()),
+        vec!["OIDC_*", "MQTT_PORT"]
+    );
+
+    changed.push(("ALLOWED_SUBNETS", "lan"));
+    reload(load_from(env(&changed), None, Scope::All), &updates);
+    assert!(!receiver.has_changed().unwrap());
+    assert_eq!(updates.borrow().allowed_subnets, "10.0.0.0/8");
+}
@@ -0,0 +1,468 @@
+use super::{
+    AliveDevice, Claim, Device, Infrastructure, Repository, Sessions, StatusOverride, Windows,
+};
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+use tower_sessions::MemoryStore;
+
+/// A sighting of a device as the scanner stores it in `alive_hosts`.
+struct Sighting {
+    macaddr: String,
+    ipaddr: IpAddr,
+    erfda: NaiveDateTime,
+}
+
+#[derive(Default)]
+struct Tables {
+    last_id: i32,
+    devices: Vec<Device>,
+    sightings: Vec<Sighting>,
+    claims: Vec<Claim>,
+    forced: Option<StatusOverride>,
+    lease: Option<(String, NaiveDateTime)>,
+    infrastructure: Vec<Infrastructure>,
+}
+
+impl Tables {
+    fn next_id(&mut self) -> i32 {
+        self.last_id += 1;
+        self.last_id
+    }
+}
+
+/// Keeps everything in memory, used for tests and trying out mac4nick
+/// without a database (`memory://`). Nothing survives a restart.
+pub(super) struct MemoryRepository {
+    tables: Mutex<Tables>,
+    windows: Windows,
+    sessions: MemoryStore,
+}
+
+impl MemoryRepository {
+    pub(super) fn new(windows: Windows) -> Self {
+        Self {
+            tables: Mutex::default(),
+            windows,
+            sessions: MemoryStore::default(),
+        }
+    }
+
+    fn present_since(&self) -> NaiveDateTime {
+        now() - TimeDelta::from_std(self.windows.present).unwrap_or(TimeDelta::MAX)
+    }
+
+    fn unassigned_since(&self) -> NaiveDateTime {
+        now() - TimeDelta::from_std(self.windows.unassigned).unwrap_or(TimeDelta::MAX)
+    }
+}
+
+fn now() -> NaiveDateTime {
+    Utc::now().naive_utc()
+}
+
+#[async_trait]
+impl Repository for MemoryRepository {
+    async fn migrate(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn sessions(&self) -> Sessions {
+        Sessions::Memory(self.sessions.clone())
+    }
+
+    async fn ping(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn create_device(&self, device: &Device) -> Result<()> {
+        if device.id.is_some() {
+            return Err(anyhow!("device has already been created"));
+        }
+        let mut tables = self.tables.lock().unwrap();
+        if tables.devices.iter().any(|d| d.macaddr == device.macaddr) {
+            return Err(anyhow!("unable to create device entry"));
+        }
+        let id = tables.next_id();
+        tables.devices.push(Device {
+            id: Some(id),
+            ..device.clone()
+        });
+        Ok(())
+    }
+
+    async fn devices_for_user(&self, user: &str) -> Result<Vec<Device>> {
+        let tables = self.tables.lock().unwrap();
+        let now = now();
+        let present_since = self.present_since();
+        let mut devices: Vec<Device> = tables
+            .devices
+            .iter()
+            .filter(|d| d.nickname.eq_ignore_ascii_case(user))
+            .map(|d| {
+                let sightings: Vec<&Sighting> = tables
+                    .sightings
+                    .iter()
+                    .filter(|s| s.macaddr == d.macaddr)
+                    .collect();
+                let last_seen = sightings.iter().map(|s| s.erfda).max();
+                let mut addresses: Vec<IpAddr> = sightings
+                    .iter()
+                    .filter(|s| Some(s.erfda) == last_seen)
+                    .map(|s| s.ipaddr)
+                    .collect();
+                addresses.sort();
+                addresses.dedup();
+                Device {
+                    present: last_seen.is_some_and(|t| t > present_since),
+                    stale: last_seen.is_some_and(|t| t < now - TimeDelta::days(30)),
+                    last_seen,
+                    first_seen: sightings.iter().map(|s| s.erfda).min(),
+                    sightings: sightings
+                        .iter()
+                        .map(|s| s.erfda)
+                        .filter(|erfda| *erfda > now - TimeDelta::days(30))
+                        .collect::<HashSet<_>>()
+                        .len() as i64,
+                    addresses,
+                    ..d.clone()
+                }
+            })
+            .collect();
+        devices.sort_by_key(|d| std::cmp::Reverse(d.last_seen));
+        Ok(devices)
+    }
+
+    async fn present_devices(&self) -> Result<Vec<Device>> {
+        let tables = self.tables.lock().unwrap();
+        let since = self.present_since();
+        Ok(tables
+            .devices
+            .iter()
+            .filter(|d| {
+                tables
+                    .sightings
+                    .iter()
+                    .any(|s| s.macaddr == d.macaddr && s.erfda > since)
+            })
+            .map(|d| Device {
+                present: true,
+                ..d.clone()
+            })
+            .collect())
+    }
+
+    async fn devices(&self) -> Result<Vec<Device>> {
+        let tables = self.tables.lock().unwrap();
+        let mut devices = tables.devices.clone();
+        devices.sort_by(|a, b| (&a.nickname, &a.macaddr).cmp(&(&b.nickname, &b.macaddr)));
+        Ok(devices)
+    }
+
+    async fn device_for_mac(&self, macaddr: &str) -> Result<Option<Device>> {
+        let tables = self.tables.lock().unwrap();
+        Ok(tables
+            .devices
+            .iter()
+            .find(|d| d.macaddr == macaddr)
+            .cloned())
+    }
+
+    async fn devices_for_macs(&self, macaddrs: &[String]) -> Result<Vec<Device>> {
+        let tables = self.tables.lock().unwrap();
+        Ok(tables
+            .devices
+            .iter()
+            .filter(|d| macaddrs.contains(&d.macaddr))
+            .cloned()
+            .collect())
+    }
+
+    async fn update_device(&self, device: &Device) -> Result<()> {
+        let id = device.id()?;
+        let mut tables = self.tables.lock().unwrap();
+        if let Some(stored) = tables.devices.iter_mut().find(|d| d.id == Some(id)) {
+            stored.privacy = device.privacy;
+            stored.descr = device.descr.clone();
+        }
+        Ok(())
+    }
+
+    async fn delete_device(&self, device: &Device) -> Result<()> {
+        let id = device.id()?;
+        let mut tables = self.tables.lock().unwrap();
+        tables.devices.retain(|d| d.id != Some(id));
+        Ok(())
+    }
+
+    async fn flag_departed(&self, members: &[String]) -> Result<u64> {
+        if members.is_empty() {
+            return Err(anyhow!("refusing to flag the devices of every member"));
+        }
+        let mut tables = self.tables.lock().unwrap();
+        let mut flagged = 0;
+        for device in tables.devices.iter_mut() {
+            let member = members
+                .iter()
+                .any(|member| member.eq_ignore_ascii_case(&device.nickname));
+            match device.departed {
+                None if !member => {
+                    device.departed = Some(now());
+                    flagged += 1;
+                }
+                Some(_) if member => device.departed = None,
+                _ => {}
+            }
+        }
+        Ok(flagged)
+    }
+
+    async fn log_all(&self, alive: &[AliveDevice]) -> Result<()> {
+        let mut tables = self.tables.lock().unwrap();
+        let erfda = now();
+        tables.sightings.extend(alive.iter().map(|alive| Sighting {
+            macaddr: alive.macaddr.clone(),
+            ipaddr: alive.ipaddr,
+            erfda,
+        }));
+        Ok(())
+    }
+
+    async fn unassigned(&self) -> Result<Vec<AliveDevice>> {
+        let tables = self.tables.lock().unwrap();
+        let since = self.unassigned_since();
+        let mut unassigned: Vec<&Sighting> = vec![];
+        for sighting in tables.sightings.iter().rev() {
+            if sighting.erfda > since
+                && !tables.devices.iter().any(|d| d.macaddr == sighting.macaddr)
+                && !tables
+                    .infrastructure
+                    .iter()
+                    .any(|i| i.macaddr == sighting.macaddr)
+                && !unassigned.iter().any(|s| s.macaddr == sighting.macaddr)
+            {
+                unassigned.push(sighting);
+            }
+        }
+        Ok(unassigned
+            .into_iter()
+            .map(|s| AliveDevice {
+                macaddr: s.macaddr.clone(),
+                ipaddr: s.ipaddr,
+            })
+            .collect())
+    }
+
+    async fn guests(&self) -> Result<Vec<AliveDevice>> {
+        let tables = self.tables.lock().unwrap();
+        let since = self.present_since();
+        let mut guests: Vec<AliveDevice> = vec![];
+        for sighting in &tables.sightings {
+            if sighting.erfda > since
+                && !tables.devices.iter().any(|d| d.macaddr == sighting.macaddr)
+                && !tables
+                    .infrastructure
+                    .iter()
+                    .any(|i| i.macaddr == sighting.macaddr)
+                && !guests
+                    .iter()
+                    .any(|g| g.macaddr == sighting.macaddr && g.ipaddr == sighting.ipaddr)
+            {
+                guests.push(AliveDevice {
+                    macaddr: sighting.macaddr.clone(),
+                    ipaddr: sighting.ipaddr,
+                });
+            }
+        }
+        guests.sort_by(|a, b| a.macaddr.cmp(&b.macaddr));
+        Ok(guests)
+    }
+
+    async fn alive_for_ip(&self, ip: IpAddr) -> Result<Option<AliveDevice>> {
+        let tables = self.tables.lock().unwrap();
+        let since = self.unassigned_since();
+        Ok(tables
+            .sightings
+            .iter()
+            .rev()
+            .find(|s| s.ipaddr == ip && s.erfda > since)
+            .map(|s| AliveDevice {
+                macaddr: s.macaddr.clone(),
+                ipaddr: s.ipaddr,
+            }))
+    }
+
+    async fn create_claim(&self, claim: &Claim) -> Result<bool> {
+        if claim.id.is_some() {
+            return Err(anyhow!("claim has already been created"));
+        }
+        let mut tables = self.tables.lock().unwrap();
+        let since = now() - TimeDelta::days(1);
+        if tables
+            .claims
+            .iter()
+            .any(|c| c.macaddr == claim.macaddr && c.created.is_some_and(|created| created > since))
+        {
+            return Ok(false);
+        }
+        let id = tables.next_id();
+        tables.claims.push(Claim {
+            id: Some(id),
+            created: Some(now()),
+            ..claim.clone()
+        });
+        Ok(true)
+    }
+
+    async fn claims_for_user(&self, user: &str) -> Result<Vec<Claim>> {
+        let tables = self.tables.lock().unwrap();
+        let since = now() - TimeDelta::days(1);
+        Ok(tables
+            .claims
+            .iter()
+            .rev()
+            .filter(|c| c.nickname == user && c.created.is_some_and(|t| t > since))
+            .cloned()
+            .collect())
+    }
+
+    async fn seen_claims(&self, user: &str) -> Result<Vec<Claim>> {
+        let tables = self.tables.lock().unwrap();
+        let since = now() - TimeDelta::days(1);
+        Ok(tables
+            .claims
+            .iter()
+            .filter(|c| c.nickname == user)
+            .filter(|c| {
+                c.created.is_some_and(|created| {
+                    created > since
+                        && tables
+                            .sightings
+                            .iter()
+                            .any(|s| s.macaddr == c.macaddr && s.erfda >= created)
+                })
+            })
+            .cloned()
+            .collect())
+    }
+
+    async fn delete_claim(&self, claim: &Claim) -> Result<()> {
+        let id = claim.id()?;
+        let mut tables = self.tables.lock().unwrap();
+        tables.claims.retain(|c| c.id != Some(id));
+        Ok(())
+    }
+
+    async fn force_status(&self, forced: &StatusOverride, duration: Duration) -> Result<()> {
+        let mut tables = self.tables.lock().unwrap();
+        tables.forced = Some(StatusOverride {
+            expires: Some(now() + TimeDelta::from_std(duration)?),
+            ..forced.clone()
+        });
+        Ok(())
+    }
+
+    async fn forced_status(&self) -> Result<Option<StatusOverride>> {
+        let tables = self.tables.lock().unwrap();
+        let now = now();
+        Ok(tables
+            .forced
+            .clone()
+            .filter(|forced| forced.expires.is_some_and(|expires| expires > now)))
+    }
+
+    async fn clear_forced_status(&self) -> Result<()> {
+        self.tables.lock().unwrap().forced = None;
+        Ok(())
+    }
+
+    async fn acquire_lease(&self, holder: &str, ttl: Duration) -> Result<bool> {
+        let mut tables = self.tables.lock().unwrap();
+        let now = now();
+        let available = match &tables.lease {
+            Some((current, expires)) => current == holder || *expires <= now,
+            None => true,
+        };
+        if available {
+            let ttl = TimeDelta::from_std(ttl).unwrap_or(TimeDelta::MAX);
+            tables.lease = Some((holder.to_string(), now + ttl));
+        }
+        Ok(available)
+    }
+
+    async fn release_lease(&self, holder: &str) -> Result<()> {
+        let mut tables = self.tables.lock().unwrap();
+        if tables
+            .lease
+            .as_ref()
+            .is_some_and(|(current, _)| current == holder)
+        {
+            tables.lease = None;
+        }
+        Ok(())
+    }
+
+    async fn create_infrastructure(&self, infrastructure: &Infrastructure) -> Result<()> {
+        if infrastructure.id.is_some() {
+            return Err(anyhow!("infrastructure has already been created"));
+        }
+        let mut tables = self.tables.lock().unwrap();
+        if tables
+            .infrastructure
+            .iter()
+            .any(|i| i.macaddr == infrastructure.macaddr || i.name == infrastructure.name)
+        {
+            return Err(anyhow!("unable to create infrastructure"));
+        }
+        let id = tables.next_id();
+        tables.infrastructure.push(Infrastructure {
+            id: Some(id),
+            ..infrastructure.clone()
+        });
+        Ok(())
+    }
+
+    async fn infrastructure(&self) -> Result<Vec<Infrastructure>> {
+        let tables = self.tables.lock().unwrap();
+        let since = self.present_since();
+        let mut infrastructure: Vec<Infrastructure> = tables
+            .infrastructure
+            .iter()
+            .map(|i| {
+                let last_seen = tables
+                    .sightings
+                    .iter()
+                    .filter(|s| s.macaddr == i.macaddr)
+                    .map(|s| s.erfda)
+                    .max();
+                Infrastructure {
+                    last_seen,
+                    online: last_seen.is_some_and(|t| t > since),
+                    ..i.clone()
+                }
+            })
+            .collect();
+        infrastructure.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(infrastructure)
+    }
+
+    async fn infrastructure_for_mac(&self, macaddr: &str) -> Result<Option<Infrastructure>> {
+        let tables = self.tables.lock().unwrap();
+        Ok(tables
+            .infrastructure
+            .iter()
+            .find(|i| i.macaddr == macaddr)
+            .cloned())
+    }
+
+    async fn delete_infrastructure(&self, infrastructure: &Infrastructure) -> Result<()> {
+        let id = infrastructure.id()?;
+        let mut tables = self.tables.lock().unwrap();
+        tables.infrastructure.retain(|i| i.id != Some(id));
+        Ok(())
+    }
+}
@@ -0,0 +1,447 @@
+use crate::mac;
+use anyhow::{Context, Result, anyhow};
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use std::convert::TryFrom;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[cfg(test)]
+mod memory;
+mod mysql;
+mod postgres;
+mod sessions;
+mod sqlite;
+#[cfg(test)]
+mod tests;
+
+pub use sessions::Sessions;
+
+/// Storage of registered devices, sightings and claims. Implemented for
+/// every supported database, see [`connect`].
+#[async_trait]
+pub trait Repository: Send + Sync {
+    /// Creates the schema or upgrades it to the latest version.
+    async fn migrate(&self) -> Result<()>;
+    /// Checks that the database answers.
+    async fn ping(&self) -> Result<()>;
+    /// Session store of the web interface in the same database.
+    fn sessions(&self) -> Sessions;
+
+    async fn create_device(&self, device: &Device) -> Result<()>;
+    /// Returns the devices of a member including their presence information.
+    async fn devices_for_user(&self, user: &str) -> Result<Vec<Device>>;
+    /// Returns the registered devices seen within the present window.
+    async fn present_devices(&self) -> Result<Vec<Device>>;
+    /// Returns all registered devices without presence information, ordered
+    /// by owner.
+    async fn devices(&self) -> Result<Vec<Device>>;
+    async fn device_for_mac(&self, macaddr: &str) -> Result<Option<Device>>;
+    /// Returns the registered devices among the given addresses in a single
+    /// query.
+    async fn devices_for_macs(&self, macaddrs: &[String]) -> Result<Vec<Device>>;
+    async fn update_device(&self, device: &Device) -> Result<()>;
+    async fn delete_device(&self, device: &Device) -> Result<()>;
+    /// Flags the devices of everyone not among `members` as departed and
+    /// clears the flag of members who returned, comparing nicknames
+    /// case-insensitively. Returns the number of newly flagged devices.
+    async fn flag_departed(&self, members: &[String]) -> Result<u64>;
+
+    /// Records the devices seen by a scan with a single insert.
+    async fn log_all(&self, alive: &[AliveDevice]) -> Result<()>;
+    /// Returns recently seen devices that nobody has registered and that are
+    /// not infrastructure.
+    async fn unassigned(&self) -> Result<Vec<AliveDevice>>;
+    /// Returns each address unregistered devices used within the present
+    /// window, leaving out infrastructure.
+    async fn guests(&self) -> Result<Vec<AliveDevice>>;
+    /// Returns the device that most recently used the given address.
+    async fn alive_for_ip(&self, ip: IpAddr) -> Result<Option<AliveDevice>>;
+
+    /// Records a claim unless the address has a pending claim already and
+    /// returns whether it did.
+    async fn create_claim(&self, claim: &Claim) -> Result<bool>;
+    /// Returns the claims of a member that have not expired yet.
+    async fn claims_for_user(&self, user: &str) -> Result<Vec<Claim>>;
+    /// Returns the claims of a member whose device has been seen by a scan
+    /// after the claim was made.
+    async fn seen_claims(&self, user: &str) -> Result<Vec<Claim>>;
+    async fn delete_claim(&self, claim: &Claim) -> Result<()>;
+
+    /// Forces the space status for the given duration, replacing any status
+    /// forced before.
+    async fn force_status(&self, forced: &StatusOverride, duration: Duration) -> Result<()>;
+    /// Returns the forced status unless it has expired.
+    async fn forced_status(&self) -> Result<Option<StatusOverride>>;
+    async fn clear_forced_status(&self) -> Result<()>;
+
+    /// Takes or renews the lease that allows a single instance to scan.
+    /// Returns whether `holder` holds the lease for the next `ttl`, another
+    /// holder only takes over once the lease has expired.
+    async fn acquire_lease(&self, holder: &str, ttl: Duration) -> Result<bool>;
+
+    /// Gives up the lease if `holder` holds it, so another instance takes
+    /// over right away.
+    async fn release_lease(&self, holder: &str) -> Result<()>;
+
+    async fn create_infrastructure(&self, infrastructure: &Infrastructure) -> Result<()>;
+    /// Returns all infrastructure devices with their online state.
+    async fn infrastructure(&self) -> Result<Vec<Infrastructure>>;
+    async fn infrastructure_for_mac(&self, macaddr: &str) -> Result<Option<Infrastructure>>;
+    async fn delete_infrastructure(&self, infrastructure: &Infrastructure) -> Result<()>;
+}
+
+/// Size of the connection pool shared by the web interface and the scanner.
+#[derive(Clone, Copy, Debug)]
+pub struct PoolSize {
+    pub max_connections: u32,
+    pub min_connections: u32,
+}
+
+impl Default for PoolSize {
+    fn default() -> Self {
+        Self {
+            max_connections: 10,
+            min_connections: 0,
+        }
+    }
+}
+
+impl PoolSize {
+    fn options<DB: sqlx::Database>(&self) -> sqlx::pool::PoolOptions<DB> {
+        sqlx::pool::PoolOptions::new()
+            .max_connections(self.max_connections)
+            .min_connections(self.min_connections)
+    }
+}
+
+/// How long after their last sighting devices count as present, and as
+/// unassigned if nobody registered them. The web interface, the MQTT output
+/// and the statistics all decide presence with these windows.
+#[derive(Clone, Copy, Debug)]
+pub struct Windows {
+    pub present: Duration,
+    pub unassigned: Duration,
+}
+
+impl Default for Windows {
+    fn default() -> Self {
+        Self {
+            present: Duration::from_secs(30 * 60),
+            unassigned: Duration::from_secs(30 * 60),
+        }
+    }
+}
+
+impl Windows {
+    fn present_secs(&self) -> i64 {
+        self.present.as_secs() as i64
+    }
+
+    fn unassigned_secs(&self) -> i64 {
+        self.unassigned.as_secs() as i64
+    }
+}
+
+/// Opens the database named by the scheme of the dsn (`mysql://`,
+/// `postgres://` or `sqlite://`). Tests also use `memory://`, which loses
+/// everything on restart.
+pub async fn connect(dsn: &str, size: PoolSize, windows: Windows) -> Result<Arc<dyn Repository>> {
+    let scheme = dsn.split(':').next().unwrap_or_default();
+    let repository: Arc<dyn Repository> = match scheme {
+        "mysql" | "mariadb" => Arc::new(mysql::MySqlRepository::connect(dsn, size, windows).await?),
+        "postgres" | "postgresql" => {
+            Arc::new(postgres::PgRepository::connect(dsn, size, windows).await?)
+        }
+        "sqlite" => Arc::new(sqlite::SqliteRepository::connect(dsn, size, windows).await?),
+        #[cfg(test)]
+        "memory" => Arc::new(memory::MemoryRepository::new(windows)),
+        _ => return Err(anyhow!("unsupported database \"{}\"", scheme)),
+    };
+    Ok(repository)
+}
+
+#[derive(sqlx::FromRow, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Device {
+    pub id: Option<i32>,
+    pub macaddr: String,
+    pub nickname: String,
+    pub descr: String,
+    pub privacy: PrivacyLevel,
+    #[sqlx(default)]
+    pub present: bool,
+    #[sqlx(default)]
+    pub last_seen: Option<NaiveDateTime>,
+    #[sqlx(default)]
+    pub stale: bool,
+    #[sqlx(default)]
+    pub first_seen: Option<NaiveDateTime>,
+    #[sqlx(default)]
+    pub sightings: i64,
+    /// Since when the owner is no longer listed as an active member.
+    #[sqlx(default)]
+    pub departed: Option<NaiveDateTime>,
+    /// Addresses the device used when it was last seen.
+    #[sqlx(skip)]
+    pub addresses: Vec<IpAddr>,
+}
+
+impl Device {
+    pub fn new(macaddr: String, nickname: String, descr: String, privacy: PrivacyLevel) -> Self {
+        Self {
+            id: None,
+            macaddr,
+            nickname,
+            descr,
+            privacy,
+            present: false,
+            last_seen: None,
+            stale: false,
+            first_seen: None,
+            sightings: 0,
+            departed: None,
+            addresses: vec![],
+        }
+    }
+
+    fn id(&self) -> Result<i32> {
+        self.id.ok_or_else(|| anyhow!("selected device has no id"))
+    }
+
+    pub fn get_public_username(&self) -> String {
+        match self.privacy {
+            PrivacyLevel::ShowUserAndDevice => self.nickname.clone(),
+            PrivacyLevel::ShowUser => self.nickname.clone(),
+            PrivacyLevel::ShowAnonymous => "Anonymous".to_string(),
+            PrivacyLevel::HideUser => unreachable!("cannot get username for HideUser"),
+            PrivacyLevel::DontLog => unreachable!("cannot get username for DontLog"),
+        }
+    }
+
+    pub fn loggable(&self) -> bool {
+        !matches!(self.privacy, PrivacyLevel::DontLog)
+    }
+
+    pub fn randomized(&self) -> bool {
+        mac::is_locally_administered(&self.macaddr)
+    }
+}
+
+#[derive(sqlx::Type, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Hash)]
+#[repr(i16)]
+pub enum PrivacyLevel {
+    ShowUserAndDevice = 0,
+    ShowUser = 1,
+    ShowAnonymous = 2,
+    HideUser = 3,
+    DontLog = 4,
+}
+
+impl PrivacyLevel {
+    pub fn selected(&self, level: PrivacyLevel) -> &'static str {
+        if *self as u8 == level as u8 {
+            "selected"
+        } else {
+            ""
+        }
+    }
+}
+
+impl TryFrom<i8> for PrivacyLevel {
+    type Error = &'static str;
+
+    fn try_from(i: i8) -> Result<Self, Self::Error> {
+        let level = match i {
+            0 => PrivacyLevel::ShowUserAndDevice,
+            1 => PrivacyLevel::ShowUser,
+            2 => PrivacyLevel::ShowAnonymous,
+            3 => PrivacyLevel::HideUser,
+            4 => PrivacyLevel::DontLog,
+            _ => return Err("invalid privacy level"),
+        };
+        Ok(level)
+    }
+}
+
+/// Fills in the addresses each device used at its last sighting.
+fn with_addresses(mut devices: Vec<Device>, seen: Vec<AliveDevice>) -> Vec<Device> {
+    for alive in seen {
+        if let Some(device) = devices.iter_mut().find(|d| d.macaddr == alive.macaddr)
+            && !device.addresses.contains(&alive.ipaddr)
+        {
+            device.addresses.push(alive.ipaddr);
+        }
+    }
+    for device in devices.iter_mut() {
+        device.addresses.sort();
+    }
+    devices
+}
+
+/// An IPv4 or IPv6 address, stored as its 4 or 16 bytes in network order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpAddress(pub IpAddr);
+
+impl IpAddress {
+    fn octets(&self) -> Vec<u8> {
+        match self.0 {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        }
+    }
+}
+
+impl From<IpAddress> for IpAddr {
+    fn from(ip: IpAddress) -> Self {
+        ip.0
+    }
+}
+
+impl<DB: sqlx::Database> sqlx::Type<DB> for IpAddress
+where
+    Vec<u8>: sqlx::Type<DB>,
+{
+    fn type_info() -> DB::TypeInfo {
+        <Vec<u8> as sqlx::Type<DB>>::type_info()
+    }
+
+    fn compatible(ty: &DB::TypeInfo) -> bool {
+        <Vec<u8> as sqlx::Type<DB>>::compatible(ty)
+    }
+}
+
+impl<'q, DB: sqlx::Database> sqlx::Encode<'q, DB> for IpAddress
+where
+    Vec<u8>: sqlx::Encode<'q, DB>,
+{
+    fn encode_by_ref(
+        &self,
+        buf: &mut DB::ArgumentBuffer<'q>,
+    ) -> Result<sqlx::encode::IsNull, sqlx::error::BoxDynError> {
+        self.octets().encode(buf)
+    }
+}
+
+impl<'r, DB: sqlx::Database> sqlx::Decode<'r, DB> for IpAddress
+where
+    Vec<u8>: sqlx::Decode<'r, DB>,
+{
+    fn decode(value: DB::ValueRef<'r>) -> Result<Self, sqlx::error::BoxDynError> {
+        let bytes = <Vec<u8> as sqlx::Decode<DB>>::decode(value)?;
+        let ip = if let Ok(octets) = <[u8; 4]>::try_from(bytes.as_slice()) {
+            IpAddr::from(octets)
+        } else if let Ok(octets) = <[u8; 16]>::try_from(bytes.as_slice()) {
+            IpAddr::from(octets)
+        } else {
+            return Err(format!("invalid ip address of {} bytes", bytes.len()).into());
+        };
+        Ok(Self(ip))
+    }
+}
+
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct AliveDevice {
+    pub macaddr: String,
+    #[sqlx(try_from = "IpAddress")]
+    pub ipaddr: IpAddr,
+}
+
+impl AliveDevice {
+    pub fn new(macaddr: &str, ip: &str) -> Result<AliveDevice> {
+        let ip = IpAddr::from_str(ip).context("unable to parse ip address")?;
+        Ok(AliveDevice {
+            macaddr: macaddr.to_string(),
+            ipaddr: ip.to_canonical(),
+        })
+    }
+
+    pub fn randomized(&self) -> bool {
+        mac::is_locally_administered(&self.macaddr)
+    }
+
+    pub fn ip(&self) -> IpAddr {
+        self.ipaddr
+    }
+}
+
+/// A pending registration of a device that has to show up in a scan after
+/// the claim has been made before it is assigned to the member.
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct Claim {
+    pub id: Option<i32>,
+    pub macaddr: String,
+    pub nickname: String,
+    pub descr: String,
+    pub privacy: PrivacyLevel,
+    pub created: Option<NaiveDateTime>,
+}
+
+impl Claim {
+    fn id(&self) -> Result<i32> {
+        self.id.ok_or_else(|| anyhow!("selected claim has no id"))
+    }
+
+    pub fn device(&self) -> Device {
+        Device::new(
+            self.macaddr.clone(),
+            self.nickname.clone(),
+            self.descr.clone(),
+            self.privacy,
+        )
+    }
+}
+
+/// A space status forced by an admin, it wins over all other rules until it
+/// expires.
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct StatusOverride {
+    pub open: bool,
+    pub nickname: String,
+    pub expires: Option<NaiveDateTime>,
+}
+
+impl StatusOverride {
+    pub fn new(open: bool, nickname: String) -> Self {
+        Self {
+            open,
+            nickname,
+            expires: None,
+        }
+    }
+}
+
+/// A device owned by the space, e.g. a printer or an access point. It is
+/// managed by admins and monitored instead of counted as a member's device.
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct Infrastructure {
+    pub id: Option<i32>,
+    pub macaddr: String,
+    /// Used in the MQTT topic of the device.
+    pub name: String,
+    pub descr: String,
+    #[sqlx(default)]
+    pub last_seen: Option<NaiveDateTime>,
+    /// Whether the device has been seen within the present window.
+    #[sqlx(default)]
+    pub online: bool,
+}
+
+impl Infrastructure {
+    pub fn new(macaddr: String, name: String, descr: String) -> Self {
+        Self {
+            id: None,
+            macaddr,
+            name,
+            descr,
+            last_seen: None,
+            online: false,
+        }
+    }
+
+    fn id(&self) -> Result<i32> {
+        self.id
+            .ok_or_else(|| anyhow!("selected infrastructure has no id"))
+    }
+}
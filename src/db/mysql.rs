@@ -0,0 +1,781 @@
+use super::{
+    AliveDevice, Claim, Device, Infrastructure, IpAddress, PoolSize, Repository, Sessions,
+    StatusOverride, Windows, with_addresses,
+};
+use anyhow::{Context, Result, anyhow, bail};
+use async_trait::async_trait;
+use sqlx::migrate::Migrator;
+use sqlx::{MySql, MySqlPool, QueryBuilder};
+use std::net::IpAddr;
+use std::time::Duration;
+use tower_sessions_sqlx_store::MySqlStore;
+
+static MIGRATOR: Migrator = sqlx::migrate!("migrations/mysql");
+
+pub(super) struct MySqlRepository {
+    pool: MySqlPool,
+    windows: Windows,
+    sessions: Sessions,
+}
+
+/// Keeps the sessions in the table of the migrations, qualified with the
+/// name of the database.
+pub(super) fn session_store(pool: MySqlPool, database: &str) -> Result<Sessions> {
+    MySqlStore::new(pool)
+        .with_schema_name(database)
+        .and_then(|store| store.with_table_name("sessions"))
+        .map(Sessions::MySql)
+        .map_err(anyhow::Error::msg)
+        .with_context(|| format!("the database name {:?} is not supported", database))
+}
+
+impl MySqlRepository {
+    pub(super) async fn connect(dsn: &str, size: PoolSize, windows: Windows) -> Result<Self> {
+        let pool = size
+            .options()
+            .connect(dsn)
+            .await
+            .context("unable to open database connection")?;
+        let database: Option<String> = sqlx::query_scalar("SELECT DATABASE()")
+            .fetch_one(&pool)
+            .await
+            .context("unable to select database name")?;
+        let database = database.context("the dsn does not select a database")?;
+        let sessions = session_store(pool.clone(), &database)?;
+        Ok(Self {
+            pool,
+            windows,
+            sessions,
+        })
+    }
+
+    /// Runs a query against the tables of the former `create.sql`, which
+    /// return nothing before the first migration created them.
+    async fn legacy_rows<T>(&self, query: &'static str) -> Result<Vec<T>>
+    where
+        T: for<'r> sqlx::FromRow<'r, sqlx::mysql::MySqlRow> + Send + Unpin,
+    {
+        match sqlx::query_as(query).fetch_all(&self.pool).await {
+            Ok(rows) => Ok(rows),
+            // ER_NO_SUCH_TABLE
+            Err(sqlx::Error::Database(err)) if err.code().as_deref() == Some("42S02") => Ok(vec![]),
+            Err(err) => Err(err).context("unable to check the registrations"),
+        }
+    }
+
+    /// Refuses to migrate installations from the former `create.sql` with
+    /// rows the stricter schema can not hold, instead of dropping them: mac
+    /// addresses registered more than once, registrations without a mac
+    /// address or nickname and sightings without an address or time.
+    async fn check_legacy_rows(&self) -> Result<()> {
+        let mut problems = vec![];
+
+        let duplicates: Vec<(String, String)> = self
+            .legacy_rows(
+                "
+SELECT
+  LOWER(macaddr),
+  CAST(GROUP_CONCAT(nickname ORDER BY id SEPARATOR ', ') AS CHAR)
+FROM
+  mac_to_nick
+WHERE
+  macaddr IS NOT NULL
+  AND nickname IS NOT NULL
+GROUP BY
+  LOWER(macaddr)
+HAVING
+  COUNT(*) > 1
+",
+            )
+            .await?;
+        if !duplicates.is_empty() {
+            problems.push(format!(
+                "mac addresses registered more than once: {}",
+                duplicates
+                    .iter()
+                    .map(|(macaddr, nicknames)| format!("{} ({})", macaddr, nicknames))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
+
+        let incomplete: Vec<(i32,)> = self
+            .legacy_rows("SELECT id FROM mac_to_nick WHERE macaddr IS NULL OR nickname IS NULL")
+            .await?;
+        if !incomplete.is_empty() {
+            problems.push(format!(
+                "registrations without a mac address or nickname: id {}",
+                incomplete
+                    .iter()
+                    .map(|(id,)| id.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
+
+        let sightings: Vec<(i64,)> = self
+            .legacy_rows("SELECT COUNT(*) FROM alive_hosts WHERE macaddr IS NULL OR erfda IS NULL")
+            .await?;
+        if let Some((count,)) = sightings.first()
+            && *count > 0
+        {
+            problems.push(format!(
+                "{} sightings in alive_hosts without a mac address or time",
+                count
+            ));
+        }
+
+        if !problems.is_empty() {
+            bail!(
+                "{}; fix or delete these rows and migrate again",
+                problems.join("; ")
+            );
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Repository for MySqlRepository {
+    async fn migrate(&self) -> Result<()> {
+        self.check_legacy_rows().await?;
+        MIGRATOR
+            .run(&self.pool)
+            .await
+            .context("unable to migrate database")
+    }
+
+    fn sessions(&self) -> Sessions {
+        self.sessions.clone()
+    }
+
+    async fn ping(&self) -> Result<()> {
+        sqlx::query("SELECT 1")
+            .execute(&self.pool)
+            .await
+            .context("unable to reach the database")
+            .and(Ok(()))
+    }
+
+    async fn create_device(&self, device: &Device) -> Result<()> {
+        if device.id.is_some() {
+            return Err(anyhow!("device has already been created"));
+        }
+        sqlx::query(
+            "
+INSERT
+INTO mac_to_nick
+(macaddr, nickname, descr, privacy, created)
+VALUES
+(?, ?, ?, ?, NOW())
+",
+        )
+        .bind(&device.macaddr)
+        .bind(&device.nickname)
+        .bind(&device.descr)
+        .bind(device.privacy)
+        .execute(&self.pool)
+        .await
+        .context("unable to create device entry")
+        .and(Ok(()))
+    }
+
+    async fn devices_for_user(&self, user: &str) -> Result<Vec<Device>> {
+        let devices = sqlx::query_as(
+            "
+SELECT
+  mtn.*,
+  MAX(al.erfda) last_seen,
+  MIN(al.erfda) first_seen,
+  COUNT(DISTINCT IF(al.erfda > NOW() - INTERVAL 30 DAY, al.erfda, NULL)) sightings,
+  IF(MAX(al.erfda) > NOW() - INTERVAL ? SECOND, TRUE, FALSE) present,
+  IF(MAX(al.erfda) < NOW() - INTERVAL 30 DAY, TRUE, FALSE) stale
+FROM
+  mac_to_nick mtn
+LEFT OUTER JOIN
+  alive_hosts al
+ON
+  mtn.macaddr = al.macaddr
+WHERE
+  nickname LIKE ?
+GROUP BY
+  mtn.id
+ORDER BY
+  last_seen DESC
+",
+        )
+        .bind(self.windows.present_secs())
+        .bind(user)
+        .fetch_all(&self.pool)
+        .await
+        .context("unable to select by user")?;
+        let seen = sqlx::query_as(
+            "
+SELECT
+  al.macaddr,
+  al.ipaddr
+FROM
+  alive_hosts al
+JOIN (
+  SELECT
+    l.macaddr,
+    MAX(l.erfda) erfda
+  FROM
+    alive_hosts l
+  JOIN
+    mac_to_nick mtn
+  ON
+    l.macaddr = mtn.macaddr
+  WHERE
+    mtn.nickname LIKE ?
+  GROUP BY
+    l.macaddr
+) latest
+ON
+  al.macaddr = latest.macaddr
+  AND al.erfda = latest.erfda
+",
+        )
+        .bind(user)
+        .fetch_all(&self.pool)
+        .await
+        .context("unable to select addresses by user")?;
+        Ok(with_addresses(devices, seen))
+    }
+
+    async fn present_devices(&self) -> Result<Vec<Device>> {
+        let devices: Vec<Device> = sqlx::query_as(
+            "
+SELECT
+  mtn.*
+FROM
+  mac_to_nick mtn
+WHERE
+  EXISTS (
+    SELECT
+      1
+    FROM
+      alive_hosts al
+    WHERE
+      al.macaddr = mtn.macaddr
+      AND al.erfda > NOW() - INTERVAL ? SECOND
+  )
+",
+        )
+        .bind(self.windows.present_secs())
+        .fetch_all(&self.pool)
+        .await
+        .context("unable to select present devices")?;
+        Ok(devices
+            .into_iter()
+            .map(|device| Device {
+                present: true,
+                ..device
+            })
+            .collect())
+    }
+
+    async fn devices(&self) -> Result<Vec<Device>> {
+        sqlx::query_as(
+            "
+SELECT
+  *
+FROM
+  mac_to_nick
+ORDER BY
+  nickname,
+  macaddr
+",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .context("unable to select devices")
+    }
+
+    async fn device_for_mac(&self, macaddr: &str) -> Result<Option<Device>> {
+        sqlx::query_as(
+            "
+SELECT
+  *
+FROM
+  mac_to_nick
+WHERE
+  macaddr = ?
+",
+        )
+        .bind(macaddr)
+        .fetch_optional(&self.pool)
+        .await
+        .context("unable to select by mac")
+    }
+
+    async fn devices_for_macs(&self, macaddrs: &[String]) -> Result<Vec<Device>> {
+        if macaddrs.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut query = QueryBuilder::<MySql>::new("SELECT * FROM mac_to_nick WHERE macaddr IN (");
+        let mut separated = query.separated(", ");
+        for macaddr in macaddrs {
+            separated.push_bind(macaddr);
+        }
+        query
+            .push(")")
+            .build_query_as()
+            .fetch_all(&self.pool)
+            .await
+            .context("unable to select by macs")
+    }
+
+    async fn update_device(&self, device: &Device) -> Result<()> {
+        sqlx::query(
+            "
+UPDATE
+  mac_to_nick
+SET
+  privacy = ?,
+  descr = ?
+WHERE
+  id = ?
+",
+        )
+        .bind(device.privacy)
+        .bind(&device.descr)
+        .bind(device.id()?)
+        .execute(&self.pool)
+        .await
+        .context("unable to update")
+        .and(Ok(()))
+    }
+
+    async fn delete_device(&self, device: &Device) -> Result<()> {
+        sqlx::query(
+            "
+DELETE FROM
+  mac_to_nick
+WHERE
+  id = ?
+LIMIT 1
+",
+        )
+        .bind(device.id()?)
+        .execute(&self.pool)
+        .await
+        .context("unable to delete")
+        .and(Ok(()))
+    }
+
+    async fn flag_departed(&self, members: &[String]) -> Result<u64> {
+        if members.is_empty() {
+            return Err(anyhow!("refusing to flag the devices of every member"));
+        }
+        let mut flag = QueryBuilder::<MySql>::new(
+            "UPDATE mac_to_nick SET departed = NOW() \
+             WHERE departed IS NULL AND LOWER(nickname) NOT IN (",
+        );
+        let mut unflag = QueryBuilder::<MySql>::new(
+            "UPDATE mac_to_nick SET departed = NULL \
+             WHERE departed IS NOT NULL AND LOWER(nickname) IN (",
+        );
+        for query in [&mut flag, &mut unflag] {
+            let mut separated = query.separated(", ");
+            for member in members {
+                separated.push_bind(member.to_lowercase());
+            }
+            query.push(")");
+        }
+        let flagged = flag
+            .build()
+            .execute(&self.pool)
+            .await
+            .context("unable to flag departed members")?;
+        unflag
+            .build()
+            .execute(&self.pool)
+            .await
+            .context("unable to unflag returning members")?;
+        Ok(flagged.rows_affected())
+    }
+
+    async fn log_all(&self, alive: &[AliveDevice]) -> Result<()> {
+        if alive.is_empty() {
+            return Ok(());
+        }
+        QueryBuilder::<MySql>::new("INSERT INTO alive_hosts (macaddr, ipaddr, erfda) ")
+            .push_values(alive, |mut row, alive| {
+                row.push_bind(&alive.macaddr)
+                    .push_bind(IpAddress(alive.ipaddr))
+                    .push("NOW()");
+            })
+            .build()
+            .execute(&self.pool)
+            .await
+            .context("unable to log devices")
+            .and(Ok(()))
+    }
+
+    async fn unassigned(&self) -> Result<Vec<AliveDevice>> {
+        let mut alive: Vec<AliveDevice> = sqlx::query_as(
+            "
+SELECT
+  al.macaddr macaddr,
+  al.ipaddr ipaddr
+FROM
+  alive_hosts al
+JOIN (
+  SELECT
+    macaddr,
+    MAX(erfda) erfda
+  FROM
+    alive_hosts
+  WHERE
+    erfda > NOW() - INTERVAL ? SECOND
+  GROUP BY
+    macaddr
+) latest
+ON
+  al.macaddr = latest.macaddr
+  AND al.erfda = latest.erfda
+LEFT OUTER JOIN
+  mac_to_nick mtn
+ON
+  al.macaddr = mtn.macaddr
+WHERE
+  mtn.nickname IS NULL
+  AND NOT EXISTS (
+    SELECT
+      1
+    FROM
+      infrastructure i
+    WHERE
+      i.macaddr = al.macaddr
+  )
+ORDER BY
+  al.erfda DESC,
+  al.macaddr
+",
+        )
+        .bind(self.windows.unassigned_secs())
+        .fetch_all(&self.pool)
+        .await
+        .context("unable to load alive devices")?;
+        // a device may have been seen with several addresses at once
+        alive.dedup_by(|a, b| a.macaddr == b.macaddr);
+        Ok(alive)
+    }
+
+    async fn guests(&self) -> Result<Vec<AliveDevice>> {
+        sqlx::query_as(
+            "
+SELECT DISTINCT
+  al.macaddr macaddr,
+  al.ipaddr ipaddr
+FROM
+  alive_hosts al
+LEFT OUTER JOIN
+  mac_to_nick mtn
+ON
+  al.macaddr = mtn.macaddr
+WHERE
+  mtn.nickname IS NULL
+  AND NOT EXISTS (
+    SELECT
+      1
+    FROM
+      infrastructure i
+    WHERE
+      i.macaddr = al.macaddr
+  )
+  AND al.erfda > NOW() - INTERVAL ? SECOND
+ORDER BY
+  al.macaddr
+",
+        )
+        .bind(self.windows.present_secs())
+        .fetch_all(&self.pool)
+        .await
+        .context("unable to load guests")
+    }
+
+    async fn alive_for_ip(&self, ip: IpAddr) -> Result<Option<AliveDevice>> {
+        sqlx::query_as(
+            "
+SELECT
+  macaddr,
+  ipaddr
+FROM
+  alive_hosts
+WHERE
+  ipaddr = ?
+  AND erfda > NOW() - INTERVAL ? SECOND
+ORDER BY
+  erfda DESC
+LIMIT 1
+",
+        )
+        .bind(IpAddress(ip))
+        .bind(self.windows.unassigned_secs())
+        .fetch_optional(&self.pool)
+        .await
+        .context("unable to select by ip")
+    }
+
+    async fn create_claim(&self, claim: &Claim) -> Result<bool> {
+        if claim.id.is_some() {
+            return Err(anyhow!("claim has already been created"));
+        }
+        let result = sqlx::query(
+            "
+INSERT
+INTO mac_claims
+(macaddr, nickname, descr, privacy, created)
+SELECT
+  ?, ?, ?, ?, NOW()
+FROM
+  DUAL
+WHERE
+  NOT EXISTS (
+    SELECT
+      1
+    FROM
+      mac_claims
+    WHERE
+      macaddr = ?
+      AND created > NOW() - INTERVAL 1 DAY
+  )
+",
+        )
+        .bind(&claim.macaddr)
+        .bind(&claim.nickname)
+        .bind(&claim.descr)
+        .bind(claim.privacy)
+        .bind(&claim.macaddr)
+        .execute(&self.pool)
+        .await
+        .context("unable to create claim")?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn claims_for_user(&self, user: &str) -> Result<Vec<Claim>> {
+        sqlx::query_as(
+            "
+SELECT
+  *
+FROM
+  mac_claims
+WHERE
+  nickname = ?
+  AND created > NOW() - INTERVAL 1 DAY
+ORDER BY
+  created DESC
+",
+        )
+        .bind(user)
+        .fetch_all(&self.pool)
+        .await
+        .context("unable to select claims by user")
+    }
+
+    async fn seen_claims(&self, user: &str) -> Result<Vec<Claim>> {
+        sqlx::query_as(
+            "
+SELECT
+  mc.*
+FROM
+  mac_claims mc
+WHERE
+  mc.nickname = ?
+  AND mc.created > NOW() - INTERVAL 1 DAY
+  AND EXISTS (
+    SELECT
+      1
+    FROM
+      alive_hosts al
+    WHERE
+      al.macaddr = mc.macaddr
+      AND al.erfda >= mc.created
+  )
+",
+        )
+        .bind(user)
+        .fetch_all(&self.pool)
+        .await
+        .context("unable to select seen claims")
+    }
+
+    async fn delete_claim(&self, claim: &Claim) -> Result<()> {
+        sqlx::query("DELETE FROM mac_claims WHERE id = ? LIMIT 1")
+            .bind(claim.id()?)
+            .execute(&self.pool)
+            .await
+            .context("unable to delete claim")
+            .and(Ok(()))
+    }
+
+    async fn force_status(&self, forced: &StatusOverride, duration: Duration) -> Result<()> {
+        let mut tx = self.pool.begin().await.context("unable to force status")?;
+        sqlx::query("DELETE FROM status_override")
+            .execute(&mut *tx)
+            .await
+            .context("unable to force status")?;
+        sqlx::query(
+            "
+INSERT
+INTO status_override
+(open, nickname, created, expires)
+VALUES
+(?, ?, NOW(), NOW() + INTERVAL ? SECOND)
+",
+        )
+        .bind(forced.open)
+        .bind(&forced.nickname)
+        .bind(duration.as_secs() as i64)
+        .execute(&mut *tx)
+        .await
+        .context("unable to force status")?;
+        tx.commit().await.context("unable to force status")
+    }
+
+    async fn forced_status(&self) -> Result<Option<StatusOverride>> {
+        sqlx::query_as(
+            "
+SELECT
+  open,
+  nickname,
+  expires
+FROM
+  status_override
+WHERE
+  expires > NOW()
+",
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .context("unable to select forced status")
+    }
+
+    async fn clear_forced_status(&self) -> Result<()> {
+        sqlx::query("DELETE FROM status_override")
+            .execute(&self.pool)
+            .await
+            .context("unable to clear forced status")
+            .and(Ok(()))
+    }
+
+    async fn acquire_lease(&self, holder: &str, ttl: Duration) -> Result<bool> {
+        // the assignments are evaluated in order, so the expiry is only
+        // extended if the holder is ours after the first one
+        sqlx::query(
+            "
+INSERT
+INTO scanner_lease
+(id, holder, expires)
+VALUES
+(1, ?, NOW() + INTERVAL ? SECOND)
+ON DUPLICATE KEY UPDATE
+  holder = IF(holder = VALUES(holder) OR expires <= NOW(), VALUES(holder), holder),
+  expires = IF(holder = VALUES(holder), VALUES(expires), expires)
+",
+        )
+        .bind(holder)
+        .bind(ttl.as_secs() as i64)
+        .execute(&self.pool)
+        .await
+        .context("unable to acquire lease")?;
+        let current: String = sqlx::query_scalar("SELECT holder FROM scanner_lease WHERE id = 1")
+            .fetch_one(&self.pool)
+            .await
+            .context("unable to select lease")?;
+        Ok(current == holder)
+    }
+
+    async fn release_lease(&self, holder: &str) -> Result<()> {
+        sqlx::query("DELETE FROM scanner_lease WHERE id = 1 AND holder = ?")
+            .bind(holder)
+            .execute(&self.pool)
+            .await
+            .context("unable to release lease")?;
+        Ok(())
+    }
+
+    async fn create_infrastructure(&self, infrastructure: &Infrastructure) -> Result<()> {
+        if infrastructure.id.is_some() {
+            return Err(anyhow!("infrastructure has already been created"));
+        }
+        sqlx::query(
+            "
+INSERT
+INTO infrastructure
+(macaddr, name, descr, created)
+VALUES
+(?, ?, ?, NOW())
+",
+        )
+        .bind(&infrastructure.macaddr)
+        .bind(&infrastructure.name)
+        .bind(&infrastructure.descr)
+        .execute(&self.pool)
+        .await
+        .context("unable to create infrastructure")
+        .and(Ok(()))
+    }
+
+    async fn infrastructure(&self) -> Result<Vec<Infrastructure>> {
+        sqlx::query_as(
+            "
+SELECT
+  i.id,
+  i.macaddr,
+  i.name,
+  i.descr,
+  MAX(al.erfda) last_seen,
+  IF(MAX(al.erfda) > NOW() - INTERVAL ? SECOND, TRUE, FALSE) online
+FROM
+  infrastructure i
+LEFT OUTER JOIN
+  alive_hosts al
+ON
+  i.macaddr = al.macaddr
+GROUP BY
+  i.id,
+  i.macaddr,
+  i.name,
+  i.descr
+ORDER BY
+  i.name
+",
+        )
+        .bind(self.windows.present_secs())
+        .fetch_all(&self.pool)
+        .await
+        .context("unable to select infrastructure")
+    }
+
+    async fn infrastructure_for_mac(&self, macaddr: &str) -> Result<Option<Infrastructure>> {
+        sqlx::query_as(
+            "
+SELECT
+  id,
+  macaddr,
+  name,
+  descr
+FROM
+  infrastructure
+WHERE
+  macaddr = ?
+",
+        )
+        .bind(macaddr)
+        .fetch_optional(&self.pool)
+        .await
+        .context("unable to select infrastructure by mac")
+    }
+
+    async fn delete_infrastructure(&self, infrastructure: &Infrastructure) -> Result<()> {
+        sqlx::query("DELETE FROM infrastructure WHERE id = ? LIMIT 1")
+            .bind(infrastructure.id()?)
+            .execute(&self.pool)
+            .await
+            .context("unable to delete infrastructure")
+            .and(Ok(()))
+    }
+}
@@ -0,0 +1,681 @@
+use super::{
+    AliveDevice, Claim, Device, Infrastructure, IpAddress, PoolSize, Repository, Sessions,
+    StatusOverride, Windows, with_addresses,
+};
+use anyhow::{Context, Result, anyhow};
+use async_trait::async_trait;
+use sqlx::migrate::Migrator;
+use sqlx::{PgPool, Postgres, QueryBuilder};
+use std::net::IpAddr;
+use std::time::Duration;
+use tower_sessions_sqlx_store::PostgresStore;
+
+static MIGRATOR: Migrator = sqlx::migrate!("migrations/postgres");
+
+pub(super) struct PgRepository {
+    pool: PgPool,
+    windows: Windows,
+    sessions: Sessions,
+}
+
+/// Keeps the sessions in the table of the migrations, qualified with the
+/// schema they create the tables in.
+pub(super) fn session_store(pool: PgPool, schema: &str) -> Result<Sessions> {
+    PostgresStore::new(pool)
+        .with_schema_name(schema)
+        .and_then(|store| store.with_table_name("sessions"))
+        .map(Sessions::Postgres)
+        .map_err(anyhow::Error::msg)
+        .with_context(|| format!("the schema name {:?} is not supported", schema))
+}
+
+impl PgRepository {
+    pub(super) async fn connect(dsn: &str, size: PoolSize, windows: Windows) -> Result<Self> {
+        let pool = size
+            .options()
+            .connect(dsn)
+            .await
+            .context("unable to open database connection")?;
+        let schema: String = sqlx::query_scalar("SELECT current_schema()")
+            .fetch_one(&pool)
+            .await
+            .context("unable to select schema name")?;
+        let sessions = session_store(pool.clone(), &schema)?;
+        Ok(Self {
+            pool,
+            windows,
+            sessions,
+        })
+    }
+}
+
+#[async_trait]
+impl Repository for PgRepository {
+    async fn migrate(&self) -> Result<()> {
+        MIGRATOR
+            .run(&self.pool)
+            .await
+            .context("unable to migrate database")
+    }
+
+    fn sessions(&self) -> Sessions {
+        self.sessions.clone()
+    }
+
+    async fn ping(&self) -> Result<()> {
+        sqlx::query("SELECT 1")
+            .execute(&self.pool)
+            .await
+            .context("unable to reach the database")
+            .and(Ok(()))
+    }
+
+    async fn create_device(&self, device: &Device) -> Result<()> {
+        if device.id.is_some() {
+            return Err(anyhow!("device has already been created"));
+        }
+        sqlx::query(
+            "
+INSERT
+INTO mac_to_nick
+(macaddr, nickname, descr, privacy, created)
+VALUES
+($1, $2, $3, $4, LOCALTIMESTAMP)
+",
+        )
+        .bind(&device.macaddr)
+        .bind(&device.nickname)
+        .bind(&device.descr)
+        .bind(device.privacy)
+        .execute(&self.pool)
+        .await
+        .context("unable to create device entry")
+        .and(Ok(()))
+    }
+
+    async fn devices_for_user(&self, user: &str) -> Result<Vec<Device>> {
+        let devices = sqlx::query_as(
+            "
+SELECT
+  mtn.*,
+  MAX(al.erfda) last_seen,
+  MIN(al.erfda) first_seen,
+  COUNT(DISTINCT al.erfda) FILTER (WHERE al.erfda > LOCALTIMESTAMP - INTERVAL '30 days') sightings,
+  COALESCE(MAX(al.erfda) > LOCALTIMESTAMP - $2 * INTERVAL '1 second', FALSE) present,
+  COALESCE(MAX(al.erfda) < LOCALTIMESTAMP - INTERVAL '30 days', FALSE) stale
+FROM
+  mac_to_nick mtn
+LEFT OUTER JOIN
+  alive_hosts al
+ON
+  mtn.macaddr = al.macaddr
+WHERE
+  nickname ILIKE $1
+GROUP BY
+  mtn.id
+ORDER BY
+  last_seen DESC
+",
+        )
+        .bind(user)
+        .bind(self.windows.present_secs())
+        .fetch_all(&self.pool)
+        .await
+        .context("unable to select by user")?;
+        let seen = sqlx::query_as(
+            "
+SELECT
+  al.macaddr,
+  al.ipaddr
+FROM
+  alive_hosts al
+JOIN (
+  SELECT
+    l.macaddr,
+    MAX(l.erfda) erfda
+  FROM
+    alive_hosts l
+  JOIN
+    mac_to_nick mtn
+  ON
+    l.macaddr = mtn.macaddr
+  WHERE
+    mtn.nickname ILIKE $1
+  GROUP BY
+    l.macaddr
+) latest
+ON
+  al.macaddr = latest.macaddr
+  AND al.erfda = latest.erfda
+",
+        )
+        .bind(user)
+        .fetch_all(&self.pool)
+        .await
+        .context("unable to select addresses by user")?;
+        Ok(with_addresses(devices, seen))
+    }
+
+    async fn present_devices(&self) -> Result<Vec<Device>> {
+        let devices: Vec<Device> = sqlx::query_as(
+            "
+SELECT
+  mtn.*
+FROM
+  mac_to_nick mtn
+WHERE
+  EXISTS (
+    SELECT
+      1
+    FROM
+      alive_hosts al
+    WHERE
+      al.macaddr = mtn.macaddr
+      AND al.erfda > LOCALTIMESTAMP - $1 * INTERVAL '1 second'
+  )
+",
+        )
+        .bind(self.windows.present_secs())
+        .fetch_all(&self.pool)
+        .await
+        .context("unable to select present devices")?;
+        Ok(devices
+            .into_iter()
+            .map(|device| Device {
+                present: true,
+                ..device
+            })
+            .collect())
+    }
+
+    async fn devices(&self) -> Result<Vec<Device>> {
+        sqlx::query_as(
+            "
+SELECT
+  *
+FROM
+  mac_to_nick
+ORDER BY
+  nickname,
+  macaddr
+",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .context("unable to select devices")
+    }
+
+    async fn device_for_mac(&self, macaddr: &str) -> Result<Option<Device>> {
+        sqlx::query_as(
+            "
+SELECT
+  *
+FROM
+  mac_to_nick
+WHERE
+  macaddr = $1
+",
+        )
+        .bind(macaddr)
+        .fetch_optional(&self.pool)
+        .await
+        .context("unable to select by mac")
+    }
+
+    async fn devices_for_macs(&self, macaddrs: &[String]) -> Result<Vec<Device>> {
+        sqlx::query_as(
+            "
+SELECT
+  *
+FROM
+  mac_to_nick
+WHERE
+  macaddr = ANY($1)
+",
+        )
+        .bind(macaddrs)
+        .fetch_all(&self.pool)
+        .await
+        .context("unable to select by macs")
+    }
+
+    async fn update_device(&self, device: &Device) -> Result<()> {
+        sqlx::query(
+            "
+UPDATE
+  mac_to_nick
+SET
+  privacy = $1,
+  descr = $2
+WHERE
+  id = $3
+",
+        )
+        .bind(device.privacy)
+        .bind(&device.descr)
+        .bind(device.id()?)
+        .execute(&self.pool)
+        .await
+        .context("unable to update")
+        .and(Ok(()))
+    }
+
+    async fn delete_device(&self, device: &Device) -> Result<()> {
+        sqlx::query(
+            "
+DELETE FROM
+  mac_to_nick
+WHERE
+  id = $1
+",
+        )
+        .bind(device.id()?)
+        .execute(&self.pool)
+        .await
+        .context("unable to delete")
+        .and(Ok(()))
+    }
+
+    async fn flag_departed(&self, members: &[String]) -> Result<u64> {
+        if members.is_empty() {
+            return Err(anyhow!("refusing to flag the devices of every member"));
+        }
+        let members: Vec<String> = members.iter().map(|m| m.to_lowercase()).collect();
+        let flagged = sqlx::query(
+            "
+UPDATE
+  mac_to_nick
+SET
+  departed = LOCALTIMESTAMP
+WHERE
+  departed IS NULL
+  AND LOWER(nickname) <> ALL($1)
+",
+        )
+        .bind(&members)
+        .execute(&self.pool)
+        .await
+        .context("unable to flag departed members")?;
+        sqlx::query(
+            "
+UPDATE
+  mac_to_nick
+SET
+  departed = NULL
+WHERE
+  departed IS NOT NULL
+  AND LOWER(nickname) = ANY($1)
+",
+        )
+        .bind(&members)
+        .execute(&self.pool)
+        .await
+        .context("unable to unflag returning members")?;
+        Ok(flagged.rows_affected())
+    }
+
+    async fn log_all(&self, alive: &[AliveDevice]) -> Result<()> {
+        if alive.is_empty() {
+            return Ok(());
+        }
+        QueryBuilder::<Postgres>::new("INSERT INTO alive_hosts (macaddr, ipaddr, erfda) ")
+            .push_values(alive, |mut row, alive| {
+                row.push_bind(&alive.macaddr)
+                    .push_bind(IpAddress(alive.ipaddr))
+                    .push("LOCALTIMESTAMP");
+            })
+            .build()
+            .execute(&self.pool)
+            .await
+            .context("unable to log devices")
+            .and(Ok(()))
+    }
+
+    async fn unassigned(&self) -> Result<Vec<AliveDevice>> {
+        sqlx::query_as(
+            "
+SELECT
+  al.macaddr macaddr,
+  (ARRAY_AGG(al.ipaddr ORDER BY al.erfda DESC))[1] ipaddr
+FROM
+  alive_hosts al
+LEFT OUTER JOIN
+  mac_to_nick mtn
+ON
+  al.macaddr = mtn.macaddr
+WHERE
+  mtn.nickname IS NULL
+  AND NOT EXISTS (
+    SELECT
+      1
+    FROM
+      infrastructure i
+    WHERE
+      i.macaddr = al.macaddr
+  )
+  AND al.erfda > LOCALTIMESTAMP - $1 * INTERVAL '1 second'
+GROUP BY
+  al.macaddr
+ORDER BY
+  MAX(al.erfda) DESC
+",
+        )
+        .bind(self.windows.unassigned_secs())
+        .fetch_all(&self.pool)
+        .await
+        .context("unable to load alive devices")
+    }
+
+    async fn guests(&self) -> Result<Vec<AliveDevice>> {
+        sqlx::query_as(
+            "
+SELECT DISTINCT
+  al.macaddr macaddr,
+  al.ipaddr ipaddr
+FROM
+  alive_hosts al
+LEFT OUTER JOIN
+  mac_to_nick mtn
+ON
+  al.macaddr = mtn.macaddr
+WHERE
+  mtn.nickname IS NULL
+  AND NOT EXISTS (
+    SELECT
+      1
+    FROM
+      infrastructure i
+    WHERE
+      i.macaddr = al.macaddr
+  )
+  AND al.erfda > LOCALTIMESTAMP - $1 * INTERVAL '1 second'
+ORDER BY
+  al.macaddr
+",
+        )
+        .bind(self.windows.present_secs())
+        .fetch_all(&self.pool)
+        .await
+        .context("unable to load guests")
+    }
+
+    async fn alive_for_ip(&self, ip: IpAddr) -> Result<Option<AliveDevice>> {
+        sqlx::query_as(
+            "
+SELECT
+  macaddr,
+  ipaddr
+FROM
+  alive_hosts
+WHERE
+  ipaddr = $1
+  AND erfda > LOCALTIMESTAMP - $2 * INTERVAL '1 second'
+ORDER BY
+  erfda DESC
+LIMIT 1
+",
+        )
+        .bind(IpAddress(ip))
+        .bind(self.windows.unassigned_secs())
+        .fetch_optional(&self.pool)
+        .await
+        .context("unable to select by ip")
+    }
+
+    async fn create_claim(&self, claim: &Claim) -> Result<bool> {
+        if claim.id.is_some() {
+            return Err(anyhow!("claim has already been created"));
+        }
+        let result = sqlx::query(
+            "
+INSERT
+INTO mac_claims
+(macaddr, nickname, descr, privacy, created)
+SELECT
+  $1, $2, $3, $4, LOCALTIMESTAMP
+WHERE
+  NOT EXISTS (
+    SELECT
+      1
+    FROM
+      mac_claims
+    WHERE
+      macaddr = $1
+      AND created > LOCALTIMESTAMP - INTERVAL '1 day'
+  )
+",
+        )
+        .bind(&claim.macaddr)
+        .bind(&claim.nickname)
+        .bind(&claim.descr)
+        .bind(claim.privacy)
+        .execute(&self.pool)
+        .await
+        .context("unable to create claim")?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn claims_for_user(&self, user: &str) -> Result<Vec<Claim>> {
+        sqlx::query_as(
+            "
+SELECT
+  *
+FROM
+  mac_claims
+WHERE
+  nickname = $1
+  AND created > LOCALTIMESTAMP - INTERVAL '1 day'
+ORDER BY
+  created DESC
+",
+        )
+        .bind(user)
+        .fetch_all(&self.pool)
+        .await
+        .context("unable to select claims by user")
+    }
+
+    async fn seen_claims(&self, user: &str) -> Result<Vec<Claim>> {
+        sqlx::query_as(
+            "
+SELECT
+  mc.*
+FROM
+  mac_claims mc
+WHERE
+  mc.nickname = $1
+  AND mc.created > LOCALTIMESTAMP - INTERVAL '1 day'
+  AND EXISTS (
+    SELECT
+      1
+    FROM
+      alive_hosts al
+    WHERE
+      al.macaddr = mc.macaddr
+      AND al.erfda >= mc.created
+  )
+",
+        )
+        .bind(user)
+        .fetch_all(&self.pool)
+        .await
+        .context("unable to select seen claims")
+    }
+
+    async fn delete_claim(&self, claim: &Claim) -> Result<()> {
+        sqlx::query("DELETE FROM mac_claims WHERE id = $1")
+            .bind(claim.id()?)
+            .execute(&self.pool)
+            .await
+            .context("unable to delete claim")
+            .and(Ok(()))
+    }
+
+    async fn force_status(&self, forced: &StatusOverride, duration: Duration) -> Result<()> {
+        let mut tx = self.pool.begin().await.context("unable to force status")?;
+        sqlx::query("DELETE FROM status_override")
+            .execute(&mut *tx)
+            .await
+            .context("unable to force status")?;
+        sqlx::query(
+            "
+INSERT
+INTO status_override
+(open, nickname, created, expires)
+VALUES
+($1, $2, LOCALTIMESTAMP, LOCALTIMESTAMP + $3 * INTERVAL '1 second')
+",
+        )
+        .bind(forced.open)
+        .bind(&forced.nickname)
+        .bind(duration.as_secs() as i64)
+        .execute(&mut *tx)
+        .await
+        .context("unable to force status")?;
+        tx.commit().await.context("unable to force status")
+    }
+
+    async fn forced_status(&self) -> Result<Option<StatusOverride>> {
+        sqlx::query_as(
+            "
+SELECT
+  open,
+  nickname,
+  expires
+FROM
+  status_override
+WHERE
+  expires > LOCALTIMESTAMP
+",
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .context("unable to select forced status")
+    }
+
+    async fn clear_forced_status(&self) -> Result<()> {
+        sqlx::query("DELETE FROM status_override")
+            .execute(&self.pool)
+            .await
+            .context("unable to clear forced status")
+            .and(Ok(()))
+    }
+
+    async fn acquire_lease(&self, holder: &str, ttl: Duration) -> Result<bool> {
+        let acquired: Option<String> = sqlx::query_scalar(
+            "
+INSERT
+INTO scanner_lease
+(id, holder, expires)
+VALUES
+(1, $1, LOCALTIMESTAMP + $2 * INTERVAL '1 second')
+ON CONFLICT (id) DO UPDATE
+SET
+  holder = EXCLUDED.holder,
+  expires = EXCLUDED.expires
+WHERE
+  scanner_lease.holder = EXCLUDED.holder
+  OR scanner_lease.expires <= LOCALTIMESTAMP
+RETURNING
+  holder
+",
+        )
+        .bind(holder)
+        .bind(ttl.as_secs() as i64)
+        .fetch_optional(&self.pool)
+        .await
+        .context("unable to acquire lease")?;
+        Ok(acquired.is_some())
+    }
+
+    async fn release_lease(&self, holder: &str) -> Result<()> {
+        sqlx::query("DELETE FROM scanner_lease WHERE id = 1 AND holder = $1")
+            .bind(holder)
+            .execute(&self.pool)
+            .await
+            .context("unable to release lease")?;
+        Ok(())
+    }
+
+    async fn create_infrastructure(&self, infrastructure: &Infrastructure) -> Result<()> {
+        if infrastructure.id.is_some() {
+            return Err(anyhow!("infrastructure has already been created"));
+        }
+        sqlx::query(
+            "
+INSERT
+INTO infrastructure
+(macaddr, name, descr, created)
+VALUES
+($1, $2, $3, LOCALTIMESTAMP)
+",
+        )
+        .bind(&infrastructure.macaddr)
+        .bind(&infrastructure.name)
+        .bind(&infrastructure.descr)
+        .execute(&self.pool)
+        .await
+        .context("unable to create infrastructure")
+        .and(Ok(()))
+    }
+
+    async fn infrastructure(&self) -> Result<Vec<Infrastructure>> {
+        sqlx::query_as(
+            "
+SELECT
+  i.id,
+  i.macaddr,
+  i.name,
+  i.descr,
+  MAX(al.erfda) last_seen,
+  COALESCE(MAX(al.erfda) > LOCALTIMESTAMP - $1 * INTERVAL '1 second', FALSE) online
+FROM
+  infrastructure i
+LEFT OUTER JOIN
+  alive_hosts al
+ON
+  i.macaddr = al.macaddr
+GROUP BY
+  i.id,
+  i.macaddr,
+  i.name,
+  i.descr
+ORDER BY
+  i.name
+",
+        )
+        .bind(self.windows.present_secs())
+        .fetch_all(&self.pool)
+        .await
+        .context("unable to select infrastructure")
+    }
+
+    async fn infrastructure_for_mac(&self, macaddr: &str) -> Result<Option<Infrastructure>> {
+        sqlx::query_as(
+            "
+SELECT
+  id,
+  macaddr,
+  name,
+  descr
+FROM
+  infrastructure
+WHERE
+  macaddr = $1
+",
+        )
+        .bind(macaddr)
+        .fetch_optional(&self.pool)
+        .await
+        .context("unable to select infrastructure by mac")
+    }
+
+    async fn delete_infrastructure(&self, infrastructure: &Infrastructure) -> Result<()> {
+        sqlx::query("DELETE FROM infrastructure WHERE id = $1")
+            .bind(infrastructure.id()?)
+            .execute(&self.pool)
+            .await
+            .context("unable to delete infrastructure")
+            .and(Ok(()))
+    }
+}
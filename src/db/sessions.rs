@@ -0,0 +1,74 @@
+use async_trait::async_trait;
+#[cfg(test)]
+use tower_sessions::MemoryStore;
+use tower_sessions::session::{Id, Record};
+use tower_sessions::session_store::{ExpiredDeletion, Result, SessionStore};
+use tower_sessions_sqlx_store::{MySqlStore, PostgresStore, SqliteStore};
+
+/// Keeps the sessions of the web interface in the database of the
+/// repository, the tables are created by its migrations.
+#[derive(Clone, Debug)]
+pub enum Sessions {
+    MySql(MySqlStore),
+    Postgres(PostgresStore),
+    Sqlite(SqliteStore),
+    #[cfg(test)]
+    Memory(MemoryStore),
+}
+
+#[async_trait]
+impl SessionStore for Sessions {
+    async fn create(&self, record: &mut Record) -> Result<()> {
+        match self {
+            Sessions::MySql(store) => store.create(record).await,
+            Sessions::Postgres(store) => store.create(record).await,
+            Sessions::Sqlite(store) => store.create(record).await,
+            #[cfg(test)]
+            Sessions::Memory(store) => store.create(record).await,
+        }
+    }
+
+    async fn save(&self, record: &Record) -> Result<()> {
+        match self {
+            Sessions::MySql(store) => store.save(record).await,
+            Sessions::Postgres(store) => store.save(record).await,
+            Sessions::Sqlite(store) => store.save(record).await,
+            #[cfg(test)]
+            Sessions::Memory(store) => store.save(record).await,
+        }
+    }
+
+    async fn load(&self, id: &Id) -> Result<Option<Record>> {
+        match self {
+            Sessions::MySql(store) => store.load(id).await,
+            Sessions::Postgres(store) => store.load(id).await,
+            Sessions::Sqlite(store) => store.load(id).await,
+            #[cfg(test)]
+            Sessions::Memory(store) => store.load(id).await,
+        }
+    }
+
+    async fn delete(&self, id: &Id) -> Result<()> {
+        match self {
+            Sessions::MySql(store) => store.delete(id).await,
+            Sessions::Postgres(store) => store.delete(id).await,
+            Sessions::Sqlite(store) => store.delete(id).await,
+            #[cfg(test)]
+            Sessions::Memory(store) => store.delete(id).await,
+        }
+    }
+}
+
+#[async_trait]
+impl ExpiredDeletion for Sessions {
+    async fn delete_expired(&self) -> Result<()> {
+        match self {
+            Sessions::MySql(store) => store.delete_expired().await,
+            Sessions::Postgres(store) => store.delete_expired().await,
+            Sessions::Sqlite(store) => store.delete_expired().await,
+            // loading an expired session from memory removes it
+            #[cfg(test)]
+            Sessions::Memory(_) => Ok(()),
+        }
+    }
+}
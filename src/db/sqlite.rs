@@ -0,0 +1,678 @@
+use super::{
+    AliveDevice, Claim, Device, Infrastructure, IpAddress, PoolSize, Repository, Sessions,
+    StatusOverride, Windows, with_addresses,
+};
+use anyhow::{Context, Result, anyhow};
+use async_trait::async_trait;
+use sqlx::migrate::Migrator;
+use sqlx::sqlite::SqliteConnectOptions;
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::Duration;
+use tower_sessions_sqlx_store::SqliteStore;
+
+static MIGRATOR: Migrator = sqlx::migrate!("migrations/sqlite");
+
+/// Modifier for `datetime('now', ...)` going back the given seconds.
+fn ago(secs: i64) -> String {
+    format!("-{} seconds", secs)
+}
+
+pub(super) struct SqliteRepository {
+    pool: SqlitePool,
+    windows: Windows,
+}
+
+impl SqliteRepository {
+    pub(super) async fn connect(dsn: &str, size: PoolSize, windows: Windows) -> Result<Self> {
+        let options = SqliteConnectOptions::from_str(dsn)
+            .context("unable to parse database dsn")?
+            .create_if_missing(true);
+        // every connection to an in-memory database opens a new, empty one
+        let pool = if dsn.contains(":memory:") || dsn.contains("mode=memory") {
+            size.options()
+                .max_connections(1)
+                .min_connections(0)
+                .idle_timeout(None)
+                .max_lifetime(None)
+                .connect_with(options)
+                .await
+        } else {
+            size.options().connect_with(options).await
+        }
+        .context("unable to open database connection")?;
+        Ok(Self { pool, windows })
+    }
+}
+
+#[async_trait]
+impl Repository for SqliteRepository {
+    async fn migrate(&self) -> Result<()> {
+        MIGRATOR
+            .run(&self.pool)
+            .await
+            .context("unable to migrate database")
+    }
+
+    fn sessions(&self) -> Sessions {
+        Sessions::Sqlite(
+            SqliteStore::new(self.pool.clone())
+                .with_table_name("sessions")
+                .expect("valid session table"),
+        )
+    }
+
+    async fn ping(&self) -> Result<()> {
+        sqlx::query("SELECT 1")
+            .execute(&self.pool)
+            .await
+            .context("unable to reach the database")
+            .and(Ok(()))
+    }
+
+    async fn create_device(&self, device: &Device) -> Result<()> {
+        if device.id.is_some() {
+            return Err(anyhow!("device has already been created"));
+        }
+        sqlx::query(
+            "
+INSERT
+INTO mac_to_nick
+(macaddr, nickname, descr, privacy, created)
+VALUES
+(?, ?, ?, ?, datetime('now'))
+",
+        )
+        .bind(&device.macaddr)
+        .bind(&device.nickname)
+        .bind(&device.descr)
+        .bind(device.privacy)
+        .execute(&self.pool)
+        .await
+        .context("unable to create device entry")
+        .and(Ok(()))
+    }
+
+    async fn devices_for_user(&self, user: &str) -> Result<Vec<Device>> {
+        let devices = sqlx::query_as(
+            "
+SELECT
+  mtn.*,
+  MAX(al.erfda) last_seen,
+  MIN(al.erfda) first_seen,
+  COUNT(DISTINCT CASE WHEN al.erfda > datetime('now', '-30 days') THEN al.erfda END) sightings,
+  COALESCE(MAX(al.erfda) > datetime('now', ?), FALSE) present,
+  COALESCE(MAX(al.erfda) < datetime('now', '-30 days'), FALSE) stale
+FROM
+  mac_to_nick mtn
+LEFT OUTER JOIN
+  alive_hosts al
+ON
+  mtn.macaddr = al.macaddr
+WHERE
+  nickname LIKE ?
+GROUP BY
+  mtn.id
+ORDER BY
+  last_seen DESC
+",
+        )
+        .bind(ago(self.windows.present_secs()))
+        .bind(user)
+        .fetch_all(&self.pool)
+        .await
+        .context("unable to select by user")?;
+        let seen = sqlx::query_as(
+            "
+SELECT
+  al.macaddr,
+  al.ipaddr
+FROM
+  alive_hosts al
+JOIN (
+  SELECT
+    l.macaddr,
+    MAX(l.erfda) erfda
+  FROM
+    alive_hosts l
+  JOIN
+    mac_to_nick mtn
+  ON
+    l.macaddr = mtn.macaddr
+  WHERE
+    mtn.nickname LIKE ?
+  GROUP BY
+    l.macaddr
+) latest
+ON
+  al.macaddr = latest.macaddr
+  AND al.erfda = latest.erfda
+",
+        )
+        .bind(user)
+        .fetch_all(&self.pool)
+        .await
+        .context("unable to select addresses by user")?;
+        Ok(with_addresses(devices, seen))
+    }
+
+    async fn present_devices(&self) -> Result<Vec<Device>> {
+        let devices: Vec<Device> = sqlx::query_as(
+            "
+SELECT
+  mtn.*
+FROM
+  mac_to_nick mtn
+WHERE
+  EXISTS (
+    SELECT
+      1
+    FROM
+      alive_hosts al
+    WHERE
+      al.macaddr = mtn.macaddr
+      AND al.erfda > datetime('now', ?)
+  )
+",
+        )
+        .bind(ago(self.windows.present_secs()))
+        .fetch_all(&self.pool)
+        .await
+        .context("unable to select present devices")?;
+        Ok(devices
+            .into_iter()
+            .map(|device| Device {
+                present: true,
+                ..device
+            })
+            .collect())
+    }
+
+    async fn devices(&self) -> Result<Vec<Device>> {
+        sqlx::query_as(
+            "
+SELECT
+  *
+FROM
+  mac_to_nick
+ORDER BY
+  nickname,
+  macaddr
+",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .context("unable to select devices")
+    }
+
+    async fn device_for_mac(&self, macaddr: &str) -> Result<Option<Device>> {
+        sqlx::query_as(
+            "
+SELECT
+  *
+FROM
+  mac_to_nick
+WHERE
+  macaddr = ?
+",
+        )
+        .bind(macaddr)
+        .fetch_optional(&self.pool)
+        .await
+        .context("unable to select by mac")
+    }
+
+    async fn devices_for_macs(&self, macaddrs: &[String]) -> Result<Vec<Device>> {
+        if macaddrs.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut query = QueryBuilder::<Sqlite>::new("SELECT * FROM mac_to_nick WHERE macaddr IN (");
+        let mut separated = query.separated(", ");
+        for macaddr in macaddrs {
+            separated.push_bind(macaddr);
+        }
+        query
+            .push(")")
+            .build_query_as()
+            .fetch_all(&self.pool)
+            .await
+            .context("unable to select by macs")
+    }
+
+    async fn update_device(&self, device: &Device) -> Result<()> {
+        sqlx::query(
+            "
+UPDATE
+  mac_to_nick
+SET
+  privacy = ?,
+  descr = ?
+WHERE
+  id = ?
+",
+        )
+        .bind(device.privacy)
+        .bind(&device.descr)
+        .bind(device.id()?)
+        .execute(&self.pool)
+        .await
+        .context("unable to update")
+        .and(Ok(()))
+    }
+
+    async fn delete_device(&self, device: &Device) -> Result<()> {
+        sqlx::query(
+            "
+DELETE FROM
+  mac_to_nick
+WHERE
+  id = ?
+",
+        )
+        .bind(device.id()?)
+        .execute(&self.pool)
+        .await
+        .context("unable to delete")
+        .and(Ok(()))
+    }
+
+    async fn flag_departed(&self, members: &[String]) -> Result<u64> {
+        if members.is_empty() {
+            return Err(anyhow!("refusing to flag the devices of every member"));
+        }
+        let mut flag = QueryBuilder::<Sqlite>::new(
+            "UPDATE mac_to_nick SET departed = datetime('now') \
+             WHERE departed IS NULL AND LOWER(nickname) NOT IN (",
+        );
+        let mut unflag = QueryBuilder::<Sqlite>::new(
+            "UPDATE mac_to_nick SET departed = NULL \
+             WHERE departed IS NOT NULL AND LOWER(nickname) IN (",
+        );
+        for query in [&mut flag, &mut unflag] {
+            let mut separated = query.separated(", ");
+            for member in members {
+                separated.push_bind(member.to_lowercase());
+            }
+            query.push(")");
+        }
+        let flagged = flag
+            .build()
+            .execute(&self.pool)
+            .await
+            .context("unable to flag departed members")?;
+        unflag
+            .build()
+            .execute(&self.pool)
+            .await
+            .context("unable to unflag returning members")?;
+        Ok(flagged.rows_affected())
+    }
+
+    async fn log_all(&self, alive: &[AliveDevice]) -> Result<()> {
+        if alive.is_empty() {
+            return Ok(());
+        }
+        QueryBuilder::<Sqlite>::new("INSERT INTO alive_hosts (macaddr, ipaddr, erfda) ")
+            .push_values(alive, |mut row, alive| {
+                row.push_bind(&alive.macaddr)
+                    .push_bind(IpAddress(alive.ipaddr))
+                    .push("datetime('now')");
+            })
+            .build()
+            .execute(&self.pool)
+            .await
+            .context("unable to log devices")
+            .and(Ok(()))
+    }
+
+    async fn unassigned(&self) -> Result<Vec<AliveDevice>> {
+        sqlx::query_as(
+            "
+SELECT
+  al.macaddr macaddr,
+  al.ipaddr ipaddr,
+  MAX(al.erfda) last_seen
+FROM
+  alive_hosts al
+LEFT OUTER JOIN
+  mac_to_nick mtn
+ON
+  al.macaddr = mtn.macaddr
+WHERE
+  mtn.nickname IS NULL
+  AND NOT EXISTS (
+    SELECT
+      1
+    FROM
+      infrastructure i
+    WHERE
+      i.macaddr = al.macaddr
+  )
+  AND al.erfda > datetime('now', ?)
+GROUP BY
+  al.macaddr
+ORDER BY
+  last_seen DESC
+",
+        )
+        .bind(ago(self.windows.unassigned_secs()))
+        .fetch_all(&self.pool)
+        .await
+        .context("unable to load alive devices")
+    }
+
+    async fn guests(&self) -> Result<Vec<AliveDevice>> {
+        sqlx::query_as(
+            "
+SELECT DISTINCT
+  al.macaddr macaddr,
+  al.ipaddr ipaddr
+FROM
+  alive_hosts al
+LEFT OUTER JOIN
+  mac_to_nick mtn
+ON
+  al.macaddr = mtn.macaddr
+WHERE
+  mtn.nickname IS NULL
+  AND NOT EXISTS (
+    SELECT
+      1
+    FROM
+      infrastructure i
+    WHERE
+      i.macaddr = al.macaddr
+  )
+  AND al.erfda > datetime('now', ?)
+ORDER BY
+  al.macaddr
+",
+        )
+        .bind(ago(self.windows.present_secs()))
+        .fetch_all(&self.pool)
+        .await
+        .context("unable to load guests")
+    }
+
+    async fn alive_for_ip(&self, ip: IpAddr) -> Result<Option<AliveDevice>> {
+        sqlx::query_as(
+            "
+SELECT
+  macaddr,
+  ipaddr
+FROM
+  alive_hosts
+WHERE
+  ipaddr = ?
+  AND erfda > datetime('now', ?)
+ORDER BY
+  erfda DESC
+LIMIT 1
+",
+        )
+        .bind(IpAddress(ip))
+        .bind(ago(self.windows.unassigned_secs()))
+        .fetch_optional(&self.pool)
+        .await
+        .context("unable to select by ip")
+    }
+
+    async fn create_claim(&self, claim: &Claim) -> Result<bool> {
+        if claim.id.is_some() {
+            return Err(anyhow!("claim has already been created"));
+        }
+        let result = sqlx::query(
+            "
+INSERT
+INTO mac_claims
+(macaddr, nickname, descr, privacy, created)
+SELECT
+  ?, ?, ?, ?, datetime('now')
+WHERE
+  NOT EXISTS (
+    SELECT
+      1
+    FROM
+      mac_claims
+    WHERE
+      macaddr = ?
+      AND created > datetime('now', '-1 day')
+  )
+",
+        )
+        .bind(&claim.macaddr)
+        .bind(&claim.nickname)
+        .bind(&claim.descr)
+        .bind(claim.privacy)
+        .bind(&claim.macaddr)
+        .execute(&self.pool)
+        .await
+        .context("unable to create claim")?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn claims_for_user(&self, user: &str) -> Result<Vec<Claim>> {
+        sqlx::query_as(
+            "
+SELECT
+  *
+FROM
+  mac_claims
+WHERE
+  nickname = ?
+  AND created > datetime('now', '-1 day')
+ORDER BY
+  created DESC
+",
+        )
+        .bind(user)
+        .fetch_all(&self.pool)
+        .await
+        .context("unable to select claims by user")
+    }
+
+    async fn seen_claims(&self, user: &str) -> Result<Vec<Claim>> {
+        sqlx::query_as(
+            "
+SELECT
+  mc.*
+FROM
+  mac_claims mc
+WHERE
+  mc.nickname = ?
+  AND mc.created > datetime('now', '-1 day')
+  AND EXISTS (
+    SELECT
+      1
+    FROM
+      alive_hosts al
+    WHERE
+      al.macaddr = mc.macaddr
+      AND al.erfda >= mc.created
+  )
+",
+        )
+        .bind(user)
+        .fetch_all(&self.pool)
+        .await
+        .context("unable to select seen claims")
+    }
+
+    async fn delete_claim(&self, claim: &Claim) -> Result<()> {
+        sqlx::query("DELETE FROM mac_claims WHERE id = ?")
+            .bind(claim.id()?)
+            .execute(&self.pool)
+            .await
+            .context("unable to delete claim")
+            .and(Ok(()))
+    }
+
+    async fn force_status(&self, forced: &StatusOverride, duration: Duration) -> Result<()> {
+        let mut tx = self.pool.begin().await.context("unable to force status")?;
+        sqlx::query("DELETE FROM status_override")
+            .execute(&mut *tx)
+            .await
+            .context("unable to force status")?;
+        sqlx::query(
+            "
+INSERT
+INTO status_override
+(open, nickname, created, expires)
+VALUES
+(?, ?, datetime('now'), datetime('now', ?))
+",
+        )
+        .bind(forced.open)
+        .bind(&forced.nickname)
+        .bind(format!("+{} seconds", duration.as_secs()))
+        .execute(&mut *tx)
+        .await
+        .context("unable to force status")?;
+        tx.commit().await.context("unable to force status")
+    }
+
+    async fn forced_status(&self) -> Result<Option<StatusOverride>> {
+        sqlx::query_as(
+            "
+SELECT
+  open,
+  nickname,
+  expires
+FROM
+  status_override
+WHERE
+  expires > datetime('now')
+",
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .context("unable to select forced status")
+    }
+
+    async fn clear_forced_status(&self) -> Result<()> {
+        sqlx::query("DELETE FROM status_override")
+            .execute(&self.pool)
+            .await
+            .context("unable to clear forced status")
+            .and(Ok(()))
+    }
+
+    async fn acquire_lease(&self, holder: &str, ttl: Duration) -> Result<bool> {
+        let acquired: Option<String> = sqlx::query_scalar(
+            "
+INSERT
+INTO scanner_lease
+(id, holder, expires)
+VALUES
+(1, ?, datetime('now', ?))
+ON CONFLICT (id) DO UPDATE
+SET
+  holder = excluded.holder,
+  expires = excluded.expires
+WHERE
+  scanner_lease.holder = excluded.holder
+  OR scanner_lease.expires <= datetime('now')
+RETURNING
+  holder
+",
+        )
+        .bind(holder)
+        .bind(format!("+{} seconds", ttl.as_secs()))
+        .fetch_optional(&self.pool)
+        .await
+        .context("unable to acquire lease")?;
+        Ok(acquired.is_some())
+    }
+
+    async fn release_lease(&self, holder: &str) -> Result<()> {
+        sqlx::query("DELETE FROM scanner_lease WHERE id = 1 AND holder = ?")
+            .bind(holder)
+            .execute(&self.pool)
+            .await
+            .context("unable to release lease")?;
+        Ok(())
+    }
+
+    async fn create_infrastructure(&self, infrastructure: &Infrastructure) -> Result<()> {
+        if infrastructure.id.is_some() {
+            return Err(anyhow!("infrastructure has already been created"));
+        }
+        sqlx::query(
+            "
+INSERT
+INTO infrastructure
+(macaddr, name, descr, created)
+VALUES
+(?, ?, ?, datetime('now'))
+",
+        )
+        .bind(&infrastructure.macaddr)
+        .bind(&infrastructure.name)
+        .bind(&infrastructure.descr)
+        .execute(&self.pool)
+        .await
+        .context("unable to create infrastructure")
+        .and(Ok(()))
+    }
+
+    async fn infrastructure(&self) -> Result<Vec<Infrastructure>> {
+        sqlx::query_as(
+            "
+SELECT
+  i.id,
+  i.macaddr,
+  i.name,
+  i.descr,
+  MAX(al.erfda) last_seen,
+  COALESCE(MAX(al.erfda) > datetime('now', ?), FALSE) online
+FROM
+  infrastructure i
+LEFT OUTER JOIN
+  alive_hosts al
+ON
+  i.macaddr = al.macaddr
+GROUP BY
+  i.id,
+  i.macaddr,
+  i.name,
+  i.descr
+ORDER BY
+  i.name
+",
+        )
+        .bind(ago(self.windows.present_secs()))
+        .fetch_all(&self.pool)
+        .await
+        .context("unable to select infrastructure")
+    }
+
+    async fn infrastructure_for_mac(&self, macaddr: &str) -> Result<Option<Infrastructure>> {
+        sqlx::query_as(
+            "
+SELECT
+  id,
+  macaddr,
+  name,
+  descr
+FROM
+  infrastructure
+WHERE
+  macaddr = ?
+",
+        )
+        .bind(macaddr)
+        .fetch_optional(&self.pool)
+        .await
+        .context("unable to select infrastructure by mac")
+    }
+
+    async fn delete_infrastructure(&self, infrastructure: &Infrastructure) -> Result<()> {
+        sqlx::query("DELETE FROM infrastructure WHERE id = ?")
+            .bind(infrastructure.id()?)
+            .execute(&self.pool)
+            .await
+            .context("unable to delete infrastructure")
+            .and(Ok(()))
+    }
+}
@@ -0,0 +1,585 @@
+//! Behaviour every [`Repository`] implementation has to provide. SQLite and
+//! the memory store always run, MySQL and PostgreSQL only run if `TEST_MYSQL_DSN` or
+//! `TEST_POSTGRES_DSN` point to a database that may be written to.
+
+use super::*;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Addresses and names that do not collide with previous runs against a
+/// persistent database.
+struct Fixture {
+    nickname: String,
+    other: String,
+    prefix: String,
+    ip: Ipv4Addr,
+    ipv6: Ipv6Addr,
+}
+
+impl Fixture {
+    fn new() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let bytes = (nanos as u32).to_be_bytes();
+        Self {
+            nickname: format!("member{}", nanos % 1_000_000_000),
+            other: format!("other{}", nanos % 1_000_000_000),
+            prefix: format!("02:{:02x}:{:02x}:{:02x}", bytes[1], bytes[2], bytes[3]),
+            ip: Ipv4Addr::new(10, bytes[1], bytes[2], bytes[3]),
+            ipv6: Ipv6Addr::new(
+                0x2001,
+                0xdb8,
+                0,
+                0,
+                0,
+                bytes[1].into(),
+                bytes[2].into(),
+                bytes[3].into(),
+            ),
+        }
+    }
+
+    fn mac(&self, n: u8) -> String {
+        format!("{}:00:{:02x}", self.prefix, n)
+    }
+
+    fn device(&self, n: u8) -> Device {
+        Device::new(
+            self.mac(n),
+            self.nickname.clone(),
+            format!("device {}", n),
+            PrivacyLevel::ShowUser,
+        )
+    }
+
+    fn alive(&self, n: u8) -> AliveDevice {
+        AliveDevice::new(&self.mac(n), &self.ip.to_string()).unwrap()
+    }
+
+    fn alive_v6(&self, n: u8) -> AliveDevice {
+        AliveDevice::new(&self.mac(n), &self.ipv6.to_string()).unwrap()
+    }
+}
+
+async fn suite(repo: Arc<dyn Repository>) {
+    repo.migrate().await.unwrap();
+    // migrating twice must be a no-op
+    repo.migrate().await.unwrap();
+    repo.ping().await.unwrap();
+
+    devices(repo.as_ref()).await;
+    sightings(repo.as_ref()).await;
+    claims(repo.as_ref()).await;
+    forced_status(repo.as_ref()).await;
+    infrastructure(repo.as_ref()).await;
+    departed(repo.as_ref()).await;
+    lease(repo.as_ref()).await;
+    sessions(repo.as_ref()).await;
+}
+
+async fn devices(repo: &dyn Repository) {
+    let fixture = Fixture::new();
+
+    assert!(
+        repo.device_for_mac(&fixture.mac(1))
+            .await
+            .unwrap()
+            .is_none()
+    );
+    repo.create_device(&fixture.device(1)).await.unwrap();
+    assert!(repo.create_device(&fixture.device(1)).await.is_err());
+
+    let mut device = repo.device_for_mac(&fixture.mac(1)).await.unwrap().unwrap();
+    assert_eq!(device.nickname, fixture.nickname);
+    assert_eq!(device.privacy, PrivacyLevel::ShowUser);
+    assert!(repo.create_device(&device).await.is_err());
+
+    device.descr = "renamed".to_string();
+    device.privacy = PrivacyLevel::DontLog;
+    repo.update_device(&device).await.unwrap();
+
+    let devices = repo.devices_for_user(&fixture.nickname).await.unwrap();
+    assert_eq!(devices.len(), 1);
+    assert_eq!(devices[0].descr, "renamed");
+    assert!(
+        repo.devices()
+            .await
+            .unwrap()
+            .iter()
+            .any(|d| d.macaddr == fixture.mac(1) && d.nickname == fixture.nickname)
+    );
+    assert_eq!(devices[0].privacy, PrivacyLevel::DontLog);
+    assert!(!devices[0].present);
+    assert_eq!(devices[0].last_seen, None);
+    assert_eq!(devices[0].sightings, 0);
+
+    repo.delete_device(&device).await.unwrap();
+    assert!(
+        repo.device_for_mac(&fixture.mac(1))
+            .await
+            .unwrap()
+            .is_none()
+    );
+    assert!(
+        repo.devices_for_user(&fixture.nickname)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+}
+
+async fn sightings(repo: &dyn Repository) {
+    let fixture = Fixture::new();
+
+    repo.create_device(&fixture.device(1)).await.unwrap();
+    repo.create_device(&fixture.device(2)).await.unwrap();
+    repo.log_all(&[fixture.alive(1)]).await.unwrap();
+    repo.log_all(&[fixture.alive(1), fixture.alive_v6(1), fixture.alive(3)])
+        .await
+        .unwrap();
+    repo.log_all(&[]).await.unwrap();
+
+    let mut registered = repo
+        .devices_for_macs(&[fixture.mac(1), fixture.mac(2), fixture.mac(3)])
+        .await
+        .unwrap();
+    registered.sort_by(|a, b| a.macaddr.cmp(&b.macaddr));
+    assert_eq!(registered.len(), 2);
+    assert_eq!(registered[0].macaddr, fixture.mac(1));
+    assert_eq!(registered[1].macaddr, fixture.mac(2));
+    assert!(repo.devices_for_macs(&[]).await.unwrap().is_empty());
+
+    let devices = repo.devices_for_user(&fixture.nickname).await.unwrap();
+    assert_eq!(devices.len(), 2);
+    let seen = devices
+        .iter()
+        .find(|d| d.macaddr == fixture.mac(1))
+        .unwrap();
+    assert!(seen.present);
+    assert!(!seen.stale);
+    assert!(seen.last_seen.is_some());
+    assert!(seen.first_seen.is_some());
+    assert_eq!(
+        seen.addresses,
+        vec![IpAddr::V4(fixture.ip), IpAddr::V6(fixture.ipv6)]
+    );
+    // addresses logged by the same scan are one sighting, the two scans
+    // may share a timestamp on databases with second precision
+    assert!((1..=2).contains(&seen.sightings));
+    let unseen = devices
+        .iter()
+        .find(|d| d.macaddr == fixture.mac(2))
+        .unwrap();
+    assert!(!unseen.present);
+    assert_eq!(unseen.last_seen, None);
+
+    let present = repo.present_devices().await.unwrap();
+    assert!(
+        present
+            .iter()
+            .any(|d| d.macaddr == fixture.mac(1) && d.present)
+    );
+    assert!(!present.iter().any(|d| d.macaddr == fixture.mac(2)));
+
+    let unassigned = repo.unassigned().await.unwrap();
+    assert!(unassigned.iter().any(|d| d.macaddr == fixture.mac(3)));
+    assert!(!unassigned.iter().any(|d| d.macaddr == fixture.mac(1)));
+    let alive = unassigned
+        .iter()
+        .find(|d| d.macaddr == fixture.mac(3))
+        .unwrap();
+    assert_eq!(alive.ip(), IpAddr::V4(fixture.ip));
+
+    let guests: Vec<AliveDevice> = repo
+        .guests()
+        .await
+        .unwrap()
+        .into_iter()
+        .filter(|d| d.macaddr.starts_with(&fixture.prefix))
+        .collect();
+    assert_eq!(guests.len(), 1);
+    assert_eq!(guests[0].macaddr, fixture.mac(3));
+    assert_eq!(guests[0].ip(), IpAddr::V4(fixture.ip));
+
+    let current = repo.alive_for_ip(fixture.ip.into()).await.unwrap().unwrap();
+    assert!(current.macaddr.starts_with(&fixture.prefix));
+    let current = repo
+        .alive_for_ip(fixture.ipv6.into())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(current.macaddr, fixture.mac(1));
+    assert!(
+        repo.alive_for_ip(Ipv4Addr::new(192, 0, 2, 1).into())
+            .await
+            .unwrap()
+            .is_none()
+    );
+}
+
+async fn claims(repo: &dyn Repository) {
+    let fixture = Fixture::new();
+
+    let claim = Claim {
+        id: None,
+        macaddr: fixture.mac(1),
+        nickname: fixture.nickname.clone(),
+        descr: "claimed".to_string(),
+        privacy: PrivacyLevel::ShowAnonymous,
+        created: None,
+    };
+    assert!(repo.create_claim(&claim).await.unwrap());
+    // a pending claim keeps others from claiming the address
+    assert!(
+        !repo
+            .create_claim(&Claim {
+                nickname: fixture.other.clone(),
+                ..claim.clone()
+            })
+            .await
+            .unwrap()
+    );
+    assert!(
+        repo.create_claim(&Claim {
+            id: Some(1),
+            ..claim
+        })
+        .await
+        .is_err()
+    );
+
+    let claims = repo.claims_for_user(&fixture.nickname).await.unwrap();
+    assert_eq!(claims.len(), 1);
+    assert!(claims[0].created.is_some());
+    assert!(
+        repo.claims_for_user(&fixture.other)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+    assert!(
+        repo.seen_claims(&fixture.nickname)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+
+    repo.log_all(&[fixture.alive(1)]).await.unwrap();
+    let seen = repo.seen_claims(&fixture.nickname).await.unwrap();
+    assert_eq!(seen.len(), 1);
+    assert_eq!(seen[0].device().privacy, PrivacyLevel::ShowAnonymous);
+
+    repo.delete_claim(&seen[0]).await.unwrap();
+    assert!(
+        repo.claims_for_user(&fixture.nickname)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+}
+
+async fn forced_status(repo: &dyn Repository) {
+    let fixture = Fixture::new();
+    let hour = Duration::from_secs(60 * 60);
+
+    repo.force_status(&StatusOverride::new(true, fixture.nickname.clone()), hour)
+        .await
+        .unwrap();
+    let forced = repo.forced_status().await.unwrap().unwrap();
+    assert!(forced.open);
+    assert_eq!(forced.nickname, fixture.nickname);
+    assert!(forced.expires.is_some());
+
+    // forcing again replaces the previous status
+    repo.force_status(&StatusOverride::new(false, fixture.other.clone()), hour)
+        .await
+        .unwrap();
+    let forced = repo.forced_status().await.unwrap().unwrap();
+    assert!(!forced.open);
+    assert_eq!(forced.nickname, fixture.other);
+
+    repo.clear_forced_status().await.unwrap();
+    assert!(repo.forced_status().await.unwrap().is_none());
+
+    // an expired status is ignored
+    repo.force_status(
+        &StatusOverride::new(true, fixture.nickname.clone()),
+        Duration::ZERO,
+    )
+    .await
+    .unwrap();
+    assert!(repo.forced_status().await.unwrap().is_none());
+    repo.clear_forced_status().await.unwrap();
+}
+
+async fn infrastructure(repo: &dyn Repository) {
+    let fixture = Fixture::new();
+    let printer = Infrastructure::new(
+        fixture.mac(1),
+        format!("printer-{}", fixture.nickname),
+        "laser printer".to_string(),
+    );
+    repo.create_infrastructure(&printer).await.unwrap();
+    assert!(repo.create_infrastructure(&printer).await.is_err());
+    repo.create_infrastructure(&Infrastructure::new(
+        fixture.mac(2),
+        format!("ap-{}", fixture.nickname),
+        String::new(),
+    ))
+    .await
+    .unwrap();
+    repo.log_all(&[fixture.alive(1)]).await.unwrap();
+
+    let all = repo.infrastructure().await.unwrap();
+    let seen = all.iter().find(|i| i.macaddr == fixture.mac(1)).unwrap();
+    assert!(seen.online);
+    assert!(seen.last_seen.is_some());
+    assert_eq!(seen.descr, "laser printer");
+    let unseen = all.iter().find(|i| i.macaddr == fixture.mac(2)).unwrap();
+    assert!(!unseen.online);
+    assert_eq!(unseen.last_seen, None);
+
+    // infrastructure is never offered for registration nor counted as guest
+    assert!(
+        !repo
+            .unassigned()
+            .await
+            .unwrap()
+            .iter()
+            .any(|d| d.macaddr == fixture.mac(1))
+    );
+    assert!(
+        !repo
+            .guests()
+            .await
+            .unwrap()
+            .iter()
+            .any(|d| d.macaddr == fixture.mac(1))
+    );
+
+    let stored = repo
+        .infrastructure_for_mac(&fixture.mac(1))
+        .await
+        .unwrap()
+        .unwrap();
+    repo.delete_infrastructure(&stored).await.unwrap();
+    assert!(
+        repo.infrastructure_for_mac(&fixture.mac(1))
+            .await
+            .unwrap()
+            .is_none()
+    );
+    let unseen = repo
+        .infrastructure_for_mac(&fixture.mac(2))
+        .await
+        .unwrap()
+        .unwrap();
+    repo.delete_infrastructure(&unseen).await.unwrap();
+}
+
+/// Nothing is present or unassigned once the windows have passed.
+async fn windows(repo: Arc<dyn Repository>) {
+    repo.migrate().await.unwrap();
+    let fixture = Fixture::new();
+    repo.create_device(&fixture.device(1)).await.unwrap();
+    repo.log_all(&[fixture.alive(1), fixture.alive(2)])
+        .await
+        .unwrap();
+
+    let devices = repo.devices_for_user(&fixture.nickname).await.unwrap();
+    assert!(devices[0].last_seen.is_some());
+    assert!(!devices[0].present);
+    assert!(
+        !repo
+            .present_devices()
+            .await
+            .unwrap()
+            .iter()
+            .any(|d| d.macaddr == fixture.mac(1))
+    );
+    assert!(
+        !repo
+            .unassigned()
+            .await
+            .unwrap()
+            .iter()
+            .any(|d| d.macaddr == fixture.mac(2))
+    );
+    assert!(
+        repo.alive_for_ip(fixture.ip.into())
+            .await
+            .unwrap()
+            .is_none()
+    );
+}
+
+const CLOSED: Windows = Windows {
+    present: Duration::ZERO,
+    unassigned: Duration::ZERO,
+};
+
+#[tokio::test]
+async fn sqlite() {
+    let repo = connect("sqlite::memory:", PoolSize::default(), Windows::default());
+    suite(repo.await.unwrap()).await;
+    let repo = connect("sqlite::memory:", PoolSize::default(), CLOSED);
+    windows(repo.await.unwrap()).await;
+}
+
+#[tokio::test]
+async fn memory() {
+    let repo = connect("memory://", PoolSize::default(), Windows::default());
+    suite(repo.await.unwrap()).await;
+    let repo = connect("memory://", PoolSize::default(), CLOSED);
+    windows(repo.await.unwrap()).await;
+}
+
+#[tokio::test]
+#[ignore = "needs a server, see TEST_POSTGRES_DSN"]
+async fn postgres() {
+    let dsn = std::env::var("TEST_POSTGRES_DSN").expect("TEST_POSTGRES_DSN");
+    suite(
+        connect(&dsn, PoolSize::default(), Windows::default())
+            .await
+            .unwrap(),
+    )
+    .await;
+    windows(connect(&dsn, PoolSize::default(), CLOSED).await.unwrap()).await;
+}
+
+#[tokio::test]
+#[ignore = "needs a server, see TEST_MYSQL_DSN"]
+async fn mysql() {
+    let dsn = std::env::var("TEST_MYSQL_DSN").expect("TEST_MYSQL_DSN");
+    suite(
+        connect(&dsn, PoolSize::default(), Windows::default())
+            .await
+            .unwrap(),
+    )
+    .await;
+    windows(connect(&dsn, PoolSize::default(), CLOSED).await.unwrap()).await;
+}
+
+#[tokio::test]
+async fn unsupported_scheme() {
+    assert!(
+        connect(
+            "oracle://localhost/mac4nick",
+            PoolSize::default(),
+            Windows::default()
+        )
+        .await
+        .is_err()
+    );
+}
+
+#[tokio::test]
+async fn session_tables_of_unsupported_names_are_refused() {
+    let pool = sqlx::MySqlPool::connect_lazy("mysql://localhost/mac4nick").unwrap();
+    assert!(mysql::session_store(pool.clone(), "mac4nick").is_ok());
+    let err = mysql::session_store(pool, "mac4nick-prod").unwrap_err();
+    assert!(format!("{:#}", err).contains("\"mac4nick-prod\" is not supported"));
+
+    let pool = sqlx::PgPool::connect_lazy("postgres://localhost/mac4nick").unwrap();
+    assert!(postgres::session_store(pool.clone(), "public").is_ok());
+    assert!(postgres::session_store(pool, "mac4nick-prod").is_err());
+}
+
+async fn departed(repo: &dyn Repository) {
+    let fixture = Fixture::new();
+    let mut other = fixture.device(2);
+    other.nickname = fixture.other.clone();
+    repo.create_device(&fixture.device(1)).await.unwrap();
+    repo.create_device(&other).await.unwrap();
+    let departed = |nickname: String| async move {
+        repo.devices_for_user(&nickname)
+            .await
+            .unwrap()
+            .iter()
+            .map(|device| device.departed.is_some())
+            .collect::<Vec<_>>()
+    };
+
+    assert!(repo.flag_departed(&[]).await.is_err());
+    let flagged = repo
+        .flag_departed(&[fixture.nickname.to_uppercase()])
+        .await
+        .unwrap();
+    assert!(flagged >= 1);
+    assert_eq!(departed(fixture.nickname.clone()).await, vec![false]);
+    assert_eq!(departed(fixture.other.clone()).await, vec![true]);
+    // devices are flagged once, and again after their owner returned
+    assert_eq!(
+        repo.flag_departed(std::slice::from_ref(&fixture.nickname))
+            .await
+            .unwrap(),
+        0
+    );
+    repo.flag_departed(&[fixture.nickname.clone(), fixture.other.clone()])
+        .await
+        .unwrap();
+    assert_eq!(departed(fixture.other.clone()).await, vec![false]);
+
+    for nickname in [&fixture.nickname, &fixture.other] {
+        for device in repo.devices_for_user(nickname).await.unwrap() {
+            repo.delete_device(&device).await.unwrap();
+        }
+    }
+}
+
+async fn lease(repo: &dyn Repository) {
+    let fixture = Fixture::new();
+    let ttl = Duration::from_secs(60);
+
+    // a lease left over by a previous run against a persistent database
+    // expires right away
+    repo.acquire_lease(&fixture.nickname, Duration::ZERO)
+        .await
+        .unwrap();
+    repo.acquire_lease(&fixture.nickname, Duration::ZERO)
+        .await
+        .unwrap();
+
+    assert!(repo.acquire_lease(&fixture.nickname, ttl).await.unwrap());
+    assert!(!repo.acquire_lease(&fixture.other, ttl).await.unwrap());
+    // renewing keeps the lease
+    assert!(repo.acquire_lease(&fixture.nickname, ttl).await.unwrap());
+    assert!(!repo.acquire_lease(&fixture.other, ttl).await.unwrap());
+
+    // the other instance takes over once the lease has expired
+    assert!(
+        repo.acquire_lease(&fixture.nickname, Duration::ZERO)
+            .await
+            .unwrap()
+    );
+    assert!(repo.acquire_lease(&fixture.other, ttl).await.unwrap());
+    assert!(!repo.acquire_lease(&fixture.nickname, ttl).await.unwrap());
+
+    // only the holder releases the lease
+    repo.release_lease(&fixture.nickname).await.unwrap();
+    assert!(!repo.acquire_lease(&fixture.nickname, ttl).await.unwrap());
+    repo.release_lease(&fixture.other).await.unwrap();
+    assert!(repo.acquire_lease(&fixture.nickname, ttl).await.unwrap());
+    repo.release_lease(&fixture.nickname).await.unwrap();
+}
+
+async fn sessions(repo: &dyn Repository) {
+    use tower_sessions::{ExpiredDeletion, Session};
+
+    let store = Arc::new(repo.sessions());
+    let session = Session::new(None, store.clone(), None);
+    session.insert("csrf", "token").await.unwrap();
+    session.save().await.unwrap();
+
+    let loaded = Session::new(session.id(), store.clone(), None);
+    assert_eq!(
+        loaded.get::<String>("csrf").await.unwrap().as_deref(),
+        Some("token")
+    );
+    store.delete_expired().await.unwrap();
+    loaded.delete().await.unwrap();
+    let deleted = Session::new(session.id(), store, None);
+    assert_eq!(deleted.get::<String>("csrf").await.unwrap(), None);
+}
@@ -1,18 +1,79 @@
 use crate::AppMessage;
 use crate::AppState;
+use crate::UnassignedMode;
 use crate::db;
+use crate::mac;
+use crate::status::SpaceStatus;
 use axum_messages::Level;
 use serde::Deserialize;
 use std::convert::TryFrom;
+use std::net::IpAddr;
+use std::time::Duration;
 
 #[derive(Deserialize, Clone)]
 #[serde(rename_all = "lowercase")]
 enum Action {
     Register,
+    Claim,
     Update,
     Delete,
 }
 
+/// Refuses registrations of nicknames that are not in the LDAP members
+/// group.
+async fn check_membership(state: &AppState, nickname: &str) -> Option<AppMessage> {
+    let directory = state.directory.as_ref()?;
+    match directory.is_member(nickname).await {
+        Ok(true) => None,
+        Ok(false) => Some((
+            Level::Error,
+            "only active members may register devices".to_string(),
+        )),
+        Err(err) => {
+            tracing::error!("unable to check the membership of {}: {:#}", nickname, err);
+            Some((
+                Level::Error,
+                "unable to check your membership, please try again later".to_string(),
+            ))
+        }
+    }
+}
+
+/// Refuses addresses that are registered already or belong to the space
+/// infrastructure.
+async fn check_unregistered(state: &AppState, macaddr: &str) -> Option<AppMessage> {
+    match state.repo.device_for_mac(macaddr).await {
+        Ok(Some(_)) => {
+            return Some((
+                Level::Error,
+                format!("device {} is already registered", macaddr),
+            ));
+        }
+        Ok(None) => {}
+        Err(err) => {
+            tracing::error!("unable to load device {}: {:#}", macaddr, err);
+            return Some((
+                Level::Error,
+                "unable to load device from database".to_string(),
+            ));
+        }
+    }
+    match state.repo.infrastructure_for_mac(macaddr).await {
+        Ok(Some(_)) => Some((
+            Level::Error,
+            format!("device {} belongs to the space infrastructure", macaddr),
+        )),
+        Ok(None) => None,
+        Err(err) => {
+            tracing::error!("unable to load infrastructure {}: {:#}", macaddr, err);
+            Some((
+                Level::Error,
+                "unable to load infrastructure from database".to_string(),
+            ))
+        }
+    }
+}
+
 #[derive(Deserialize, Clone)]
 pub struct ChangeForm {
     action: Action,
@@ -22,9 +83,28 @@ pub struct ChangeForm {
 }
 
 impl ChangeForm {
-    pub async fn handle(self, state: &AppState, nickname: String) -> AppMessage {
+    pub async fn handle(
+        self,
+        state: &AppState,
+        nickname: String,
+        client_ip: Option<IpAddr>,
+    ) -> AppMessage {
+        if matches!(self.action, Action::Register | Action::Claim)
+            && let Some(refused) = check_membership(state, &nickname).await
+        {
+            return refused;
+        }
+        let claim_mode = state.config.borrow().unassigned_mode == UnassignedMode::Claim;
         match self.action {
+            Action::Register if claim_mode => {
+                self.register_current(state, nickname, client_ip).await
+            }
             Action::Register => self.register(state, nickname).await,
+            Action::Claim if claim_mode => self.claim(state, nickname).await,
+            Action::Claim => (
+                Level::Error,
+                "devices are registered directly, claims are not accepted".to_string(),
+            ),
             Action::Update => self.update(state).await,
             Action::Delete => self.delete(state).await,
         }
@@ -35,17 +115,25 @@ impl ChangeForm {
             Ok(privacy) => privacy,
             Err(_) => return (Level::Error, "unable to parse privacy level".to_string()),
         };
-        let dbresult = db::Device {
-            id: None,
-            macaddr: self.macaddr,
-            nickname: nickname.to_string(),
-            descr: self.descr.clone(),
-            privacy,
-            present: false,
+        let macaddr = match mac::normalize(&self.macaddr) {
+            Ok(macaddr) => macaddr,
+            Err(err) => return (Level::Error, err.to_string()),
+        };
+        if let Some(refused) = check_unregistered(state, &macaddr).await {
+            return refused;
         }
-        .create(&state.pool)
-        .await;
+        let randomized = mac::is_locally_administered(&macaddr);
+        let device = db::Device::new(macaddr, nickname.to_string(), self.descr.clone(), privacy);
+        let dbresult = state.repo.create_device(&device).await;
         match dbresult {
+            Ok(_) if randomized => (
+                Level::Warning,
+                format!(
+                    "assinged device \"{}\" to {}, but its address looks randomized and may change; \
+                     disable the private address for the space wifi",
+                    &self.descr, &nickname
+                ),
+            ),
             Ok(_) => (
                 Level::Info,
                 format!("assinged device \"{}\" to {}", &self.descr, &nickname),
@@ -54,10 +142,87 @@ impl ChangeForm {
         }
     }
 
+    /// Registers a device only if it is the one the request is coming from.
+    pub async fn register_current(
+        self,
+        state: &AppState,
+        nickname: String,
+        client_ip: Option<IpAddr>,
+    ) -> AppMessage {
+        let current = match client_ip {
+            Some(ip) => match state.repo.alive_for_ip(ip).await {
+                Ok(current) => current,
+                Err(err) => {
+                    tracing::error!("unable to look up the device of {}: {:#}", ip, err);
+                    return (
+                        Level::Error,
+                        "unable to look up your device, please try again later".to_string(),
+                    );
+                }
+            },
+            _ => None,
+        };
+        match current {
+            Some(current) if mac::normalize(&self.macaddr).ok().as_ref() == Some(&current.macaddr) => {
+                self.register(state, nickname).await
+            }
+            _ => (
+                Level::Error,
+                "only the device you are using can be registered directly, claim other devices by their mac address"
+                    .to_string(),
+            ),
+        }
+    }
+
+    /// Records a claim that is turned into a registration as soon as the
+    /// device shows up in a scan.
+    pub async fn claim(self, state: &AppState, nickname: String) -> AppMessage {
+        let privacy = match db::PrivacyLevel::try_from(self.privacy) {
+            Ok(privacy) => privacy,
+            Err(_) => return (Level::Error, "unable to parse privacy level".to_string()),
+        };
+        let macaddr = match mac::normalize(&self.macaddr) {
+            Ok(macaddr) => macaddr,
+            Err(err) => return (Level::Error, err.to_string()),
+        };
+        if let Some(refused) = check_unregistered(state, &macaddr).await {
+            return refused;
+        }
+        let randomized = mac::is_locally_administered(&macaddr);
+        let claim = db::Claim {
+            id: None,
+            macaddr: macaddr.clone(),
+            nickname,
+            descr: self.descr,
+            privacy,
+            created: None,
+        };
+        let dbresult = state.repo.create_claim(&claim).await;
+        match dbresult {
+            Ok(false) => (
+                Level::Error,
+                format!("device {} has been claimed already", macaddr),
+            ),
+            Ok(_) if randomized => (
+                Level::Warning,
+                format!(
+                    "claimed {}, keep it connected until the next scan; its address looks randomized \
+                     and may change, disable the private address for the space wifi",
+                    macaddr
+                ),
+            ),
+            Ok(_) => (
+                Level::Info,
+                format!("claimed {}, keep it connected until the next scan", macaddr),
+            ),
+            Err(_) => (Level::Error, "unable to create claim".to_string()),
+        }
+    }
+
     pub async fn update(self, state: &AppState) -> AppMessage {
-        let mut device = match db::Device::for_mac(&state.pool, &self.macaddr).await {
-            Ok(device) => device,
-            Err(_) => {
+        let mut device = match state.repo.device_for_mac(&self.macaddr).await {
+            Ok(Some(device)) => device,
+            _ => {
                 return (
                     Level::Error,
                     "unable to load device from database".to_string(),
@@ -69,29 +234,224 @@ impl ChangeForm {
             Err(_) => return (Level::Error, "unable to parse privacy level".to_string()),
         };
         device.descr = self.descr;
-        match device.update(&state.pool).await {
-            Ok(device) => (Level::Info, format!("updated device \"{}\"", device.descr)),
+        match state.repo.update_device(&device).await {
+            Ok(_) => (Level::Info, format!("updated device \"{}\"", device.descr)),
             Err(_) => (Level::Error, "unable to update device".to_string()),
         }
     }
 
     pub async fn delete(self, state: &AppState) -> AppMessage {
-        let device = match db::Device::for_mac(&state.pool, &self.macaddr).await {
-            Ok(device) => device,
-            Err(_) => {
+        let device = match state.repo.device_for_mac(&self.macaddr).await {
+            Ok(Some(device)) => device,
+            _ => {
                 return (
                     Level::Error,
                     "unable to load device from database".to_string(),
                 );
             }
         };
-        let descr = device.descr.clone();
-        match device.delete(&state.pool).await {
+        match state.repo.delete_device(&device).await {
             Ok(_) => (
                 Level::Info,
-                format!("device \"{}\" has been deleted", descr),
+                format!("device \"{}\" has been deleted", device.descr),
             ),
             Err(_) => (Level::Error, "unable to delete device".to_string()),
         }
     }
 }
+
+/// Turns the claims of a member whose devices have shown up in a scan into
+/// registrations.
+pub async fn confirm_claims(state: &AppState, nickname: &str) -> Vec<AppMessage> {
+    let claims = match state.repo.seen_claims(nickname).await {
+        Ok(claims) => claims,
+        Err(err) => {
+            tracing::error!("unable to load claims: {:?}", err);
+            return vec![];
+        }
+    };
+    let mut messages = vec![];
+    for claim in claims {
+        let registered = match state.repo.device_for_mac(&claim.macaddr).await {
+            Ok(registered) => registered,
+            Err(err) => {
+                // keep the claim to try again on the next page load
+                tracing::error!("unable to load device {}: {:#}", claim.macaddr, err);
+                messages.push((
+                    Level::Error,
+                    "unable to load device from database".to_string(),
+                ));
+                continue;
+            }
+        };
+        let message = if registered.is_some() {
+            (
+                Level::Error,
+                format!(
+                    "device {} has been registered by someone else",
+                    claim.macaddr
+                ),
+            )
+        } else if state.repo.create_device(&claim.device()).await.is_ok() {
+            (
+                Level::Success,
+                format!(
+                    "claimed device \"{}\" showed up and has been assinged to {}",
+                    claim.descr, nickname
+                ),
+            )
+        } else {
+            (Level::Error, "unable to create device".to_string())
+        };
+        if let Err(err) = state.repo.delete_claim(&claim).await {
+            tracing::error!("unable to delete claim: {:?}", err);
+        }
+        messages.push(message);
+    }
+    messages
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+enum StatusAction {
+    Open,
+    Closed,
+    Clear,
+}
+
+/// Lets admins force the space status for a few hours.
+#[derive(Deserialize, Clone)]
+pub struct StatusForm {
+    action: StatusAction,
+    #[serde(default)]
+    hours: u64,
+}
+
+impl StatusForm {
+    pub async fn handle(self, state: &AppState, nickname: String) -> AppMessage {
+        if !state.is_admin(&nickname).await {
+            return (
+                Level::Error,
+                "only admins may change the space status".to_string(),
+            );
+        }
+        let open = match self.action {
+            StatusAction::Clear => {
+                return match state.repo.clear_forced_status().await {
+                    Ok(_) => (
+                        Level::Info,
+                        "the space status is no longer forced".to_string(),
+                    ),
+                    Err(_) => (Level::Error, "unable to clear the space status".to_string()),
+                };
+            }
+            StatusAction::Open => true,
+            StatusAction::Closed => false,
+        };
+        if !(1..=168).contains(&self.hours) {
+            return (
+                Level::Error,
+                "the status can be forced for one hour up to a week".to_string(),
+            );
+        }
+        let forced = db::StatusOverride::new(open, nickname);
+        let duration = Duration::from_secs(self.hours * 60 * 60);
+        match state.repo.force_status(&forced, duration).await {
+            Ok(_) => (
+                Level::Info,
+                format!(
+                    "the space is forced {} for {} hours",
+                    SpaceStatus::from(&forced),
+                    self.hours
+                ),
+            ),
+            Err(_) => (Level::Error, "unable to force the space status".to_string()),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+enum InfrastructureAction {
+    Add,
+    Delete,
+}
+
+/// Lets admins manage the devices owned by the space.
+#[derive(Deserialize, Clone)]
+pub struct InfrastructureForm {
+    action: InfrastructureAction,
+    macaddr: String,
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    descr: String,
+}
+
+impl InfrastructureForm {
+    pub async fn handle(self, state: &AppState, nickname: String) -> AppMessage {
+        if !state.is_admin(&nickname).await {
+            return (
+                Level::Error,
+                "only admins may manage the infrastructure".to_string(),
+            );
+        }
+        let macaddr = match mac::normalize(&self.macaddr) {
+            Ok(macaddr) => macaddr,
+            Err(err) => return (Level::Error, err.to_string()),
+        };
+        match self.action {
+            InfrastructureAction::Add => self.add(state, macaddr).await,
+            InfrastructureAction::Delete => Self::delete(state, macaddr).await,
+        }
+    }
+
+    async fn add(self, state: &AppState, macaddr: String) -> AppMessage {
+        let valid_name = !self.name.is_empty()
+            && self.name.len() <= 32
+            && self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid_name {
+            return (
+                Level::Error,
+                "the name may only contain letters, digits, - and _".to_string(),
+            );
+        }
+        if let Ok(Some(device)) = state.repo.device_for_mac(&macaddr).await {
+            return (
+                Level::Error,
+                format!(
+                    "device {} is registered to {}, it has to be deleted first",
+                    macaddr, device.nickname
+                ),
+            );
+        }
+        let infrastructure = db::Infrastructure::new(macaddr, self.name, self.descr);
+        match state.repo.create_infrastructure(&infrastructure).await {
+            Ok(_) => (
+                Level::Info,
+                format!("added {} to the infrastructure", infrastructure.name),
+            ),
+            Err(_) => (
+                Level::Error,
+                "unable to add infrastructure, the address or name is already used".to_string(),
+            ),
+        }
+    }
+
+    async fn delete(state: &AppState, macaddr: String) -> AppMessage {
+        let infrastructure = match state.repo.infrastructure_for_mac(&macaddr).await {
+            Ok(Some(infrastructure)) => infrastructure,
+            _ => return (Level::Error, "unable to find infrastructure".to_string()),
+        };
+        match state.repo.delete_infrastructure(&infrastructure).await {
+            Ok(_) => (
+                Level::Info,
+                format!("removed {} from the infrastructure", infrastructure.name),
+            ),
+            Err(_) => (Level::Error, "unable to delete infrastructure".to_string()),
+        }
+    }
+}
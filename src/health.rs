@@ -0,0 +1,201 @@
+//! Readiness of mac4nick and the services it depends on.
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// Whether this instance scans.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScannerState {
+    /// Holds the lease and scans.
+    #[default]
+    Active,
+    /// Another instance holds the lease, this one takes over once it
+    /// expires.
+    Standby,
+    /// The instance does not run the scanner.
+    Disabled,
+}
+
+/// Outcome of the latest scan.
+#[derive(Clone, Debug, Default)]
+struct LastScan {
+    state: ScannerState,
+    finished: Option<DateTime<Utc>>,
+    succeeded: Option<DateTime<Utc>>,
+    failed: bool,
+    controller_reachable: Option<bool>,
+}
+
+/// What the scanner has observed about its dependencies, shared with the
+/// web interface.
+#[derive(Debug, Default)]
+pub struct Health {
+    mqtt_connected: AtomicBool,
+    last_scan: Mutex<LastScan>,
+    tasks: Mutex<BTreeMap<&'static str, TaskCheck>>,
+}
+
+/// State of a supervised background task. `/readyz` is public, the panic
+/// message is only logged.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct TaskCheck {
+    pub running: bool,
+    pub restarts: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_panic: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Check {
+    pub fn ok() -> Self {
+        Self {
+            ok: true,
+            error: None,
+        }
+    }
+
+    pub fn failed(error: impl ToString) -> Self {
+        Self {
+            ok: false,
+            error: Some(error.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ScanCheck {
+    #[serde(flatten)]
+    pub check: Check,
+    pub last_success: Option<DateTime<Utc>>,
+    pub last_finished: Option<DateTime<Utc>>,
+    /// Set when the latest scan failed, a single failure does not make the
+    /// instance unready. The error itself is only logged, it may name the
+    /// controller or the database.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+/// Body of `/readyz`. Checks of dependencies only the active scanner uses
+/// are left out on the other instances.
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub scanner: ScannerState,
+    pub database: Check,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mqtt: Option<Check>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scan: Option<ScanCheck>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub controller: Option<Check>,
+    /// A task waiting to be restarted makes the instance unready.
+    pub tasks: BTreeMap<&'static str, TaskCheck>,
+}
+
+impl Health {
+    pub fn mqtt_connected(&self, connected: bool) {
+        self.mqtt_connected.store(connected, Ordering::Relaxed);
+    }
+
+    /// Records the state of the scanner and returns the previous one.
+    pub fn scanner(&self, state: ScannerState) -> ScannerState {
+        std::mem::replace(&mut self.last_scan.lock().unwrap().state, state)
+    }
+
+    pub fn task_started(&self, name: &'static str) {
+        self.tasks.lock().unwrap().entry(name).or_default().running = true;
+    }
+
+    /// Forgets a task that finished on its own, like on shutdown.
+    pub fn task_stopped(&self, name: &'static str) {
+        self.tasks.lock().unwrap().remove(name);
+    }
+
+    pub fn task_crashed(&self, name: &'static str) {
+        let mut tasks = self.tasks.lock().unwrap();
+        let task = tasks.entry(name).or_default();
+        task.running = false;
+        task.restarts += 1;
+        task.last_panic = Some("task panicked".to_string());
+    }
+
+    pub fn controller_reachable(&self, reachable: bool) {
+        self.last_scan.lock().unwrap().controller_reachable = Some(reachable);
+    }
+
+    pub fn scanned(&self, result: &anyhow::Result<()>) {
+        let now = Utc::now();
+        let mut last_scan = self.last_scan.lock().unwrap();
+        last_scan.finished = Some(now);
+        last_scan.failed = result.is_err();
+        if result.is_ok() {
+            last_scan.succeeded = Some(now);
+        }
+    }
+
+    /// Combines the observed state with the result of pinging the database.
+    /// A scan counts as stale once `max_age` has passed since the last
+    /// successful one.
+    pub fn readiness(&self, database: Check, max_age: Duration) -> Readiness {
+        let last_scan = self.last_scan.lock().unwrap().clone();
+
+        let active = last_scan.state == ScannerState::Active;
+
+        let mqtt = if self.mqtt_connected.load(Ordering::Relaxed) {
+            Check::ok()
+        } else {
+            Check::failed("not connected to the broker")
+        };
+
+        let max_age = TimeDelta::from_std(max_age).unwrap_or(TimeDelta::MAX);
+        let scan = match last_scan.succeeded {
+            None => Check::failed("no scan has succeeded yet"),
+            Some(succeeded) if Utc::now() - succeeded > max_age => {
+                Check::failed("the last successful scan is too old")
+            }
+            Some(_) => Check::ok(),
+        };
+
+        let controller = match last_scan.controller_reachable {
+            Some(true) => Check::ok(),
+            Some(false) => Check::failed("the last scan could not reach the controller"),
+            None => Check::failed("the controller has not been contacted yet"),
+        };
+
+        // a standby instance stays connected to the broker to take over
+        let mqtt = (last_scan.state != ScannerState::Disabled).then_some(mqtt);
+        let scan = active.then_some(ScanCheck {
+            check: scan,
+            last_success: last_scan.succeeded,
+            last_finished: last_scan.finished,
+            last_error: last_scan.failed.then(|| "last scan failed".to_string()),
+        });
+        let controller = active.then_some(controller);
+        let tasks = self.tasks.lock().unwrap().clone();
+
+        Readiness {
+            ready: database.ok
+                && mqtt.as_ref().is_none_or(|mqtt| mqtt.ok)
+                && scan.as_ref().is_none_or(|scan| scan.check.ok)
+                && controller.as_ref().is_none_or(|controller| controller.ok)
+                && tasks.values().all(|task| task.running),
+            scanner: last_scan.state,
+            database,
+            mqtt,
+            scan,
+            controller,
+            tasks,
+        }
+    }
+}
@@ -21,3 +21,22 @@ impl IntoResponse for AppError {
             .into_response()
     }
 }
+
+/// Guesses a device description from the browser's user agent, used to
+/// prefill the one-click registration of the current device.
+pub(crate) fn device_hint(user_agent: &str) -> String {
+    let hints = [
+        ("iPhone", "iPhone"),
+        ("iPad", "iPad"),
+        ("Android", "Android device"),
+        ("Windows", "Windows computer"),
+        ("Macintosh", "Mac"),
+        ("CrOS", "Chromebook"),
+        ("Linux", "Linux computer"),
+    ];
+    hints
+        .iter()
+        .find(|(needle, _)| user_agent.contains(needle))
+        .map(|(_, hint)| hint.to_string())
+        .unwrap_or_default()
+}
@@ -0,0 +1,164 @@
+//! Membership and admin rights taken from groups of an LDAP directory.
+
+use crate::Config;
+use crate::db::Repository;
+use anyhow::{Context, Result, bail};
+use ldap3::{LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long the members of a group are remembered, so browsing the web
+/// interface does not query the directory on every request.
+const CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// The lowercase nicknames listed in a group.
+type Members = Arc<HashSet<String>>;
+
+/// The directory configured by the `LDAP_*` settings.
+pub struct Directory {
+    url: String,
+    bind: Option<(String, String)>,
+    starttls: bool,
+    members_group: Option<String>,
+    admins_group: Option<String>,
+    attributes: Vec<String>,
+    cache: Mutex<HashMap<String, (Members, Instant)>>,
+}
+
+/// The nickname a value of a member attribute names, in lowercase. DNs like
+/// `uid=alice,ou=people,dc=example,dc=org` name the member by their first
+/// RDN, `memberUid` values are the nickname itself.
+fn nickname(value: &str) -> String {
+    let first = value.split(',').next().unwrap_or_default();
+    match first.split_once('=') {
+        Some((_, nickname)) => nickname.trim().to_lowercase(),
+        None => value.trim().to_lowercase(),
+    }
+}
+
+impl Directory {
+    /// Returns `None` unless `LDAP_URL` is set.
+    pub fn new(config: &Config) -> Option<Self> {
+        Some(Self {
+            url: config.ldap_url.clone()?,
+            bind: config
+                .ldap_bind_dn
+                .clone()
+                .map(|dn| (dn, config.ldap_bind_password.clone().unwrap_or_default())),
+            starttls: config.ldap_starttls,
+            members_group: config.ldap_members_group.clone(),
+            admins_group: config.ldap_admins_group.clone(),
+            attributes: config
+                .ldap_member_attributes
+                .split(',')
+                .map(|attribute| attribute.trim().to_string())
+                .filter(|attribute| !attribute.is_empty())
+                .collect(),
+            cache: Mutex::default(),
+        })
+    }
+
+    /// Reads the nicknames listed in the group `dn`.
+    async fn fetch(&self, dn: &str) -> Result<HashSet<String>> {
+        let settings = LdapConnSettings::new()
+            .set_conn_timeout(Duration::from_secs(10))
+            .set_starttls(self.starttls);
+        let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &self.url)
+            .await
+            .with_context(|| format!("unable to connect to {}", self.url))?;
+        ldap3::drive!(conn);
+        if let Some((bind_dn, password)) = &self.bind {
+            ldap.simple_bind(bind_dn, password)
+                .await
+                .and_then(|result| result.success())
+                .with_context(|| format!("unable to bind as {}", bind_dn))?;
+        }
+        let (entries, _) = ldap
+            .search(dn, Scope::Base, "(objectClass=*)", self.attributes.clone())
+            .await
+            .and_then(|result| result.success())
+            .with_context(|| format!("unable to read the group {}", dn))?;
+        let _ = ldap.unbind().await;
+
+        let entry = entries
+            .into_iter()
+            .next()
+            .map(SearchEntry::construct)
+            .with_context(|| format!("the group {} does not exist", dn))?;
+        Ok(entry
+            .attrs
+            .into_iter()
+            .filter(|(attribute, _)| {
+                self.attributes
+                    .iter()
+                    .any(|wanted| wanted.eq_ignore_ascii_case(attribute))
+            })
+            .flat_map(|(_, values)| values)
+            .map(|value| nickname(&value))
+            .collect())
+    }
+
+    async fn group(&self, dn: &str) -> Result<Members> {
+        if let Some((members, fetched)) = self.cache.lock().unwrap().get(dn)
+            && fetched.elapsed() < CACHE_TTL
+        {
+            return Ok(members.clone());
+        }
+        let members = Arc::new(self.fetch(dn).await?);
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(dn.to_string(), (members.clone(), Instant::now()));
+        Ok(members)
+    }
+
+    /// Whether `nickname` is an active member, which everybody is without
+    /// `LDAP_MEMBERS_GROUP`.
+    pub async fn is_member(&self, nickname: &str) -> Result<bool> {
+        match &self.members_group {
+            Some(dn) => Ok(self.group(dn).await?.contains(&nickname.to_lowercase())),
+            None => Ok(true),
+        }
+    }
+
+    /// Whether `nickname` is in `LDAP_ADMINS_GROUP`.
+    pub async fn is_admin(&self, nickname: &str) -> Result<bool> {
+        match &self.admins_group {
+            Some(dn) => Ok(self.group(dn).await?.contains(&nickname.to_lowercase())),
+            None => Ok(false),
+        }
+    }
+
+    /// Flags the devices of members who left the members group and clears
+    /// the flag of those who returned, returning the number flagged.
+    pub async fn sync(&self, repo: &dyn Repository) -> Result<u64> {
+        let Some(dn) = &self.members_group else {
+            return Ok(0);
+        };
+        let members = self.fetch(dn).await?;
+        // an empty group is more likely a broken directory than a club
+        // without members
+        if members.is_empty() {
+            bail!("the group {} is empty, not flagging any devices", dn);
+        }
+        repo.flag_departed(&members.into_iter().collect::<Vec<_>>())
+            .await
+    }
+
+    /// Runs [`Directory::sync`] every `interval`.
+    pub async fn flag_departed(&self, repo: Arc<dyn Repository>, interval: Duration) {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            match self.sync(repo.as_ref()).await {
+                Ok(0) => {}
+                Ok(flagged) => tracing::info!("flagged {} devices of departed members", flagged),
+                Err(err) => tracing::error!("unable to check for departed members: {:#}", err),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,134 @@
+//! Runs against the directory of [`testing::directory`]. The ignored
+//! `server` test checks a real server like OpenLDAP or glauth, see the README.
+
+use super::*;
+use crate::db::{self, Device, PoolSize, PrivacyLevel, Windows};
+use crate::testing;
+
+fn configured(overrides: &[(&str, &str)]) -> Directory {
+    Directory::new(&testing::config(overrides)).unwrap()
+}
+
+#[test]
+fn member_values_name_the_nickname() {
+    assert_eq!(nickname("uid=Alice,ou=people,dc=example,dc=org"), "alice");
+    assert_eq!(nickname("cn = bob ,ou=people,dc=example,dc=org"), "bob");
+    assert_eq!(nickname("carol"), "carol");
+}
+
+#[tokio::test]
+async fn groups_grant_membership_and_admin_rights() {
+    let (url, _) = testing::directory().await;
+    let directory = configured(&testing::ldap_config(&url));
+
+    assert!(directory.is_member("alice").await.unwrap());
+    assert!(directory.is_member("bob").await.unwrap());
+    assert!(!directory.is_member("carol").await.unwrap());
+    assert!(directory.is_admin("Carol").await.unwrap());
+    assert!(!directory.is_admin("alice").await.unwrap());
+
+    let without_groups = configured(&[("LDAP_URL", &url)]);
+    assert!(without_groups.is_member("mallory").await.unwrap());
+    assert!(!without_groups.is_admin("carol").await.unwrap());
+}
+
+#[tokio::test]
+async fn directory_errors_are_reported() {
+    let (url, _) = testing::directory().await;
+    let mut config = testing::ldap_config(&url);
+    config.push(("LDAP_BIND_PASSWORD", "guessed"));
+    let err = configured(&config).is_member("alice").await.unwrap_err();
+    assert!(format!("{:#}", err).contains("unable to bind"));
+
+    let mut config = testing::ldap_config(&url);
+    config.push(("LDAP_MEMBERS_GROUP", "cn=gone,ou=groups,dc=example,dc=org"));
+    assert!(configured(&config).is_member("alice").await.is_err());
+
+    let unreachable = format!("ldap://{}", testing::free_addr());
+    assert!(
+        configured(&testing::ldap_config(&unreachable))
+            .is_member("alice")
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn devices_of_departed_members_are_flagged() {
+    let (url, groups) = testing::directory().await;
+    let directory = configured(&testing::ldap_config(&url));
+    let repo = db::connect("memory://", PoolSize::default(), Windows::default())
+        .await
+        .unwrap();
+    for (macaddr, nickname) in [
+        ("00:11:22:00:00:01", "alice"),
+        ("00:11:22:00:00:02", "bob"),
+        ("00:11:22:00:00:03", "dave"),
+    ] {
+        repo.create_device(&Device::new(
+            macaddr.to_string(),
+            nickname.to_string(),
+            "laptop".to_string(),
+            PrivacyLevel::ShowUser,
+        ))
+        .await
+        .unwrap();
+    }
+
+    assert_eq!(directory.sync(repo.as_ref()).await.unwrap(), 1);
+    let departed = |devices: Vec<Device>| {
+        devices
+            .into_iter()
+            .filter(|device| device.departed.is_some())
+            .map(|device| device.nickname)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(departed(repo.devices().await.unwrap()), ["dave"]);
+
+    // an empty group does not flag everybody
+    let members = groups
+        .lock()
+        .unwrap()
+        .insert(testing::LDAP_MEMBERS.to_string(), vec![])
+        .unwrap();
+    assert!(directory.sync(repo.as_ref()).await.is_err());
+    assert_eq!(departed(repo.devices().await.unwrap()), ["dave"]);
+
+    let mut returned = members;
+    returned.push(("memberUid".to_string(), "dave".to_string()));
+    returned.retain(|(_, value)| !value.starts_with("uid=Bob"));
+    groups
+        .lock()
+        .unwrap()
+        .insert(testing::LDAP_MEMBERS.to_string(), returned);
+    directory.sync(repo.as_ref()).await.unwrap();
+    assert_eq!(departed(repo.devices().await.unwrap()), ["bob"]);
+}
+
+/// Checks the groups of a real directory. `TEST_LDAP_MEMBER` has to be in
+/// `TEST_LDAP_MEMBERS_GROUP`, the bind DN and password are optional.
+#[tokio::test]
+#[ignore = "needs a server, see TEST_LDAP_URL"]
+async fn server() {
+    let url = std::env::var("TEST_LDAP_URL").expect("TEST_LDAP_URL");
+    let setting = |name: &str| std::env::var(format!("TEST_{}", name)).ok();
+    let (bind_dn, password, group, member) = (
+        setting("LDAP_BIND_DN"),
+        setting("LDAP_BIND_PASSWORD"),
+        setting("LDAP_MEMBERS_GROUP").expect("TEST_LDAP_MEMBERS_GROUP"),
+        setting("LDAP_MEMBER").expect("TEST_LDAP_MEMBER"),
+    );
+    let mut config = vec![("LDAP_URL", url.as_str()), ("LDAP_MEMBERS_GROUP", &group)];
+    if std::env::var("TEST_LDAP_STARTTLS").is_ok() {
+        config.push(("LDAP_STARTTLS", "true"));
+    }
+    if let (Some(bind_dn), Some(password)) = (&bind_dn, &password) {
+        config.extend([
+            ("LDAP_BIND_DN", bind_dn.as_str()),
+            ("LDAP_BIND_PASSWORD", password),
+        ]);
+    }
+    let directory = configured(&config);
+    assert!(directory.is_member(&member).await.unwrap());
+    assert!(!directory.is_member("nobody-by-this-name").await.unwrap());
+}
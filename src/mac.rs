@@ -0,0 +1,49 @@
+use anyhow::{Result, anyhow};
+
+/// Normalizes a user supplied MAC address to the lowercase, colon separated
+/// format the UniFi controller reports (`aa:bb:cc:dd:ee:ff`).
+///
+/// Accepts colon or dash separated octets, Cisco style dotted groups
+/// (`aabb.ccdd.eeff`) and plain hex strings.
+pub fn normalize(input: &str) -> Result<String> {
+    let digits: String = input
+        .trim()
+        .chars()
+        .filter(|c| !matches!(c, ':' | '-' | '.' | ' '))
+        .collect();
+
+    if digits.len() != 12 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(anyhow!("\"{}\" is not a valid mac address", input.trim()));
+    }
+
+    let digits = digits.to_ascii_lowercase();
+    let octets: Vec<&str> = (0..12).step_by(2).map(|i| &digits[i..i + 2]).collect();
+    let mac = octets.join(":");
+
+    if mac == "00:00:00:00:00:00" || mac == "ff:ff:ff:ff:ff:ff" {
+        return Err(anyhow!("\"{}\" is not a device address", mac));
+    }
+    if is_multicast(&mac) {
+        return Err(anyhow!("\"{}\" is a multicast address", mac));
+    }
+    Ok(mac)
+}
+
+fn first_octet(mac: &str) -> Option<u8> {
+    mac.get(0..2).and_then(|o| u8::from_str_radix(o, 16).ok())
+}
+
+/// Multicast addresses have the I/G bit of the first octet set and are never
+/// assigned to a single device.
+pub fn is_multicast(mac: &str) -> bool {
+    first_octet(mac).is_some_and(|o| o & 0x01 != 0)
+}
+
+/// Locally administered addresses have the U/L bit of the first octet set.
+/// Phones and laptops use them for randomized (private) addresses.
+pub fn is_locally_administered(mac: &str) -> bool {
+    first_octet(mac).is_some_and(|o| o & 0x02 != 0)
+}
+
+#[cfg(test)]
+mod tests;
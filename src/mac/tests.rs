@@ -0,0 +1,60 @@
+use super::*;
+
+#[test]
+fn common_notations_are_normalized() {
+    for input in [
+        "00:11:22:AA:BB:CC",
+        "00-11-22-aa-bb-cc",
+        "0011.22aa.bbcc",
+        "001122aabbcc",
+        " 00:11:22:aa:bb:cc\n",
+    ] {
+        assert_eq!(normalize(input).unwrap(), "00:11:22:aa:bb:cc", "{}", input);
+    }
+}
+
+#[test]
+fn malformed_addresses_are_rejected() {
+    for input in [
+        "",
+        "00:11:22:aa:bb",
+        "00:11:22:aa:bb:cc:dd",
+        "00:11:22:aa:bb:zz",
+    ] {
+        let err = normalize(input).unwrap_err();
+        assert!(
+            err.to_string().contains("is not a valid mac address"),
+            "{}",
+            err
+        );
+    }
+}
+
+#[test]
+fn addresses_of_no_single_device_are_rejected() {
+    for input in ["00:00:00:00:00:00", "FF-FF-FF-FF-FF-FF"] {
+        let err = normalize(input).unwrap_err();
+        assert!(
+            err.to_string().contains("is not a device address"),
+            "{}",
+            err
+        );
+    }
+    let err = normalize("01:00:5e:00:00:fb").unwrap_err();
+    assert!(
+        err.to_string().contains("is a multicast address"),
+        "{}",
+        err
+    );
+    assert!(normalize("33:33:00:00:00:01").is_err());
+}
+
+#[test]
+fn address_bits_are_read_from_the_first_octet() {
+    assert!(is_multicast("01:00:5e:00:00:fb"));
+    assert!(!is_multicast("00:11:22:aa:bb:cc"));
+    assert!(is_locally_administered("02:11:22:aa:bb:cc"));
+    assert!(is_locally_administered("da:a1:19:00:00:01"));
+    assert!(!is_locally_administered("00:11:22:aa:bb:cc"));
+    assert!(!is_locally_administered("not a mac"));
+}
@@ -1,24 +1,40 @@
-use anyhow::{Context, Result};
+use anyhow::{Result, anyhow};
 use axum::extract::State;
 use axum::{
     Router,
-    middleware::from_extractor,
+    middleware::{from_extractor_with_state, from_fn, from_fn_with_state},
     routing::{get, post},
 };
 use axum_messages::{Level, MessagesManagerLayer};
 use envconfig::Envconfig;
-use sqlx::MySqlPool;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
+use tokio::sync::watch;
 use tower_http::{services::ServeDir, trace::TraceLayer};
-use tower_sessions::{MemoryStore, SessionManagerLayer};
+use tower_sessions::cookie::SameSite;
+use tower_sessions::{ExpiredDeletion, SessionManagerLayer};
 
+mod auth;
+mod cli;
+mod config;
 mod db;
 mod forms;
+mod health;
 mod helpers;
+mod ldap;
+mod mac;
+mod metrics;
 mod middleware;
+mod oidc;
 mod routes;
 mod scan;
+mod status;
+mod supervisor;
 mod templates;
+#[cfg(test)]
+mod testing;
 
 /// Configuration
 #[derive(Clone, Envconfig)]
@@ -29,12 +45,15 @@ struct Config {
     #[envconfig(from = "DATABASE_DSN")]
     dsn: String,
 
-    #[envconfig(from = "ALLOWED_SUBNETS", default = "0.0.0.0/0")]
+    #[envconfig(from = "ALLOWED_SUBNETS", default = "0.0.0.0/0,::/0")]
     allowed_subnets: String,
 
     #[envconfig(from = "UNIFI_HOSTNAME")]
     unifi_hostname: String,
 
+    #[envconfig(from = "UNIFI_SCHEME", default = "https")]
+    unifi_scheme: String,
+
     #[envconfig(from = "UNIFI_USERNAME")]
     unifi_username: String,
 
@@ -62,13 +81,289 @@ struct Config {
     )]
     mqtt_member_device_count_topic: String,
 
+    #[envconfig(
+        from = "MQTT_INFRASTRUCTURE_TOPIC",
+        default = "sensor/space/infrastructure"
+    )]
+    mqtt_infrastructure_topic: String,
+
+    #[envconfig(from = "MQTT_GUEST_COUNT_TOPIC", default = "sensor/space/guest/count")]
+    mqtt_guest_count_topic: String,
+
+    #[envconfig(from = "MQTT_SCANNER_TOPIC", default = "sensor/space/scanner")]
+    mqtt_scanner_topic: String,
+
     #[envconfig(from = "MQTT_HOST")]
     mqtt_host: String,
+
+    #[envconfig(from = "MQTT_PORT", default = "1883")]
+    mqtt_port: u16,
+
+    #[envconfig(from = "TRUST_FORWARDED_FOR", default = "false")]
+    trust_forwarded_for: bool,
+
+    #[envconfig(from = "UNASSIGNED_MODE", default = "list")]
+    unassigned_mode: UnassignedMode,
+
+    #[envconfig(from = "DATABASE_MIGRATE", default = "true")]
+    migrate: bool,
+
+    #[envconfig(from = "DATABASE_MAX_CONNECTIONS", default = "10")]
+    db_max_connections: u32,
+
+    #[envconfig(from = "DATABASE_MIN_CONNECTIONS", default = "0")]
+    db_min_connections: u32,
+
+    #[envconfig(from = "SCAN_INTERVAL", default = "60")]
+    scan_interval: u64,
+
+    #[envconfig(from = "PRESENT_WINDOW", default = "1800")]
+    present_window: u64,
+
+    #[envconfig(from = "UNASSIGNED_WINDOW", default = "1800")]
+    unassigned_window: u64,
+
+    #[envconfig(from = "STATUS_MIN_MEMBERS", default = "1")]
+    status_min_members: usize,
+
+    #[envconfig(from = "STATUS_IGNORE", default = "")]
+    status_ignore: String,
+
+    #[envconfig(from = "MQTT_STATUS_OVERRIDE_TOPIC")]
+    mqtt_status_override_topic: Option<String>,
+
+    #[envconfig(from = "MQTT_DOOR_TOPIC")]
+    mqtt_door_topic: Option<String>,
+
+    #[envconfig(from = "ADMINS", default = "")]
+    admins: String,
+
+    #[envconfig(from = "ROLE", default = "both")]
+    role: Role,
+
+    #[envconfig(from = "SESSION_SECURE", default = "false")]
+    session_secure: bool,
+
+    #[envconfig(from = "AUTH_MODE", default = "cookie")]
+    auth_mode: AuthMode,
+
+    #[envconfig(from = "TRUSTED_PROXIES", default = "")]
+    trusted_proxies: String,
+
+    #[envconfig(from = "AUTH_HEADER", default = "X-Forwarded-User")]
+    auth_header: String,
+
+    #[envconfig(from = "AUTH_JWT_HEADER", default = "Authorization")]
+    auth_jwt_header: String,
+
+    #[envconfig(from = "AUTH_JWT_COOKIE")]
+    auth_jwt_cookie: Option<String>,
+
+    #[envconfig(from = "AUTH_JWT_SECRET")]
+    auth_jwt_secret: Option<String>,
+
+    #[envconfig(from = "AUTH_JWKS_URL")]
+    auth_jwks_url: Option<String>,
+
+    #[envconfig(from = "AUTH_JWT_CLAIM", default = "preferred_username")]
+    auth_jwt_claim: String,
+
+    #[envconfig(from = "AUTH_JWT_ISSUER")]
+    auth_jwt_issuer: Option<String>,
+
+    #[envconfig(from = "AUTH_JWT_AUDIENCE")]
+    auth_jwt_audience: Option<String>,
+
+    #[envconfig(from = "OIDC_ISSUER")]
+    oidc_issuer: Option<String>,
+
+    #[envconfig(from = "OIDC_CLIENT_ID")]
+    oidc_client_id: Option<String>,
+
+    #[envconfig(from = "OIDC_CLIENT_SECRET")]
+    oidc_client_secret: Option<String>,
+
+    #[envconfig(from = "OIDC_REDIRECT_URL")]
+    oidc_redirect_url: Option<String>,
+
+    #[envconfig(from = "OIDC_SCOPES", default = "openid profile")]
+    oidc_scopes: String,
+
+    #[envconfig(from = "OIDC_NICKNAME_CLAIM", default = "preferred_username")]
+    oidc_nickname_claim: String,
+
+    #[envconfig(from = "LDAP_URL")]
+    ldap_url: Option<String>,
+
+    #[envconfig(from = "LDAP_BIND_DN")]
+    ldap_bind_dn: Option<String>,
+
+    #[envconfig(from = "LDAP_BIND_PASSWORD")]
+    ldap_bind_password: Option<String>,
+
+    #[envconfig(from = "LDAP_STARTTLS", default = "false")]
+    ldap_starttls: bool,
+
+    #[envconfig(from = "LDAP_MEMBERS_GROUP")]
+    ldap_members_group: Option<String>,
+
+    #[envconfig(from = "LDAP_ADMINS_GROUP")]
+    ldap_admins_group: Option<String>,
+
+    #[envconfig(
+        from = "LDAP_MEMBER_ATTRIBUTES",
+        default = "member,uniqueMember,memberUid"
+    )]
+    ldap_member_attributes: String,
+
+    #[envconfig(from = "LDAP_SYNC_INTERVAL", default = "3600")]
+    ldap_sync_interval: u64,
+}
+
+impl Config {
+    fn pool_size(&self) -> db::PoolSize {
+        db::PoolSize {
+            max_connections: self.db_max_connections,
+            min_connections: self.db_min_connections,
+        }
+    }
+
+    fn is_admin(&self, nickname: &str) -> bool {
+        self.admins
+            .split(',')
+            .any(|admin| admin.trim().eq_ignore_ascii_case(nickname))
+    }
+
+    /// Age of the last successful scan after which the instance is no
+    /// longer ready.
+    fn max_scan_age(&self) -> Duration {
+        Duration::from_secs(self.scan_interval * 3)
+    }
+
+    /// How long the active scanner keeps the lease without renewing it,
+    /// another instance takes over after that.
+    fn lease_ttl(&self) -> Duration {
+        Duration::from_secs(self.scan_interval * 3)
+    }
+
+    fn windows(&self) -> db::Windows {
+        db::Windows {
+            present: Duration::from_secs(self.present_window),
+            unassigned: Duration::from_secs(self.unassigned_window),
+        }
+    }
+}
+
+/// How members get to register devices that are online but unknown.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnassignedMode {
+    /// Every member sees all unassigned devices and may register any of them.
+    List,
+    /// Members only see the device they are using and have to claim other
+    /// devices by their MAC address.
+    Claim,
+}
+
+impl FromStr for UnassignedMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "list" => Ok(UnassignedMode::List),
+            "claim" => Ok(UnassignedMode::Claim),
+            _ => Err(anyhow!("unknown unassigned mode \"{}\"", s)),
+        }
+    }
+}
+
+/// What an instance runs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// Only the web interface, the scanning is left to other instances.
+    Web,
+    /// Only the scanner, besides it the health and metrics endpoints are
+    /// served.
+    Scanner,
+    /// The web interface and the scanner.
+    Both,
+}
+
+impl Role {
+    fn web(self) -> bool {
+        self != Role::Scanner
+    }
+
+    fn scanner(self) -> bool {
+        self != Role::Web
+    }
+}
+
+impl FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "web" => Ok(Role::Web),
+            "scanner" => Ok(Role::Scanner),
+            "both" => Ok(Role::Both),
+            _ => Err(anyhow!("unknown role \"{}\"", s)),
+        }
+    }
+}
+
+/// How the auth proxy tells mac4nick who is logged in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuthMode {
+    /// The nickname in the `_forward_auth_name` cookie, which is not
+    /// verified at all.
+    Cookie,
+    /// The nickname in a header set by one of the trusted proxies.
+    Header,
+    /// A claim of a token signed with a shared secret or a key of a JWKS.
+    Jwt,
+    /// No proxy, members log in with an OpenID Connect provider.
+    Oidc,
+}
+
+impl FromStr for AuthMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "cookie" => Ok(AuthMode::Cookie),
+            "header" => Ok(AuthMode::Header),
+            "jwt" => Ok(AuthMode::Jwt),
+            "oidc" => Ok(AuthMode::Oidc),
+            _ => Err(anyhow!("unknown auth mode \"{}\"", s)),
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct AppState {
-    pool: MySqlPool,
+    repo: Arc<dyn db::Repository>,
+    config: watch::Receiver<Config>,
+    report: watch::Receiver<Option<status::Report>>,
+    metrics: Arc<metrics::Metrics>,
+    health: Arc<health::Health>,
+    auth: Arc<auth::Verifier>,
+    directory: Option<Arc<ldap::Directory>>,
+}
+
+impl AppState {
+    /// Whether `nickname` is listed in `ADMINS` or in the LDAP admin group.
+    async fn is_admin(&self, nickname: &str) -> bool {
+        if self.config.borrow().is_admin(nickname) {
+            return true;
+        }
+        let Some(directory) = &self.directory else {
+            return false;
+        };
+        directory.is_admin(nickname).await.unwrap_or_else(|err| {
+            tracing::error!("unable to check the admin group: {:#}", err);
+            false
+        })
+    }
 }
 
 type AxumAppState = State<AppState>;
@@ -82,48 +377,173 @@ async fn main() -> Result<()> {
         openssl_probe::init_openssl_env_vars();
     }
 
-    let config = Config::init_from_env().context("unable to parse environment")?;
+    let args: cli::Args = argh::from_env();
+    let file = config::file(args.config.as_deref());
+    // the management commands only touch the database
+    let scope = match args.command {
+        Some(cli::Command::Serve(_) | cli::Command::Scan(_)) | None => config::Scope::All,
+        Some(_) => config::Scope::Database,
+    };
+    let config = config::load(file.as_deref(), scope)?;
 
     tracing_subscriber::fmt()
         .with_env_filter(tracing_subscriber::EnvFilter::from_env("RUST_LOG"))
         .init();
 
-    let job = tokio::spawn(async move {
-        let config = Config::init_from_env()
-            .context("unable to parse environment")
-            .unwrap();
-        let scanner = scan::Scanner::new(&config);
-        let mut interval = tokio::time::interval(Duration::from_secs(60));
-        loop {
-            interval.tick().await;
-            if let Err(err) = scanner.scan().await {
-                tracing::error!("unable to scan for devices: {}", err);
-            };
-        }
-    });
+    match args.command {
+        Some(cli::Command::Migrate(_)) => migrate(config).await,
+        Some(cli::Command::Scan(command)) => cli::scan(config, file, command).await,
+        Some(cli::Command::Devices(command)) => cli::devices(config, command).await,
+        Some(cli::Command::Export(_)) => cli::export(config).await,
+        Some(cli::Command::Import(command)) => cli::import(config, command).await,
+        Some(cli::Command::Serve(_)) | None => serve(config, file).await,
+    }
+}
 
-    let pool = MySqlPool::connect(&config.dsn)
-        .await
-        .context("unable to open database connection")?;
-    let session_store = MemoryStore::default();
-    let session_layer = SessionManagerLayer::new(session_store).with_secure(false);
+fn router(app_state: AppState) -> Router {
+    let oidc = app_state.auth.oidc().is_some();
+    // the provider redirects back from another site, a strict session
+    // cookie would not be sent along with the callback
+    let session_layer = SessionManagerLayer::new(app_state.repo.sessions())
+        .with_secure(app_state.config.borrow().session_secure)
+        .with_same_site(if oidc {
+            SameSite::Lax
+        } else {
+            SameSite::Strict
+        });
 
-    let app_state = AppState { pool };
-    let app = Router::new()
+    let mut router = Router::new();
+    if app_state.config.borrow().role.web() {
+        router = router
+            .route("/", get(routes::index))
+            .route("/change", post(routes::change))
+            .route("/status", post(routes::status))
+            .route("/infrastructure", post(routes::infrastructure));
+        if oidc {
+            router = router.route("/auth/logout", post(routes::logout));
+        }
+        router = router
+            .nest_service("/static", ServeDir::new("static"))
+            .layer(from_fn(middleware::csrf))
+            .layer(from_extractor_with_state::<middleware::ForwardAuth, _>(
+                app_state.clone(),
+            ));
+        if oidc {
+            router = router
+                .route("/auth/login", get(routes::login))
+                .route("/auth/callback", get(routes::callback));
+        }
+        router = router.layer(MessagesManagerLayer).layer(session_layer);
+    }
+
+    // routes added after the layers are public
+    router
         .route("/healthz", get(routes::healthz))
-        .route("/", get(routes::index))
-        .route("/change", post(routes::change))
-        .nest_service("/static", ServeDir::new("static"))
+        .route("/readyz", get(routes::readyz))
+        .route("/api/status", get(routes::api_status))
+        .route("/metrics", get(routes::metrics))
+        .layer(from_fn_with_state(
+            app_state.metrics.clone(),
+            metrics::track,
+        ))
         .with_state(app_state)
-        .layer(MessagesManagerLayer)
-        .layer(session_layer)
-        .layer(from_extractor::<middleware::ForwardAuth>())
-        .layer(TraceLayer::new_for_http());
+        .layer(TraceLayer::new_for_http())
+}
+
+async fn migrate(config: Config) -> Result<()> {
+    let repo = db::connect(&config.dsn, config.pool_size(), config.windows()).await?;
+    repo.migrate().await?;
+    tracing::info!("database schema is up to date");
+    Ok(())
+}
+
+async fn serve(config: Config, file: Option<String>) -> Result<()> {
+    let metrics = Arc::new(metrics::Metrics::default());
+    let repo = metrics::Metered::wrap(
+        db::connect(&config.dsn, config.pool_size(), config.windows()).await?,
+        metrics.clone(),
+    );
+    if config.migrate {
+        repo.migrate().await?;
+    }
+
+    let health = Arc::new(health::Health::default());
+    let updates = config::watch(file, config.clone(), health.clone());
+    let (shutdown, stopping) = watch::channel(false);
+    let (report, scanner) = if config.role.scanner() {
+        let scanner = scan::Scanner::new(&config, repo.clone(), metrics.clone(), health.clone());
+        let report = scanner.subscribe();
+        let job = tokio::spawn(scanner.clone().supervise(stopping, updates.clone()));
+        (report, Some((scanner, job)))
+    } else {
+        health.scanner(health::ScannerState::Disabled);
+        (watch::channel(None).1, None)
+    };
+
+    let directory = ldap::Directory::new(&config).map(Arc::new);
+    if config.role.web() {
+        if config.auth_mode == AuthMode::Cookie && config.trusted_proxies.is_empty() {
+            tracing::warn!(
+                "anyone reaching mac4nick directly may claim any nickname, \
+                 set TRUSTED_PROXIES or use another AUTH_MODE"
+            );
+        }
+        let sessions = repo.sessions();
+        tokio::spawn(supervisor::supervise(
+            "sessions",
+            health.clone(),
+            move || {
+                let sessions = sessions.clone();
+                async move {
+                    let mut interval = tokio::time::interval(Duration::from_secs(3600));
+                    loop {
+                        interval.tick().await;
+                        if let Err(err) = sessions.delete_expired().await {
+                            tracing::error!("unable to delete expired sessions: {}", err);
+                        }
+                    }
+                }
+            },
+        ));
+        if let Some(directory) = directory.clone() {
+            let repo = repo.clone();
+            let interval = Duration::from_secs(config.ldap_sync_interval);
+            tokio::spawn(supervisor::supervise("ldap", health.clone(), move || {
+                let (directory, repo) = (directory.clone(), repo.clone());
+                async move { directory.flag_departed(repo, interval).await }
+            }));
+        }
+    }
+
+    let app = router(AppState {
+        repo,
+        config: updates,
+        report,
+        metrics,
+        health,
+        auth: Arc::new(auth::Verifier::new(&config)?),
+        directory,
+    });
 
     tracing::info!("listening on {}", config.listen);
     let listener = tokio::net::TcpListener::bind(config.listen).await?;
-    axum::serve(listener, app).await?;
+    // the scanner stops while the open connections are drained
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(async move {
+        supervisor::shutdown_signal().await;
+        shutdown.send_replace(true);
+    })
+    .await?;
 
-    job.await.expect("lock");
+    if let Some((scanner, job)) = scanner {
+        // the final status is published even if the task failed
+        if let Err(err) = job.await {
+            tracing::error!("scanner task failed: {}", err);
+        }
+        scanner.stop().await;
+    }
     Ok(())
 }
@@ -0,0 +1,310 @@
+//! Prometheus metrics of the scanner, the database and the web interface.
+
+use crate::db::{AliveDevice, Claim, Device, Infrastructure, Repository, Sessions, StatusOverride};
+use crate::status::{Report, SpaceStatus};
+use anyhow::Result;
+use async_trait::async_trait;
+use axum::extract::{MatchedPath, Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{Histogram, exponential_buckets};
+use prometheus_client::registry::Registry;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RequestLabels {
+    method: String,
+    path: String,
+    status: u16,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RouteLabels {
+    method: String,
+    path: String,
+}
+
+fn request_duration() -> Histogram {
+    // 1ms to about 4s
+    Histogram::new(exponential_buckets(0.001, 2.0, 13))
+}
+
+pub struct Metrics {
+    registry: Registry,
+    members: Gauge,
+    devices: Gauge,
+    guests: Gauge,
+    open: Gauge,
+    pub scan_duration: Histogram,
+    pub unifi_failures: Counter,
+    pub db_errors: Counter,
+    pub mqtt_failures: Counter,
+    http_requests: Family<RequestLabels, Counter>,
+    http_duration: Family<RouteLabels, Histogram, fn() -> Histogram>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let mut metrics = Self {
+            registry: Registry::with_prefix("mac4nick"),
+            members: Gauge::default(),
+            devices: Gauge::default(),
+            guests: Gauge::default(),
+            open: Gauge::default(),
+            // 100ms to about 100s
+            scan_duration: Histogram::new(exponential_buckets(0.1, 2.0, 11)),
+            unifi_failures: Counter::default(),
+            db_errors: Counter::default(),
+            mqtt_failures: Counter::default(),
+            http_requests: Family::default(),
+            http_duration: Family::new_with_constructor(request_duration),
+        };
+        let registry = &mut metrics.registry;
+        registry.register(
+            "members_present",
+            "Members present as of the latest scan",
+            metrics.members.clone(),
+        );
+        registry.register(
+            "devices_present",
+            "Member devices present as of the latest scan",
+            metrics.devices.clone(),
+        );
+        registry.register(
+            "guests_present",
+            "Estimated guests present as of the latest scan",
+            metrics.guests.clone(),
+        );
+        registry.register(
+            "space_open",
+            "Whether the space is open as of the latest scan",
+            metrics.open.clone(),
+        );
+        registry.register(
+            "scan_duration_seconds",
+            "Duration of the scans",
+            metrics.scan_duration.clone(),
+        );
+        registry.register(
+            "unifi_failures",
+            "Failed requests to the UniFi controller",
+            metrics.unifi_failures.clone(),
+        );
+        registry.register(
+            "db_errors",
+            "Failed database queries",
+            metrics.db_errors.clone(),
+        );
+        registry.register(
+            "mqtt_publish_failures",
+            "Messages that could not be handed to the MQTT client",
+            metrics.mqtt_failures.clone(),
+        );
+        registry.register(
+            "http_requests",
+            "Handled HTTP requests",
+            metrics.http_requests.clone(),
+        );
+        registry.register(
+            "http_request_duration_seconds",
+            "Duration of the handled HTTP requests",
+            metrics.http_duration.clone(),
+        );
+        metrics
+    }
+}
+
+impl Metrics {
+    /// Updates the gauges from the outcome of a scan.
+    pub fn report(&self, report: &Report) {
+        self.members.set(report.members as i64);
+        self.devices.set(report.devices as i64);
+        self.guests.set(report.guests as i64);
+        self.open.set((report.status == SpaceStatus::Open).into());
+    }
+
+    pub fn observe_scan(&self, duration: Duration) {
+        self.scan_duration.observe(duration.as_secs_f64());
+    }
+
+    /// Renders all metrics in the OpenMetrics text format.
+    pub fn encode(&self) -> Result<String, std::fmt::Error> {
+        let mut buffer = String::new();
+        prometheus_client::encoding::text::encode(&mut buffer, &self.registry)?;
+        Ok(buffer)
+    }
+}
+
+/// Middleware counting the requests and their duration per route. Requests
+/// that match no route share the path `unmatched` to keep the number of
+/// series bounded.
+pub async fn track(State(metrics): State<Arc<Metrics>>, request: Request, next: Next) -> Response {
+    let method = request.method().to_string();
+    let path = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let started = Instant::now();
+    let response = next.run(request).await;
+    metrics
+        .http_duration
+        .get_or_create(&RouteLabels {
+            method: method.clone(),
+            path: path.clone(),
+        })
+        .observe(started.elapsed().as_secs_f64());
+    metrics
+        .http_requests
+        .get_or_create(&RequestLabels {
+            method,
+            path,
+            status: response.status().as_u16(),
+        })
+        .inc();
+    response
+}
+
+/// Counts the failed queries of the wrapped repository.
+pub struct Metered {
+    inner: Arc<dyn Repository>,
+    metrics: Arc<Metrics>,
+}
+
+impl Metered {
+    pub fn wrap(inner: Arc<dyn Repository>, metrics: Arc<Metrics>) -> Arc<dyn Repository> {
+        Arc::new(Self { inner, metrics })
+    }
+
+    fn count<T>(&self, result: Result<T>) -> Result<T> {
+        if result.is_err() {
+            self.metrics.db_errors.inc();
+        }
+        result
+    }
+}
+
+#[async_trait]
+impl Repository for Metered {
+    async fn migrate(&self) -> Result<()> {
+        self.count(self.inner.migrate().await)
+    }
+
+    fn sessions(&self) -> Sessions {
+        self.inner.sessions()
+    }
+
+    async fn ping(&self) -> Result<()> {
+        self.count(self.inner.ping().await)
+    }
+
+    async fn create_device(&self, device: &Device) -> Result<()> {
+        self.count(self.inner.create_device(device).await)
+    }
+
+    async fn devices_for_user(&self, user: &str) -> Result<Vec<Device>> {
+        self.count(self.inner.devices_for_user(user).await)
+    }
+
+    async fn present_devices(&self) -> Result<Vec<Device>> {
+        self.count(self.inner.present_devices().await)
+    }
+
+    async fn devices(&self) -> Result<Vec<Device>> {
+        self.count(self.inner.devices().await)
+    }
+
+    async fn device_for_mac(&self, macaddr: &str) -> Result<Option<Device>> {
+        self.count(self.inner.device_for_mac(macaddr).await)
+    }
+
+    async fn devices_for_macs(&self, macaddrs: &[String]) -> Result<Vec<Device>> {
+        self.count(self.inner.devices_for_macs(macaddrs).await)
+    }
+
+    async fn update_device(&self, device: &Device) -> Result<()> {
+        self.count(self.inner.update_device(device).await)
+    }
+
+    async fn delete_device(&self, device: &Device) -> Result<()> {
+        self.count(self.inner.delete_device(device).await)
+    }
+
+    async fn flag_departed(&self, members: &[String]) -> Result<u64> {
+        self.count(self.inner.flag_departed(members).await)
+    }
+
+    async fn log_all(&self, alive: &[AliveDevice]) -> Result<()> {
+        self.count(self.inner.log_all(alive).await)
+    }
+
+    async fn unassigned(&self) -> Result<Vec<AliveDevice>> {
+        self.count(self.inner.unassigned().await)
+    }
+
+    async fn guests(&self) -> Result<Vec<AliveDevice>> {
+        self.count(self.inner.guests().await)
+    }
+
+    async fn alive_for_ip(&self, ip: IpAddr) -> Result<Option<AliveDevice>> {
+        self.count(self.inner.alive_for_ip(ip).await)
+    }
+
+    async fn create_claim(&self, claim: &Claim) -> Result<bool> {
+        self.count(self.inner.create_claim(claim).await)
+    }
+
+    async fn claims_for_user(&self, user: &str) -> Result<Vec<Claim>> {
+        self.count(self.inner.claims_for_user(user).await)
+    }
+
+    async fn seen_claims(&self, user: &str) -> Result<Vec<Claim>> {
+        self.count(self.inner.seen_claims(user).await)
+    }
+
+    async fn delete_claim(&self, claim: &Claim) -> Result<()> {
+        self.count(self.inner.delete_claim(claim).await)
+    }
+
+    async fn force_status(&self, forced: &StatusOverride, duration: Duration) -> Result<()> {
+        self.count(self.inner.force_status(forced, duration).await)
+    }
+
+    async fn forced_status(&self) -> Result<Option<StatusOverride>> {
+        self.count(self.inner.forced_status().await)
+    }
+
+    async fn clear_forced_status(&self) -> Result<()> {
+        self.count(self.inner.clear_forced_status().await)
+    }
+
+    async fn acquire_lease(&self, holder: &str, ttl: Duration) -> Result<bool> {
+        self.count(self.inner.acquire_lease(holder, ttl).await)
+    }
+
+    async fn release_lease(&self, holder: &str) -> Result<()> {
+        self.count(self.inner.release_lease(holder).await)
+    }
+
+    async fn create_infrastructure(&self, infrastructure: &Infrastructure) -> Result<()> {
+        self.count(self.inner.create_infrastructure(infrastructure).await)
+    }
+
+    async fn infrastructure(&self) -> Result<Vec<Infrastructure>> {
+        self.count(self.inner.infrastructure().await)
+    }
+
+    async fn infrastructure_for_mac(&self, macaddr: &str) -> Result<Option<Infrastructure>> {
+        self.count(self.inner.infrastructure_for_mac(macaddr).await)
+    }
+
+    async fn delete_infrastructure(&self, infrastructure: &Infrastructure) -> Result<()> {
+        self.count(self.inner.delete_infrastructure(infrastructure).await)
+    }
+}
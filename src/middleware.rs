@@ -1,23 +1,185 @@
+use crate::{AppState, auth};
 use axum::{
-    extract::FromRequestParts,
-    http::{StatusCode, request::Parts},
+    body::{Body, to_bytes},
+    extract::{ConnectInfo, FromRequestParts, Request},
+    http::{Method, StatusCode, request::Parts},
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
 };
-use axum_extra::extract::CookieJar;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use tower_sessions::Session;
 
+/// Session key of the CSRF token.
+const CSRF_KEY: &str = "csrf";
+
+/// Largest form body the CSRF check reads.
+const MAX_FORM_SIZE: usize = 64 * 1024;
+
+/// Nickname of the member sending the request, as verified by
+/// [`crate::auth::Verifier`].
+#[derive(Clone)]
 pub(crate) struct ForwardAuth(pub String);
 
-impl<S> FromRequestParts<S> for ForwardAuth
+impl FromRequestParts<AppState> for ForwardAuth {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        // the layer in front of the handlers already verified the request
+        if let Some(auth) = parts.extensions.get::<ForwardAuth>() {
+            return Ok(auth.clone());
+        }
+        match state.auth.verify(parts).await {
+            Ok(nickname) => {
+                let auth = ForwardAuth(nickname);
+                parts.extensions.insert(auth.clone());
+                Ok(auth)
+            }
+            // members that are not logged in yet are sent to the provider
+            Err(_) if state.auth.oidc().is_some() && parts.method == Method::GET => {
+                Err(Redirect::to("/auth/login").into_response())
+            }
+            Err(err) => {
+                tracing::warn!(
+                    "rejecting unauthenticated {} {} from {}: {:#}",
+                    parts.method,
+                    parts.uri,
+                    auth::peer(parts).map_or("an unknown address".to_string(), |ip| ip.to_string()),
+                    err
+                );
+                Err((StatusCode::UNAUTHORIZED, "Unauthorized").into_response())
+            }
+        }
+    }
+}
+
+/// Address of the requesting client. Behind the auth proxy the last entry of
+/// `X-Forwarded-For` is used, as it is the one appended by the proxy itself.
+/// Anybody else could send a forged header, so it is only read from the
+/// `TRUSTED_PROXIES`.
+pub(crate) struct ClientIp(pub Option<IpAddr>);
+
+impl FromRequestParts<AppState> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let forwarded = parts
+            .headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|entry| entry.trim().parse::<IpAddr>().ok())
+            .next_back();
+        let ip = match forwarded {
+            Some(ip)
+                if state.config.borrow().trust_forwarded_for
+                    && state.auth.is_trusted_proxy(parts) =>
+            {
+                Some(ip)
+            }
+            _ => parts
+                .extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip()),
+        };
+        Ok(ClientIp(ip.map(|ip| ip.to_canonical())))
+    }
+}
+
+/// Token every request changing something has to carry, kept in the
+/// session of the member.
+pub(crate) struct CsrfToken(pub String);
+
+impl<S> FromRequestParts<S> for CsrfToken
 where
     S: Send + Sync,
 {
     type Rejection = (StatusCode, &'static str);
 
-    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
-        let jar = CookieJar::from_headers(&parts.headers);
-        if let Some(nickname) = jar.get("_forward_auth_name") {
-            return Ok(ForwardAuth(nickname.value().to_owned()));
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let session = Session::from_request_parts(parts, state).await?;
+        if let Ok(Some(token)) = session.get::<String>(CSRF_KEY).await {
+            return Ok(CsrfToken(token));
+        }
+        let token: String = rand::random::<[u8; 32]>()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        match session.insert(CSRF_KEY, &token).await {
+            Ok(()) => Ok(CsrfToken(token)),
+            Err(err) => {
+                tracing::error!("unable to store csrf token: {}", err);
+                Err((StatusCode::INTERNAL_SERVER_ERROR, "Session unavailable"))
+            }
+        }
+    }
+}
+
+/// Compares without returning early, so the time taken does not reveal how
+/// much of the token matched.
+fn tokens_match(expected: &str, actual: &str) -> bool {
+    expected.len() == actual.len()
+        && expected
+            .bytes()
+            .zip(actual.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Rejects requests that change something unless they carry the token of
+/// the session, either in the `X-CSRF-Token` header or in the `csrf` field
+/// of the form.
+pub(crate) async fn csrf(session: Session, request: Request, next: Next) -> Response {
+    if request.method().is_safe() {
+        return next.run(request).await;
+    }
+    let forbidden = || (StatusCode::FORBIDDEN, "Invalid CSRF token").into_response();
+    let expected = match session.get::<String>(CSRF_KEY).await {
+        Ok(Some(expected)) => expected,
+        _ => {
+            tracing::warn!(
+                "rejecting {} without a csrf token in the session",
+                request.uri()
+            );
+            return forbidden();
+        }
+    };
+
+    let header = request
+        .headers()
+        .get("x-csrf-token")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let (request, actual) = match header {
+        Some(token) => (request, Some(token)),
+        None => {
+            let (parts, body) = request.into_parts();
+            let Ok(body) = to_bytes(body, MAX_FORM_SIZE).await else {
+                return (StatusCode::PAYLOAD_TOO_LARGE, "Form too large").into_response();
+            };
+            let token = serde_urlencoded::from_bytes::<Vec<(String, String)>>(&body)
+                .ok()
+                .and_then(|fields| {
+                    fields
+                        .into_iter()
+                        .find(|(name, _)| name == CSRF_KEY)
+                        .map(|(_, value)| value)
+                });
+            (Request::from_parts(parts, Body::from(body)), token)
         }
+    };
 
-        Err((StatusCode::UNAUTHORIZED, "Unauthorized"))
+    if actual.is_some_and(|actual| tokens_match(&expected, &actual)) {
+        next.run(request).await
+    } else {
+        tracing::warn!("rejecting {} with an invalid csrf token", request.uri());
+        forbidden()
     }
 }
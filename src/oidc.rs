@@ -0,0 +1,195 @@
+//! Logging members in with an OpenID Connect provider, for spaces without
+//! an auth proxy. The authorization code flow with PKCE is used and the
+//! nickname is kept in the session.
+
+use crate::Config;
+use crate::auth::Jwks;
+use anyhow::{Context, Result, bail};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use jsonwebtoken::Validation;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use tokio::sync::OnceCell;
+
+/// Session key of the nickname of the logged in member.
+pub(crate) const NICKNAME_KEY: &str = "nickname";
+
+/// Session key of the login in progress.
+pub(crate) const LOGIN_KEY: &str = "oidc_login";
+
+/// The parts of the provider metadata mac4nick uses.
+#[derive(Deserialize)]
+struct Metadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+struct Discovered {
+    metadata: Metadata,
+    jwks: Jwks,
+}
+
+#[derive(Deserialize)]
+struct Tokens {
+    id_token: String,
+}
+
+/// Secrets of a login in progress, the callback has to present the same
+/// state and the ID token the same nonce.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Login {
+    pub state: String,
+    nonce: String,
+    verifier: String,
+}
+
+impl Login {
+    pub(crate) fn new() -> Self {
+        let random = || URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>());
+        Self {
+            state: random(),
+            nonce: random(),
+            verifier: random(),
+        }
+    }
+
+    /// The PKCE challenge of the verifier, which is only sent to the
+    /// provider with the code.
+    fn challenge(&self) -> String {
+        URL_SAFE_NO_PAD.encode(Sha256::digest(self.verifier.as_bytes()))
+    }
+}
+
+/// The provider configured by the `OIDC_*` settings. Its metadata is
+/// discovered on the first login.
+pub struct Provider {
+    issuer: String,
+    client_id: String,
+    client_secret: Option<String>,
+    redirect_url: String,
+    scopes: String,
+    claim: String,
+    discovered: OnceCell<Discovered>,
+    client: reqwest::Client,
+}
+
+impl Provider {
+    pub fn new(config: &Config, client: reqwest::Client) -> Self {
+        Self {
+            issuer: config.oidc_issuer.clone().unwrap_or_default(),
+            client_id: config.oidc_client_id.clone().unwrap_or_default(),
+            client_secret: config.oidc_client_secret.clone(),
+            redirect_url: config.oidc_redirect_url.clone().unwrap_or_default(),
+            scopes: config.oidc_scopes.clone(),
+            claim: config.oidc_nickname_claim.clone(),
+            discovered: OnceCell::new(),
+            client,
+        }
+    }
+
+    async fn discovered(&self) -> Result<&Discovered> {
+        self.discovered
+            .get_or_try_init(|| async {
+                let url = format!(
+                    "{}/.well-known/openid-configuration",
+                    self.issuer.trim_end_matches('/')
+                );
+                let metadata: Metadata = self
+                    .client
+                    .get(&url)
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status())
+                    .with_context(|| format!("unable to fetch {}", url))?
+                    .json()
+                    .await
+                    .with_context(|| format!("invalid provider metadata at {}", url))?;
+                if metadata.issuer != self.issuer {
+                    bail!(
+                        "the provider calls itself {} instead of {}",
+                        metadata.issuer,
+                        self.issuer
+                    );
+                }
+                Ok(Discovered {
+                    jwks: Jwks::new(metadata.jwks_uri.clone()),
+                    metadata,
+                })
+            })
+            .await
+    }
+
+    /// Where to send the member to log in.
+    pub(crate) async fn authorization_url(&self, login: &Login) -> Result<String> {
+        let discovered = self.discovered().await?;
+        let url = reqwest::Url::parse_with_params(
+            &discovered.metadata.authorization_endpoint,
+            [
+                ("response_type", "code"),
+                ("client_id", &self.client_id),
+                ("redirect_uri", &self.redirect_url),
+                ("scope", &self.scopes),
+                ("state", &login.state),
+                ("nonce", &login.nonce),
+                ("code_challenge", &login.challenge()),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+        .context("invalid authorization endpoint")?;
+        Ok(url.into())
+    }
+
+    /// Trades the code of the callback for an ID token and returns the
+    /// nickname it names.
+    pub(crate) async fn nickname(&self, code: &str, login: &Login) -> Result<String> {
+        let discovered = self.discovered().await?;
+        let mut request = self
+            .client
+            .post(&discovered.metadata.token_endpoint)
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", &self.redirect_url),
+                ("client_id", &self.client_id),
+                ("code_verifier", &login.verifier),
+            ]);
+        if let Some(secret) = &self.client_secret {
+            request = request.basic_auth(&self.client_id, Some(secret));
+        }
+        let tokens: Tokens = request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .context("unable to redeem the code")?
+            .json()
+            .await
+            .context("invalid token response")?;
+
+        let header = jsonwebtoken::decode_header(&tokens.id_token).context("malformed ID token")?;
+        let key = discovered.jwks.key(&self.client, &header).await?;
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&discovered.metadata.issuer]);
+        validation.set_audience(&[&self.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud"]);
+        let claims =
+            jsonwebtoken::decode::<HashMap<String, Value>>(&tokens.id_token, &key, &validation)
+                .context("invalid ID token")?
+                .claims;
+
+        if claims.get("nonce").and_then(Value::as_str) != Some(login.nonce.as_str()) {
+            bail!("the ID token was issued for another login");
+        }
+        match claims.get(&self.claim) {
+            Some(Value::String(nickname)) if !nickname.trim().is_empty() => Ok(nickname.clone()),
+            _ => bail!("the ID token has no {} claim", self.claim),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
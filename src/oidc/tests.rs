@@ -0,0 +1,198 @@
+use crate::{router, testing};
+use axum::body::Body;
+use axum::extract::{Query, State};
+use axum::http::{Request, StatusCode, header};
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::routing::{get, post};
+use axum::{Form, Json, Router};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+const REDIRECT_URL: &str = "https://mac4nick.example.org/auth/callback";
+
+/// What the provider remembers about the login in progress.
+#[derive(Default)]
+struct Authorization {
+    challenge: String,
+    nonce: String,
+}
+
+#[derive(Clone)]
+struct Provider {
+    issuer: String,
+    authorization: Arc<Mutex<Authorization>>,
+}
+
+async fn discovery(State(provider): State<Provider>) -> Json<serde_json::Value> {
+    Json(serde_json::json!({
+        "issuer": provider.issuer,
+        "authorization_endpoint": format!("{}/authorize", provider.issuer),
+        "token_endpoint": format!("{}/token", provider.issuer),
+        "jwks_uri": format!("{}/jwks", provider.issuer),
+    }))
+}
+
+/// Logs alice in right away.
+async fn authorize(
+    State(provider): State<Provider>,
+    Query(query): Query<HashMap<String, String>>,
+) -> Response {
+    if query["client_id"] != "mac4nick"
+        || query["redirect_uri"] != REDIRECT_URL
+        || query["code_challenge_method"] != "S256"
+    {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    *provider.authorization.lock().unwrap() = Authorization {
+        challenge: query["code_challenge"].clone(),
+        nonce: query["nonce"].clone(),
+    };
+    Redirect::to(&format!(
+        "{}?code=granted&state={}",
+        REDIRECT_URL, query["state"]
+    ))
+    .into_response()
+}
+
+async fn token(
+    State(provider): State<Provider>,
+    Form(form): Form<HashMap<String, String>>,
+) -> Response {
+    let authorization = std::mem::take(&mut *provider.authorization.lock().unwrap());
+    let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(form["code_verifier"].as_bytes()));
+    if form["code"] != "granted" || challenge != authorization.challenge {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    let header = Header {
+        kid: Some("proxy".to_string()),
+        ..Header::new(Algorithm::ES256)
+    };
+    let claims = serde_json::json!({
+        "iss": provider.issuer,
+        "aud": "mac4nick",
+        "sub": "4711",
+        "exp": chrono::Utc::now().timestamp() + 60,
+        "nonce": authorization.nonce,
+        "preferred_username": "alice.smith",
+        "nickname": "alice",
+    });
+    let key = EncodingKey::from_ec_pem(testing::EC_KEY.as_bytes()).unwrap();
+    Json(serde_json::json!({
+        "access_token": "opaque",
+        "token_type": "Bearer",
+        "id_token": jsonwebtoken::encode(&header, &claims, &key).unwrap(),
+    }))
+    .into_response()
+}
+
+/// Starts a provider on localhost and returns its issuer URL.
+async fn provider() -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let provider = Provider {
+        issuer: format!("http://{}", listener.local_addr().unwrap()),
+        authorization: Default::default(),
+    };
+    let issuer = provider.issuer.clone();
+    let app = Router::new()
+        .route("/.well-known/openid-configuration", get(discovery))
+        .route("/authorize", get(authorize))
+        .route("/token", post(token))
+        .route("/jwks", get(|| async { Json(testing::jwks()) }))
+        .with_state(provider);
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    issuer
+}
+
+/// Starts a login and lets the provider authorize it, returning the
+/// callback to mac4nick.
+async fn log_in(browser: &mut testing::Browser) -> String {
+    let (status, _) = browser.get("/auth/login").await;
+    assert_eq!(status, StatusCode::SEE_OTHER);
+    let client = reqwest::ClientBuilder::new()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+    let response = client
+        .get(browser.location.as_deref().unwrap())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    let callback = response.headers()[header::LOCATION].to_str().unwrap();
+    callback
+        .strip_prefix("https://mac4nick.example.org")
+        .unwrap()
+        .to_string()
+}
+
+async fn browser() -> testing::Browser {
+    let issuer = provider().await;
+    let config = testing::config(&[
+        ("AUTH_MODE", "oidc"),
+        ("OIDC_ISSUER", &issuer),
+        ("OIDC_CLIENT_ID", "mac4nick"),
+        ("OIDC_CLIENT_SECRET", "secret"),
+        ("OIDC_REDIRECT_URL", REDIRECT_URL),
+        ("OIDC_NICKNAME_CLAIM", "nickname"),
+    ]);
+    testing::Browser::new(router(testing::state(config).await))
+}
+
+#[tokio::test]
+async fn members_log_in_with_the_provider() {
+    let mut browser = browser().await;
+    let (status, _) = browser.get("/").await;
+    assert_eq!(status, StatusCode::SEE_OTHER);
+    assert_eq!(browser.location.as_deref(), Some("/auth/login"));
+
+    let callback = log_in(&mut browser).await;
+    let before = browser.session.clone();
+    let (status, _) = browser.get(&callback).await;
+    assert_eq!(status, StatusCode::SEE_OTHER);
+    assert_eq!(browser.location.as_deref(), Some("/"));
+    assert_ne!(browser.session, before, "the session id changes on login");
+
+    let (status, page) = browser.get("/").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(page.contains("alice's Devices"));
+    assert!(page.contains("Log out alice"));
+
+    // the login cannot be completed twice
+    let (status, _) = browser.get(&callback).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let status = browser.post_to("/auth/logout", "").await;
+    assert_eq!(status, StatusCode::SEE_OTHER);
+    let (status, _) = browser.get("/").await;
+    assert_eq!(status, StatusCode::SEE_OTHER);
+    assert_eq!(browser.location.as_deref(), Some("/auth/login"));
+}
+
+#[tokio::test]
+async fn callbacks_must_match_the_login_of_the_session() {
+    let mut browser = browser().await;
+    let callback = log_in(&mut browser).await;
+
+    let (status, _) = browser
+        .get(&callback.replace("state=", "state=forged"))
+        .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // a callback sent to somebody else's browser does not log them in
+    let callback = log_in(&mut browser).await;
+    browser.session = None;
+    let (status, _) = browser.get(&callback).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = browser
+        .send(
+            Request::post("/change")
+                .body(Body::from("action=delete"))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
@@ -1,43 +1,168 @@
 use crate::AxumAppState;
-use crate::db;
-use crate::forms::ChangeForm;
+use crate::UnassignedMode;
+use crate::forms::{self, ChangeForm, InfrastructureForm, StatusForm};
+use crate::health::Check;
 use crate::helpers;
-use crate::middleware::ForwardAuth;
+use crate::middleware::{ClientIp, CsrfToken, ForwardAuth};
+use crate::oidc;
 use crate::templates::IndexTemplate;
 use anyhow::Context;
 use axum::{
-    Form,
-    extract::State,
-    response::{Html, IntoResponse, Redirect, Result},
+    Form, Json,
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode, header},
+    response::{Html, IntoResponse, Redirect, Response, Result},
 };
 use axum_messages::Messages;
+use serde::Deserialize;
+use std::time::Duration;
+use tower_sessions::Session;
 
 pub async fn healthz() -> impl IntoResponse {
     "ok"
 }
 
+/// Checks the database and reports what the scanner knows about MQTT, the
+/// UniFi controller and its last scan, answering 503 unless all are fine.
+pub async fn readyz(State(state): AxumAppState) -> impl IntoResponse {
+    let database = match tokio::time::timeout(Duration::from_secs(5), state.repo.ping()).await {
+        Ok(Ok(())) => Check::ok(),
+        // the error may name the host, user or database, which is nobody's
+        // business on this public endpoint
+        Ok(Err(err)) => {
+            tracing::warn!("database not ready: {:#}", err);
+            Check::failed("database unavailable")
+        }
+        Err(_) => Check::failed("the database did not answer in time"),
+    };
+    let readiness = state
+        .health
+        .readiness(database, state.config.borrow().max_scan_age());
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(readiness))
+}
+
+/// Public status of the space as of the latest scan.
+pub async fn api_status(State(state): AxumAppState) -> Response {
+    match state.report.borrow().clone() {
+        Some(report) => Json(report).into_response(),
+        None => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(serde_json::json!({ "error": "the space has not been scanned yet" })),
+        )
+            .into_response(),
+    }
+}
+
+pub async fn metrics(State(state): AxumAppState) -> Result<impl IntoResponse, StatusCode> {
+    let metrics = state
+        .metrics
+        .encode()
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok((
+        [(
+            header::CONTENT_TYPE,
+            "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        )],
+        metrics,
+    ))
+}
+
 pub async fn index(
     State(state): AxumAppState,
     messages: Messages,
     ForwardAuth(nickname): ForwardAuth,
+    ClientIp(client_ip): ClientIp,
+    CsrfToken(csrf): CsrfToken,
+    headers: HeaderMap,
 ) -> Result<impl IntoResponse, impl IntoResponse> {
-    let my = db::Device::for_user(&state.pool, &nickname)
+    let mut confirmed = forms::confirm_claims(&state, &nickname).await;
+    let my = state
+        .repo
+        .devices_for_user(&nickname)
         .await
         .context("unable to fetch user from db")?;
-    let unassinged = db::AliveDevice::unassinged(&state.pool)
+    let claims = state
+        .repo
+        .claims_for_user(&nickname)
         .await
-        .context("unable to find device")?;
-    Ok::<Html<String>, helpers::AppError>(Html(
-        IndexTemplate::new(
-            nickname.to_string(),
-            my,
-            unassinged,
-            messages
+        .context("unable to fetch claims from db")?;
+    let claim_mode = state.config.borrow().unassigned_mode == UnassignedMode::Claim;
+    let unassigned = if claim_mode {
+        vec![]
+    } else {
+        state
+            .repo
+            .unassigned()
+            .await
+            .context("unable to find device")?
+    };
+    let current = match client_ip {
+        Some(ip) => state
+            .repo
+            .alive_for_ip(ip)
+            .await
+            .context("unable to find current device")?,
+        None => None,
+    };
+    let current_registered = match &current {
+        Some(current) => matches!(
+            state.repo.device_for_mac(&current.macaddr).await,
+            Ok(Some(_))
+        ),
+        None => false,
+    };
+    let is_admin = state.is_admin(&nickname).await;
+    let (forced, infrastructure, departed) = if is_admin {
+        (
+            state
+                .repo
+                .forced_status()
+                .await
+                .context("unable to find forced status")?,
+            state
+                .repo
+                .infrastructure()
+                .await
+                .context("unable to fetch infrastructure")?,
+            state
+                .repo
+                .devices()
+                .await
+                .context("unable to fetch devices")?
                 .into_iter()
-                .map(|msg| (msg.level, msg.message.to_string()))
+                .filter(|device| device.departed.is_some())
                 .collect(),
         )
-        .to_string(),
+    } else {
+        (None, vec![], vec![])
+    };
+    let mut messages: Vec<_> = messages
+        .into_iter()
+        .map(|msg| (msg.level, msg.message.to_string()))
+        .collect();
+    messages.append(&mut confirmed);
+    Ok::<Html<String>, helpers::AppError>(Html(
+        IndexTemplate::new(nickname.to_string(), my, unassigned, messages)
+            .with_claims(claims, claim_mode)
+            .with_current(
+                current,
+                current_registered,
+                headers
+                    .get(header::USER_AGENT)
+                    .and_then(|ua| ua.to_str().ok())
+                    .map(helpers::device_hint)
+                    .unwrap_or_default(),
+            )
+            .with_admin(is_admin, forced, infrastructure)
+            .with_departed(departed)
+            .with_csrf(csrf)
+            .with_logout(state.auth.oidc().is_some())
+            .to_string(),
     ))
 }
 
@@ -45,9 +170,121 @@ pub async fn change(
     State(state): AxumAppState,
     messages: Messages,
     ForwardAuth(nickname): ForwardAuth,
+    ClientIp(client_ip): ClientIp,
     Form(form): Form<ChangeForm>,
+) -> Result<impl IntoResponse, ()> {
+    let message = form.handle(&state, nickname, client_ip).await;
+    messages.push(message.0, message.1, None);
+    Ok(Redirect::to("/"))
+}
+
+pub async fn status(
+    State(state): AxumAppState,
+    messages: Messages,
+    ForwardAuth(nickname): ForwardAuth,
+    Form(form): Form<StatusForm>,
+) -> Result<impl IntoResponse, ()> {
+    let message = form.handle(&state, nickname).await;
+    messages.push(message.0, message.1, None);
+    Ok(Redirect::to("/"))
+}
+
+pub async fn infrastructure(
+    State(state): AxumAppState,
+    messages: Messages,
+    ForwardAuth(nickname): ForwardAuth,
+    Form(form): Form<InfrastructureForm>,
 ) -> Result<impl IntoResponse, ()> {
     let message = form.handle(&state, nickname).await;
     messages.push(message.0, message.1, None);
     Ok(Redirect::to("/"))
 }
+
+/// Sends the member to the OpenID Connect provider to log in.
+pub async fn login(State(state): AxumAppState, session: Session) -> Response {
+    let Some(provider) = state.auth.oidc() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let login = oidc::Login::new();
+    let url = match provider.authorization_url(&login).await {
+        Ok(url) => url,
+        Err(err) => {
+            tracing::error!("unable to start the login: {:#}", err);
+            return (StatusCode::BAD_GATEWAY, "Login provider unavailable").into_response();
+        }
+    };
+    if let Err(err) = session.insert(oidc::LOGIN_KEY, &login).await {
+        tracing::error!("unable to store the login: {}", err);
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Session unavailable").into_response();
+    }
+    Redirect::to(&url).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct CallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+/// Completes the login the provider redirected the member back from.
+pub async fn callback(
+    State(state): AxumAppState,
+    session: Session,
+    Query(query): Query<CallbackQuery>,
+) -> Response {
+    let Some(provider) = state.auth.oidc() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let failed = || (StatusCode::FORBIDDEN, "Login failed").into_response();
+    // the login is removed right away, so a callback cannot be replayed
+    let login = match session.remove::<oidc::Login>(oidc::LOGIN_KEY).await {
+        Ok(Some(login)) if query.state.as_ref() == Some(&login.state) => login,
+        _ => {
+            tracing::warn!("rejecting a login callback that does not match a started login");
+            return failed();
+        }
+    };
+    let code = match (query.code, query.error) {
+        (Some(code), None) => code,
+        (_, error) => {
+            tracing::warn!(
+                "the provider refused the login: {}",
+                error.unwrap_or_default()
+            );
+            return failed();
+        }
+    };
+    let nickname = match provider.nickname(&code, &login).await {
+        Ok(nickname) => nickname,
+        Err(err) => {
+            tracing::warn!("rejecting the login: {:#}", err);
+            return failed();
+        }
+    };
+
+    // a new session id keeps a session planted before the login from
+    // being taken over
+    let stored = async {
+        session.cycle_id().await?;
+        session.insert(oidc::NICKNAME_KEY, &nickname).await
+    };
+    if let Err(err) = stored.await {
+        tracing::error!("unable to store the login: {}", err);
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Session unavailable").into_response();
+    }
+    tracing::info!("{} logged in", nickname);
+    Redirect::to("/").into_response()
+}
+
+pub async fn logout(session: Session, ForwardAuth(nickname): ForwardAuth) -> Response {
+    if let Err(err) = session.flush().await {
+        tracing::error!("unable to end the session: {}", err);
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Session unavailable").into_response();
+    }
+    tracing::info!("{} logged out", nickname);
+    Redirect::to("/").into_response()
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,674 @@
+use crate::db::{AliveDevice, Device, PrivacyLevel};
+use crate::health::ScannerState;
+use crate::status::{Report, SpaceStatus};
+use crate::{AppState, router, testing};
+use axum::Router;
+use axum::body::{Body, to_bytes};
+use axum::http::{HeaderName, Request, StatusCode, header};
+use tower::ServiceExt;
+
+/// Address of the auth proxy of the tests using `X-Forwarded-For`.
+const PROXY: &str = "192.0.2.10:40000";
+
+/// A browser of the member `nickname`, logged in by the auth proxy.
+fn member(state: &AppState, nickname: &str) -> testing::Browser {
+    let mut browser = testing::Browser::new(router(state.clone()));
+    browser
+        .headers
+        .push((header::COOKIE, format!("_forward_auth_name={}", nickname)));
+    browser
+}
+
+#[tokio::test]
+async fn rejects_requests_without_forward_auth() {
+    let state = testing::state(testing::config(&[])).await;
+    let response = router(state)
+        .oneshot(Request::get("/").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn status_api_is_public() {
+    let (sender, report) = tokio::sync::watch::channel(None);
+    let state = AppState {
+        report,
+        ..testing::state(testing::config(&[])).await
+    };
+    let get = |app: Router| async move {
+        let response = app
+            .oneshot(Request::get("/api/status").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (
+            status,
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+        )
+    };
+
+    let (status, _) = get(router(state.clone())).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+    sender.send_replace(Some(Report {
+        status: SpaceStatus::Open,
+        members: 1,
+        names: vec!["alice".to_string()],
+        devices: 2,
+        guests: 3,
+        updated: chrono::Utc::now(),
+    }));
+    let (status, body) = get(router(state)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["status"], "open");
+    assert_eq!(body["names"][0], "alice");
+    assert_eq!(body["guests"], 3);
+}
+
+#[tokio::test]
+async fn metrics_count_requests_and_report_the_space() {
+    let state = testing::state(testing::config(&[])).await;
+    state.metrics.report(&Report {
+        status: SpaceStatus::Open,
+        members: 2,
+        names: vec![],
+        devices: 3,
+        guests: 4,
+        updated: chrono::Utc::now(),
+    });
+    let mut browser = member(&state, "alice");
+    browser.get("/").await;
+    browser.get("/nowhere").await;
+
+    // like Prometheus, without being logged in
+    let response = router(state)
+        .oneshot(Request::get("/metrics").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let metrics = String::from_utf8_lossy(&body);
+    assert!(metrics.contains("mac4nick_members_present 2\n"));
+    assert!(metrics.contains("mac4nick_guests_present 4\n"));
+    assert!(metrics.contains("mac4nick_space_open 1\n"));
+    assert!(
+        metrics.contains(r#"mac4nick_http_requests_total{method="GET",path="/",status="200"} 1"#)
+    );
+    assert!(metrics.contains(r#"path="unmatched",status="404"} 1"#));
+    assert!(metrics.contains("mac4nick_db_errors_total 0\n"));
+}
+
+#[tokio::test]
+async fn readiness_reflects_the_dependencies() {
+    let state = testing::state(testing::config(&[])).await;
+    let get = |app: Router| async move {
+        let response = app
+            .oneshot(Request::get("/readyz").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (
+            status,
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+        )
+    };
+
+    let (status, body) = get(router(state.clone())).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body["ready"], false);
+    assert_eq!(body["database"]["ok"], true);
+    assert_eq!(body["mqtt"]["ok"], false);
+    assert_eq!(body["scan"]["error"], "no scan has succeeded yet");
+
+    state.health.mqtt_connected(true);
+    state.health.controller_reachable(true);
+    state.health.scanned(&Ok(()));
+    let (status, body) = get(router(state.clone())).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["ready"], true);
+    assert!(body["scan"]["last_success"].is_string());
+
+    // a single failed scan is reported but does not make the instance unready
+    state.health.scanned(&Err(anyhow::anyhow!(
+        "unable to log in to https://unifi.example.org"
+    )));
+    let (status, body) = get(router(state)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["scan"]["last_error"], "last scan failed");
+}
+
+#[tokio::test]
+async fn roles_serve_their_routes() {
+    let get = |app: Router, uri: &'static str| async move {
+        let response = app
+            .oneshot(
+                Request::get(uri)
+                    .header(header::COOKIE, "_forward_auth_name=alice")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, String::from_utf8_lossy(&body).to_string())
+    };
+
+    let scanner = testing::state(testing::config(&[("ROLE", "scanner")])).await;
+    assert_eq!(
+        get(router(scanner.clone()), "/").await.0,
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(get(router(scanner), "/metrics").await.0, StatusCode::OK);
+
+    // without the scanner only the database decides the readiness
+    let web = testing::state(testing::config(&[("ROLE", "web")])).await;
+    web.health.scanner(ScannerState::Disabled);
+    assert_eq!(get(router(web.clone()), "/").await.0, StatusCode::OK);
+    let (status, body) = get(router(web), "/readyz").await;
+    assert_eq!(status, StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["scanner"], "disabled");
+    assert!(body.get("mqtt").is_none());
+}
+
+#[tokio::test]
+async fn forms_require_the_csrf_token() {
+    let state = testing::state(testing::config(&[])).await;
+    let mut browser = member(&state, "alice");
+    let token = browser.csrf().await;
+
+    let post = |form: &str, token: Option<&str>| {
+        let mut request = Request::post("/change")
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded");
+        if let Some(token) = token {
+            request = request.header("x-csrf-token", token);
+        }
+        request.body(Body::from(form.to_string())).unwrap()
+    };
+    let register = "action=register&macaddr=00-11-22-AA-BB-CC&descr=laptop&privacy=1";
+
+    // another site can make the browser send the cookies but not the token
+    let (status, _) = browser.clone().send(post(register, None)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let forged = format!("{}&csrf=forged", register);
+    let (status, _) = browser.clone().send(post(&forged, None)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert!(
+        state
+            .repo
+            .devices_for_user("alice")
+            .await
+            .unwrap()
+            .is_empty()
+    );
+
+    // scripts send the token as a header instead
+    let (status, _) = browser.clone().send(post(register, Some(&token))).await;
+    assert_eq!(status, StatusCode::SEE_OTHER);
+    assert_eq!(state.repo.devices_for_user("alice").await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn register_update_delete() {
+    let state = testing::state(testing::config(&[])).await;
+    let mut browser = member(&state, "alice");
+
+    let status = browser
+        .post("action=register&macaddr=00-11-22-AA-BB-CC&descr=laptop&privacy=1")
+        .await;
+    assert_eq!(status, StatusCode::SEE_OTHER);
+    let device = state
+        .repo
+        .device_for_mac("00:11:22:aa:bb:cc")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(device.nickname, "alice");
+    assert_eq!(device.privacy, PrivacyLevel::ShowUser);
+
+    let (status, body) = browser.get("/").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains("assinged device &#34;laptop&#34; to alice"));
+    assert!(body.contains("00:11:22:aa:bb:cc"));
+
+    browser
+        .post("action=update&macaddr=00:11:22:aa:bb:cc&descr=notebook&privacy=3")
+        .await;
+    let device = state
+        .repo
+        .device_for_mac("00:11:22:aa:bb:cc")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(device.descr, "notebook");
+    assert_eq!(device.privacy, PrivacyLevel::HideUser);
+
+    browser
+        .post("action=delete&macaddr=00:11:22:aa:bb:cc&descr=notebook&privacy=3")
+        .await;
+    assert!(
+        state
+            .repo
+            .device_for_mac("00:11:22:aa:bb:cc")
+            .await
+            .unwrap()
+            .is_none()
+    );
+    let (_, body) = browser.get("/").await;
+    assert!(body.contains("device &#34;notebook&#34; has been deleted"));
+}
+
+#[tokio::test]
+async fn register_rejects_invalid_and_duplicate_addresses() {
+    let state = testing::state(testing::config(&[])).await;
+    let mut browser = member(&state, "alice");
+
+    browser
+        .post("action=register&macaddr=not-a-mac&descr=laptop&privacy=2")
+        .await;
+    let (_, body) = browser.get("/").await;
+    assert!(body.contains("is not a valid mac address"));
+
+    state
+        .repo
+        .create_device(&Device::new(
+            "00:11:22:aa:bb:cc".to_string(),
+            "bob".to_string(),
+            "phone".to_string(),
+            PrivacyLevel::ShowUser,
+        ))
+        .await
+        .unwrap();
+    browser
+        .post("action=register&macaddr=00:11:22:aa:bb:cc&descr=laptop&privacy=2")
+        .await;
+    let (_, body) = browser.get("/").await;
+    assert!(body.contains("is already registered"));
+    let device = state
+        .repo
+        .device_for_mac("00:11:22:aa:bb:cc")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(device.nickname, "bob");
+}
+
+#[tokio::test]
+async fn register_warns_about_randomized_addresses() {
+    let state = testing::state(testing::config(&[])).await;
+    let mut browser = member(&state, "alice");
+
+    browser
+        .post("action=register&macaddr=02:11:22:aa:bb:cc&descr=phone&privacy=2")
+        .await;
+    let (_, body) = browser.get("/").await;
+    assert!(body.contains("notification is-warning"));
+    assert!(body.contains("looks randomized"));
+}
+
+#[tokio::test]
+async fn index_lists_unassigned_and_current_device() {
+    let state = testing::state(testing::config(&[
+        ("TRUST_FORWARDED_FOR", "true"),
+        ("TRUSTED_PROXIES", "192.0.2.10/32"),
+    ]))
+    .await;
+    for (mac, ip) in [
+        ("00:11:22:00:00:01", "10.0.0.1"),
+        ("00:11:22:00:00:02", "10.0.0.2"),
+    ] {
+        state
+            .repo
+            .log_all(&[AliveDevice::new(mac, ip).unwrap()])
+            .await
+            .unwrap();
+    }
+    let mut browser = member(&state, "alice");
+    browser.peer = Some(PROXY.parse().unwrap());
+    browser.headers.push((
+        HeaderName::from_static("x-forwarded-for"),
+        "203.0.113.1, 10.0.0.2".to_string(),
+    ));
+
+    let (_, body) = browser.get("/").await;
+    assert!(body.contains("Unregistred Devices"));
+    assert!(body.contains("00:11:22:00:00:01"));
+    assert!(body.contains("This Device"));
+    assert!(body.contains(r#"name="macaddr" value="00:11:22:00:00:02""#));
+}
+
+#[tokio::test]
+async fn claim_mode_requires_proof_of_ownership() {
+    let state = testing::state(testing::config(&[
+        ("UNASSIGNED_MODE", "claim"),
+        ("TRUST_FORWARDED_FOR", "true"),
+        ("TRUSTED_PROXIES", "192.0.2.10/32"),
+    ]))
+    .await;
+    for (mac, ip) in [
+        ("00:11:22:00:00:01", "10.0.0.1"),
+        ("00:11:22:00:00:02", "10.0.0.2"),
+    ] {
+        state
+            .repo
+            .log_all(&[AliveDevice::new(mac, ip).unwrap()])
+            .await
+            .unwrap();
+    }
+    let mut browser = member(&state, "alice");
+    browser.peer = Some(PROXY.parse().unwrap());
+    browser.headers.push((
+        HeaderName::from_static("x-forwarded-for"),
+        "10.0.0.2".to_string(),
+    ));
+
+    let (_, body) = browser.get("/").await;
+    assert!(!body.contains("Unregistred Devices"));
+    assert!(!body.contains("00:11:22:00:00:01"));
+
+    // someone else's device can not be registered directly
+    browser
+        .post("action=register&macaddr=00:11:22:00:00:01&descr=stolen&privacy=2")
+        .await;
+    assert!(
+        state
+            .repo
+            .device_for_mac("00:11:22:00:00:01")
+            .await
+            .unwrap()
+            .is_none()
+    );
+
+    // the device the request comes from can
+    browser
+        .post("action=register&macaddr=00:11:22:00:00:02&descr=laptop&privacy=2")
+        .await;
+    assert!(
+        state
+            .repo
+            .device_for_mac("00:11:22:00:00:02")
+            .await
+            .unwrap()
+            .is_some()
+    );
+
+    // claimed devices are assigned once a scan has seen them
+    browser
+        .post("action=claim&macaddr=00:11:22:00:00:03&descr=phone&privacy=2")
+        .await;
+    let (_, body) = browser.get("/").await;
+    assert!(body.contains("waiting for the next scan"));
+    state
+        .repo
+        .log_all(&[AliveDevice::new("00:11:22:00:00:03", "10.0.0.3").unwrap()])
+        .await
+        .unwrap();
+    let (_, body) = browser.get("/").await;
+    assert!(body.contains("showed up and has been assinged to alice"));
+    let device = state
+        .repo
+        .device_for_mac("00:11:22:00:00:03")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(device.nickname, "alice");
+}
+
+#[tokio::test]
+async fn claims_are_exclusive_and_only_taken_in_claim_mode() {
+    let state = testing::state(testing::config(&[])).await;
+    let mut alice = member(&state, "alice");
+    alice
+        .post("action=claim&macaddr=00:11:22:00:00:03&descr=phone&privacy=2")
+        .await;
+    let (_, body) = alice.get("/").await;
+    assert!(body.contains("claims are not accepted"));
+    assert!(
+        state
+            .repo
+            .claims_for_user("alice")
+            .await
+            .unwrap()
+            .is_empty()
+    );
+
+    let state = testing::state(testing::config(&[("UNASSIGNED_MODE", "claim")])).await;
+    let mut alice = member(&state, "alice");
+    let mut mallory = member(&state, "mallory");
+    alice
+        .post("action=claim&macaddr=00:11:22:00:00:03&descr=phone&privacy=2")
+        .await;
+    mallory
+        .post("action=claim&macaddr=00-11-22-00-00-03&descr=mine&privacy=2")
+        .await;
+    let (_, body) = mallory.get("/").await;
+    assert!(body.contains("has been claimed already"));
+    assert!(
+        state
+            .repo
+            .claims_for_user("mallory")
+            .await
+            .unwrap()
+            .is_empty()
+    );
+
+    state
+        .repo
+        .log_all(&[AliveDevice::new("00:11:22:00:00:03", "10.0.0.3").unwrap()])
+        .await
+        .unwrap();
+    mallory.get("/").await;
+    alice.get("/").await;
+    let device = state
+        .repo
+        .device_for_mac("00:11:22:00:00:03")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(device.nickname, "alice");
+}
+
+#[tokio::test]
+async fn forwarded_for_is_ignored_from_untrusted_peers() {
+    let state = testing::state(testing::config(&[
+        ("UNASSIGNED_MODE", "claim"),
+        ("TRUST_FORWARDED_FOR", "true"),
+    ]))
+    .await;
+    for (mac, ip) in [
+        ("00:11:22:00:00:01", "10.0.0.1"),
+        ("00:11:22:00:00:02", "10.0.0.2"),
+    ] {
+        state
+            .repo
+            .log_all(&[AliveDevice::new(mac, ip).unwrap()])
+            .await
+            .unwrap();
+    }
+    // mallory reaches mac4nick directly and pretends to be bob's device
+    let mut mallory = member(&state, "mallory");
+    mallory.peer = Some("10.0.0.1:40000".parse().unwrap());
+    mallory.headers.push((
+        HeaderName::from_static("x-forwarded-for"),
+        "10.0.0.2".to_string(),
+    ));
+
+    mallory
+        .post("action=register&macaddr=00:11:22:00:00:02&descr=stolen&privacy=2")
+        .await;
+    assert!(
+        state
+            .repo
+            .device_for_mac("00:11:22:00:00:02")
+            .await
+            .unwrap()
+            .is_none()
+    );
+    mallory
+        .post("action=register&macaddr=00:11:22:00:00:01&descr=laptop&privacy=2")
+        .await;
+    assert!(
+        state
+            .repo
+            .device_for_mac("00:11:22:00:00:01")
+            .await
+            .unwrap()
+            .is_some()
+    );
+}
+
+#[tokio::test]
+async fn admins_force_the_space_status() {
+    let state = testing::state(testing::config(&[("ADMINS", "root, Alice")])).await;
+
+    let mut bob = member(&state, "bob");
+    bob.post_to("/status", "action=open&hours=4").await;
+    let (_, body) = bob.get("/").await;
+    assert!(body.contains("only admins may change the space status"));
+    assert!(!body.contains("Space Status"));
+    assert!(state.repo.forced_status().await.unwrap().is_none());
+
+    let mut alice = member(&state, "alice");
+    alice.post_to("/status", "action=closed&hours=0").await;
+    assert!(state.repo.forced_status().await.unwrap().is_none());
+
+    alice.post_to("/status", "action=closed&hours=4").await;
+    let forced = state.repo.forced_status().await.unwrap().unwrap();
+    assert!(!forced.open);
+    assert_eq!(forced.nickname, "alice");
+    let (_, body) = alice.get("/").await;
+    assert!(body.contains("the space is forced closed for 4 hours"));
+    assert!(body.contains("Space Status"));
+
+    alice.post_to("/status", "action=clear").await;
+    assert!(state.repo.forced_status().await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn admins_manage_the_infrastructure() {
+    let state = testing::state(testing::config(&[("ADMINS", "alice")])).await;
+    state
+        .repo
+        .log_all(&[AliveDevice::new("00:11:22:00:00:01", "10.0.0.1").unwrap()])
+        .await
+        .unwrap();
+
+    let mut bob = member(&state, "bob");
+    bob.post_to(
+        "/infrastructure",
+        "action=add&macaddr=00:11:22:00:00:01&name=printer&descr=",
+    )
+    .await;
+    let (_, body) = bob.get("/").await;
+    assert!(body.contains("only admins may manage the infrastructure"));
+    assert!(state.repo.infrastructure().await.unwrap().is_empty());
+
+    let mut alice = member(&state, "alice");
+    alice
+        .post_to(
+            "/infrastructure",
+            "action=add&macaddr=00:11:22:00:00:01&name=the+printer&descr=",
+        )
+        .await;
+    let (_, body) = alice.get("/").await;
+    assert!(body.contains("the name may only contain"));
+
+    alice
+        .post_to(
+            "/infrastructure",
+            "action=add&macaddr=00-11-22-00-00-01&name=printer&descr=laser",
+        )
+        .await;
+    let (_, body) = alice.get("/").await;
+    assert!(body.contains("added printer to the infrastructure"));
+    let (_, body) = bob.get("/").await;
+    assert!(!body.contains("00:11:22:00:00:01"));
+    assert!(!body.contains("Infrastructure:"));
+
+    // members can not register the device anymore
+    bob.post("action=register&macaddr=00:11:22:00:00:01&descr=mine&privacy=2")
+        .await;
+    let (_, body) = bob.get("/").await;
+    assert!(body.contains("belongs to the space infrastructure"));
+
+    alice
+        .post_to("/infrastructure", "action=delete&macaddr=00:11:22:00:00:01")
+        .await;
+    assert!(state.repo.infrastructure().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn only_members_of_the_directory_register_devices() {
+    let (url, _) = testing::directory().await;
+    let state = testing::state(testing::config(&testing::ldap_config(&url))).await;
+
+    let mut bob = member(&state, "bob");
+    bob.post("action=register&macaddr=00:11:22:00:00:01&descr=laptop&privacy=1")
+        .await;
+    assert!(
+        state
+            .repo
+            .device_for_mac("00:11:22:00:00:01")
+            .await
+            .unwrap()
+            .is_some()
+    );
+
+    let mut mallory = member(&state, "mallory");
+    mallory
+        .post("action=register&macaddr=00:11:22:00:00:02&descr=laptop&privacy=1")
+        .await;
+    let (_, body) = mallory.get("/").await;
+    assert!(body.contains("only active members may register devices"));
+    assert!(
+        state
+            .repo
+            .device_for_mac("00:11:22:00:00:02")
+            .await
+            .unwrap()
+            .is_none()
+    );
+
+    let unreachable = format!("ldap://{}", testing::free_addr());
+    let state = testing::state(testing::config(&testing::ldap_config(&unreachable))).await;
+    let mut bob = member(&state, "bob");
+    bob.post("action=register&macaddr=00:11:22:00:00:01&descr=laptop&privacy=1")
+        .await;
+    let (_, body) = bob.get("/").await;
+    assert!(body.contains("unable to check your membership"));
+}
+
+#[tokio::test]
+async fn admins_of_the_directory_see_departed_members() {
+    let (url, _) = testing::directory().await;
+    let state = testing::state(testing::config(&testing::ldap_config(&url))).await;
+    state
+        .repo
+        .create_device(&Device::new(
+            "00:11:22:00:00:01".to_string(),
+            "dave".to_string(),
+            "laptop".to_string(),
+            PrivacyLevel::ShowUser,
+        ))
+        .await
+        .unwrap();
+    state
+        .directory
+        .as_ref()
+        .unwrap()
+        .sync(state.repo.as_ref())
+        .await
+        .unwrap();
+
+    let (_, body) = member(&state, "alice").get("/").await;
+    assert!(!body.contains("Departed Members"));
+    assert!(!body.contains("Space Status"));
+    let (_, body) = member(&state, "carol").get("/").await;
+    assert!(body.contains("Space Status"));
+    assert!(body.contains("Departed Members"));
+    assert!(body.contains("00:11:22:00:00:01"));
+}
@@ -1,20 +1,32 @@
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, anyhow};
 use ipnetwork::IpNetwork;
-use rumqttc::{AsyncClient, MqttOptions, QoS};
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Outgoing, Packet, QoS};
 use serde::Deserialize;
-use sqlx::MySqlPool;
 use std::collections::HashMap;
-use std::str::FromStr;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{Notify, watch};
 
 use crate::db;
+use crate::health::{Health, ScannerState};
+use crate::metrics::Metrics;
+use crate::status::{Report, Rules, Signals, SpaceStatus};
+use crate::supervisor::{self, Backoff};
 
 #[derive(Deserialize, Debug)]
 struct UnifiStaEntry {
     ip: Option<String>,
+    #[serde(default, alias = "ipv6_address")]
+    ipv6_addresses: Vec<String>,
     mac: String,
 }
 
+impl UnifiStaEntry {
+    fn addresses(&self) -> impl Iterator<Item = &String> {
+        self.ip.iter().chain(self.ipv6_addresses.iter())
+    }
+}
+
 #[derive(Deserialize, Debug)]
 struct UnifiStaResponse {
     data: Vec<UnifiStaEntry>,
@@ -35,47 +47,224 @@ impl From<&db::Device> for User {
     }
 }
 
+/// What a scan found, with one retained message per topic to publish.
+struct Outcome {
+    report: Report,
+    messages: Vec<(String, String)>,
+}
+
+/// The retained messages describing the space in `report`, without the
+/// infrastructure.
+fn status_messages(config: &crate::Config, report: &Report) -> Vec<(String, String)> {
+    vec![
+        (
+            config.mqtt_spacestatus_topic.clone(),
+            report.status.to_string(),
+        ),
+        (
+            config.mqtt_member_device_count_topic.clone(),
+            report.devices.to_string(),
+        ),
+        (
+            config.mqtt_member_present_topic.clone(),
+            report.members.to_string(),
+        ),
+        (
+            config.mqtt_member_names_topic.clone(),
+            report.names.join(", "),
+        ),
+        (
+            config.mqtt_guest_count_topic.clone(),
+            report.guests.to_string(),
+        ),
+    ]
+}
+
+/// What a scan takes from the configuration. Replaced as a whole between
+/// two scans when the configuration is reloaded.
+struct Settings {
+    config: crate::Config,
+    allowed_subnets: Vec<IpNetwork>,
+    rules: Rules,
+}
+
+impl Settings {
+    fn new(config: &crate::Config) -> Self {
+        Self {
+            config: config.clone(),
+            allowed_subnets: config
+                .allowed_subnets()
+                .expect("subnets are validated when loading the configuration"),
+            rules: Rules::new(config),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub(crate) struct Scanner {
-    config: crate::Config,
+    settings: Arc<Mutex<Arc<Settings>>>,
+    repo: Arc<dyn db::Repository>,
+    client: AsyncClient,
+    signals: Arc<Mutex<Signals>>,
+    reports: watch::Sender<Option<Report>>,
+    metrics: Arc<Metrics>,
+    health: Arc<Health>,
+    /// Identifies this instance as the holder of the scanner lease.
+    holder: String,
+    /// Notified once the MQTT client has disconnected.
+    closed: Arc<Notify>,
+}
 
+/// Drives the MQTT connection and records the signals received on the
+/// subscribed topics.
+#[derive(Clone)]
+struct Connection {
+    eventloop: Arc<tokio::sync::Mutex<EventLoop>>,
     client: AsyncClient,
-    allowed_subnets: Vec<IpNetwork>,
+    signals: Arc<Mutex<Signals>>,
+    override_topic: Option<String>,
+    door_topic: Option<String>,
+    health: Arc<Health>,
+    closed: Arc<Notify>,
+}
+
+impl Connection {
+    /// Polls the eventloop until the client disconnects. Every failed
+    /// attempt to reach the broker waits longer before the next one.
+    async fn run(self) {
+        let mut eventloop = self.eventloop.lock().await;
+        let mut backoff = Backoff::new(Duration::from_millis(500), Duration::from_secs(30));
+        loop {
+            match eventloop.poll().await {
+                // subscriptions do not survive a clean session
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    backoff.reset();
+                    self.health.mqtt_connected(true);
+                    for topic in self.override_topic.iter().chain(self.door_topic.iter()) {
+                        if let Err(err) = self.client.try_subscribe(topic, QoS::AtLeastOnce) {
+                            tracing::error!("unable to subscribe to {}: {}", topic, err);
+                        }
+                    }
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    let status = String::from_utf8_lossy(&publish.payload)
+                        .parse::<SpaceStatus>()
+                        .ok();
+                    let mut signals = self.signals.lock().unwrap();
+                    if self.override_topic.as_ref() == Some(&publish.topic) {
+                        signals.overridden = status;
+                    } else if self.door_topic.as_ref() == Some(&publish.topic) {
+                        signals.door = status;
+                    }
+                }
+                Ok(Event::Outgoing(Outgoing::Disconnect)) => {
+                    self.health.mqtt_connected(false);
+                    self.closed.notify_one();
+                    return;
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    self.health.mqtt_connected(false);
+                    let delay = backoff.next();
+                    tracing::error!("mqtt issue, retrying in {:?}: {}", delay, err);
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+    }
 }
 
 impl Scanner {
-    pub(crate) fn new(config: &crate::Config) -> Self {
-        let mut options = MqttOptions::new("mac4nick", config.mqtt_host.clone(), 1883);
+    pub(crate) fn new(
+        config: &crate::Config,
+        repo: Arc<dyn db::Repository>,
+        metrics: Arc<Metrics>,
+        health: Arc<Health>,
+    ) -> Self {
+        let (scanner, connection) = Self::build(config, repo, metrics, health.clone());
+        tokio::task::spawn(supervisor::supervise("mqtt", health, move || {
+            connection.clone().run()
+        }));
+        scanner
+    }
+
+    /// Builds a scanner that never connects to the broker, for dry runs
+    /// that publish nothing.
+    pub(crate) fn offline(
+        config: &crate::Config,
+        repo: Arc<dyn db::Repository>,
+        metrics: Arc<Metrics>,
+        health: Arc<Health>,
+    ) -> Self {
+        Self::build(config, repo, metrics, health).0
+    }
+
+    /// Builds the scanner and the MQTT connection that has to be driven for
+    /// it to publish.
+    fn build(
+        config: &crate::Config,
+        repo: Arc<dyn db::Repository>,
+        metrics: Arc<Metrics>,
+        health: Arc<Health>,
+    ) -> (Self, Connection) {
+        // replicas share a hostname or run as pid 1 in their containers, the
+        // random suffix keeps them from holding the same lease
+        let holder = format!(
+            "{}:{}:{:08x}",
+            std::env::var("HOSTNAME").unwrap_or_else(|_| "mac4nick".to_string()),
+            std::process::id(),
+            rand::random::<u32>()
+        );
+        // the broker drops the older session of a client id, every instance
+        // and `scan --once` next to the server needs its own
+        let mut options = MqttOptions::new(
+            format!("mac4nick-{}", holder),
+            config.mqtt_host.clone(),
+            config.mqtt_port,
+        );
         options.set_keep_alive(Duration::from_secs(5));
         options.set_clean_session(true);
-        let (client, mut eventloop) = AsyncClient::new(options, 10);
+        let (client, eventloop) = AsyncClient::new(options, 10);
 
-        tokio::task::spawn(async move {
-            loop {
-                if let Err(err) = eventloop.poll().await {
-                    tracing::error!("mqtt issue: {}", err)
-                }
-            }
-        });
-
-        let allowed_subnets: Vec<IpNetwork> = config
-            .allowed_subnets
-            .split(',')
-            .filter_map(|s| {
-                let trimmed = s.trim();
-                let parsed = IpNetwork::from_str(trimmed);
-                if let Err(e) = &parsed {
-                    tracing::error!("Error parsing subnet '{}': {}", trimmed, e);
-                }
-                parsed.ok()
-            })
-            .collect();
+        let signals = Arc::new(Mutex::new(Signals::default()));
+        let closed = Arc::new(Notify::new());
+        let connection = Connection {
+            eventloop: Arc::new(tokio::sync::Mutex::new(eventloop)),
+            client: client.clone(),
+            signals: signals.clone(),
+            override_topic: config.mqtt_status_override_topic.clone(),
+            door_topic: config.mqtt_door_topic.clone(),
+            health: health.clone(),
+            closed: closed.clone(),
+        };
 
-        Self {
+        let scanner = Self {
+            settings: Arc::new(Mutex::new(Arc::new(Settings::new(config)))),
+            repo,
             client,
-            config: config.clone(),
-            allowed_subnets,
-        }
+            signals,
+            reports: watch::Sender::new(None),
+            metrics,
+            health,
+            holder,
+            closed,
+        };
+        (scanner, connection)
+    }
+
+    fn settings(&self) -> Arc<Settings> {
+        self.settings.lock().unwrap().clone()
+    }
+
+    /// Applies a reloaded configuration to the following scans. The MQTT
+    /// connection and the subscribed topics stay as they are.
+    pub(crate) fn reconfigure(&self, config: &crate::Config) {
+        *self.settings.lock().unwrap() = Arc::new(Settings::new(config));
+    }
+
+    /// Follows the report of the latest successful scan.
+    pub(crate) fn subscribe(&self) -> watch::Receiver<Option<Report>> {
+        self.reports.subscribe()
     }
 
     async fn publish(&self, topic: &str, data: impl std::fmt::Display) {
@@ -84,112 +273,329 @@ impl Scanner {
             .publish(topic, QoS::AtLeastOnce, true, format!("{}", data))
             .await
         {
+            self.metrics.mqtt_failures.inc();
             tracing::error!("unable to push to mqtt: {}", err);
         }
     }
 
-    pub(crate) async fn scan(&self) -> Result<()> {
-        let pool = MySqlPool::connect(&self.config.dsn)
-            .await
-            .context("unable to open database connection")?;
-
-        let hostname = self.config.unifi_hostname.clone();
+    /// Logs into the UniFi controller and returns the connected stations.
+    async fn stations(&self, config: &crate::Config) -> Result<Vec<UnifiStaEntry>> {
+        let hostname = &config.unifi_hostname;
 
         let http_client = reqwest::ClientBuilder::new()
             .cookie_store(true)
             .danger_accept_invalid_certs(true)
             .build()?;
         http_client
-            .post(format!("https://{}/api/login", hostname))
+            .post(format!("{}://{}/api/login", config.unifi_scheme, hostname))
             .json(&serde_json::json!({
-                "username": self.config.unifi_username,
-                "password": self.config.unifi_password
+                "username": config.unifi_username,
+                "password": config.unifi_password
             }))
             .send()
-            .await?;
+            .await?
+            .error_for_status()?;
 
         let resp = http_client
-            .get(format!("https://{}/api/s/default/stat/sta", hostname))
+            .get(format!(
+                "{}://{}/api/s/default/stat/sta",
+                config.unifi_scheme, hostname
+            ))
             .send()
-            .await?;
+            .await?
+            .error_for_status()?;
+
+        Ok(resp.json::<UnifiStaResponse>().await?.data)
+    }
+
+    pub(crate) async fn scan(&self) -> Result<()> {
+        let started = Instant::now();
+        let result = self.survey(&self.settings(), true).await;
+        self.metrics.observe_scan(started.elapsed());
+        let result = match result {
+            Ok(outcome) => {
+                for (topic, payload) in &outcome.messages {
+                    self.publish(topic, payload).await;
+                }
+                self.metrics.report(&outcome.report);
+                self.reports.send_replace(Some(outcome.report));
+                Ok(())
+            }
+            Err(err) => Err(err),
+        };
+        self.health.scanned(&result);
+        result
+    }
+
+    /// Takes or renews the scanner lease and returns whether this instance
+    /// is the one that scans.
+    pub(crate) async fn lead(&self) -> bool {
+        let active = self
+            .repo
+            .acquire_lease(&self.holder, self.settings().config.lease_ttl())
+            .await
+            .unwrap_or_else(|err| {
+                tracing::error!("unable to acquire the scanner lease: {:#}", err);
+                false
+            });
+        let state = if active {
+            ScannerState::Active
+        } else {
+            ScannerState::Standby
+        };
+        if self.health.scanner(state) != state {
+            match state {
+                ScannerState::Active => tracing::info!("{} is the active scanner", self.holder),
+                _ => tracing::info!("another instance is scanning, {} stands by", self.holder),
+            }
+        }
+        active
+    }
+
+    /// Scans every `SCAN_INTERVAL` while holding the scanner lease until
+    /// `stopping` turns true. A running scan is finished first, a reloaded
+    /// configuration applies from the next scan on.
+    pub(crate) async fn run(
+        self,
+        mut stopping: watch::Receiver<bool>,
+        mut updates: watch::Receiver<crate::Config>,
+    ) {
+        let period = |settings: &Settings| Duration::from_secs(settings.config.scan_interval);
+        let mut interval = tokio::time::interval(period(&self.settings()));
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = stopping.wait_for(|stopping| *stopping) => return,
+            }
+            if updates.has_changed().unwrap_or(false) {
+                let previous = period(&self.settings());
+                self.reconfigure(&updates.borrow_and_update());
+                let current = period(&self.settings());
+                if current != previous {
+                    interval =
+                        tokio::time::interval_at(tokio::time::Instant::now() + current, current);
+                }
+            }
+            if !self.lead().await {
+                continue;
+            }
+            self.publish(&self.settings().config.mqtt_scanner_topic, "online")
+                .await;
+            if let Err(err) = self.scan().await {
+                tracing::error!("unable to scan for devices: {:#}", err);
+            };
+        }
+    }
+
+    /// Runs the scanner, starting it again if it panics.
+    pub(crate) async fn supervise(
+        self,
+        stopping: watch::Receiver<bool>,
+        updates: watch::Receiver<crate::Config>,
+    ) {
+        let health = self.health.clone();
+        supervisor::supervise("scanner", health, move || {
+            self.clone().run(stopping.clone(), updates.clone())
+        })
+        .await
+    }
+
+    /// Hands the lease over to a standby instance, announces that the
+    /// scanner went offline and disconnects from the broker.
+    pub(crate) async fn stop(&self) {
+        if self.health.scanner(ScannerState::Disabled) == ScannerState::Active {
+            if let Err(err) = self.repo.release_lease(&self.holder).await {
+                tracing::error!("unable to release the scanner lease: {:#}", err);
+            }
+            // nobody is counted without a scanner, a standby instance
+            // publishes the actual state with its first scan
+            let config = &self.settings().config;
+            let closed = Report {
+                status: SpaceStatus::Closed,
+                members: 0,
+                names: vec![],
+                devices: 0,
+                guests: 0,
+                updated: chrono::Utc::now(),
+            };
+            for (topic, payload) in status_messages(config, &closed) {
+                self.publish(&topic, payload).await;
+            }
+            self.publish(&config.mqtt_scanner_topic, "offline").await;
+        }
+        if let Err(err) = self.disconnect().await {
+            tracing::warn!("{:#}", err);
+        }
+    }
+
+    /// Disconnects from the broker once the messages published so far have
+    /// been sent, publishing only queues them for the connection.
+    async fn disconnect(&self) -> Result<()> {
+        self.client
+            .disconnect()
+            .await
+            .context("unable to disconnect from mqtt")?;
+        tokio::time::timeout(Duration::from_secs(5), self.closed.notified())
+            .await
+            .map_err(|_| anyhow!("mqtt did not disconnect in time, messages may be lost"))
+    }
+
+    /// Scans a single time and disconnects, so everything is published
+    /// before the process exits.
+    pub(crate) async fn scan_once(&self) -> Result<()> {
+        let scanned = self.scan().await;
+        let disconnected = self.disconnect().await;
+        scanned.and(disconnected)
+    }
+
+    /// Scans without recording the sightings or publishing anything and
+    /// returns the messages a scan would publish. The devices seen right now
+    /// count as if they had been recorded.
+    pub(crate) async fn dry_run(&self) -> Result<Vec<(String, String)>> {
+        Ok(self.survey(&self.settings(), false).await?.messages)
+    }
+
+    async fn survey(&self, settings: &Settings, record: bool) -> Result<Outcome> {
+        let repo = &self.repo;
+
+        let stations = self.stations(&settings.config).await;
+        self.health.controller_reachable(stations.is_ok());
+        let stations = stations.inspect_err(|_| {
+            self.metrics.unifi_failures.inc();
+        })?;
 
         let mut member_known: HashMap<String, User> = HashMap::default();
         let mut device_count = 0_u64;
 
-        let unifi_sta = resp.json::<UnifiStaResponse>().await?;
-
-        for discovered in unifi_sta
-            .data
-            .iter()
-            .filter(|device| device.ip.is_some())
-            .filter(|device| {
-                self.allowed_subnets.iter().any(|subnet| {
-                    if let Some(ip_str) = &device.ip {
-                        if let Ok(ip) = ip_str.parse::<std::net::IpAddr>() {
-                            return subnet.contains(ip);
+        let mut alive: Vec<db::AliveDevice> = vec![];
+        for discovered in &stations {
+            for ip in discovered.addresses() {
+                match db::AliveDevice::new(&discovered.mac, ip) {
+                    Ok(device) => {
+                        if settings
+                            .allowed_subnets
+                            .iter()
+                            .any(|subnet| subnet.contains(device.ipaddr))
+                        {
+                            alive.push(device);
                         }
                     }
-                    false
-                })
-            })
-        {
-            let device = match db::Device::for_mac(&pool, &discovered.mac).await {
-                Ok(device) => device,
-                Err(_) => {
-                    if let Ok(alive) = db::AliveDevice::new(
-                        &discovered.mac,
-                        discovered.ip.as_ref().expect("ip is already checked"),
-                    ) {
-                        if let Err(err) = alive.log(&pool).await {
-                            tracing::info!("unable to log device {:?} {:?}", discovered.mac, err)
-                        }
+                    Err(err) => {
+                        tracing::info!("unable to parse device {:?} {:?}", discovered.mac, err);
                     }
-                    continue;
                 }
-            };
+            }
+        }
 
-            if device.privacy == db::PrivacyLevel::HideUser {
-                continue;
+        let mut macaddrs: Vec<String> = alive.iter().map(|a| a.macaddr.clone()).collect();
+        macaddrs.dedup();
+        let registered: HashMap<String, db::Device> = repo
+            .devices_for_macs(&macaddrs)
+            .await?
+            .into_iter()
+            .map(|device| (device.macaddr.clone(), device))
+            .collect();
+
+        let mut loggable = Vec::with_capacity(alive.len());
+        for alive in alive {
+            match registered.get(&alive.macaddr) {
+                None => loggable.push(alive),
+                Some(device) if device.privacy == db::PrivacyLevel::HideUser => {}
+                Some(device) if device.loggable() => {
+                    tracing::debug!("logging a device ({}): {}", alive.macaddr, device.nickname);
+                    loggable.push(alive);
+                }
+                Some(_) => tracing::debug!("device {:?} should not be logged", alive.macaddr),
             }
+        }
+
+        if record && let Err(err) = repo.log_all(&loggable).await {
+            tracing::error!("unable to log {} devices: {:?}", loggable.len(), err);
+        }
 
+        let mut present = repo.present_devices().await?;
+        let mut guests = repo.guests().await?;
+        let mut infrastructure = repo.infrastructure().await?;
+        if !record {
+            for alive in &loggable {
+                match registered.get(&alive.macaddr) {
+                    Some(device) if !present.iter().any(|p| p.macaddr == device.macaddr) => {
+                        present.push(device.clone())
+                    }
+                    Some(_) => {}
+                    None => guests.push(alive.clone()),
+                }
+            }
+            guests.retain(|guest| !infrastructure.iter().any(|i| i.macaddr == guest.macaddr));
+            for infrastructure in &mut infrastructure {
+                infrastructure.online |=
+                    loggable.iter().any(|a| a.macaddr == infrastructure.macaddr);
+            }
+        }
+
+        // presence is decided by the same window the web interface uses
+        for device in present
+            .iter()
+            .filter(|device| device.privacy < db::PrivacyLevel::HideUser)
+            .filter(|device| !settings.rules.ignores(device))
+        {
             if let Some(known) = member_known.get(&device.nickname) {
                 if device.privacy < known.privacy {
-                    member_known.insert(device.nickname.clone(), (&device).into());
+                    member_known.insert(device.nickname.clone(), device.into());
                 }
             } else {
-                member_known.insert(device.nickname.clone(), (&device).into());
+                member_known.insert(device.nickname.clone(), device.into());
             }
-
             device_count += 1;
-            if let Err(err) = device.log(&pool, discovered.ip.as_ref().unwrap()).await {
-                tracing::debug!("unable to log device {:?}: {:?}", discovered.mac, err);
-            } else {
-                tracing::debug!("logged a device ({}): {}", discovered.mac, device.nickname)
-            };
         }
 
-        let spacestatus = if device_count > 0 { "open" } else { "closed" };
         let member_count = member_known.len();
-        let member_names = member_known
+        let forced = repo.forced_status().await?;
+        let signals = *self.signals.lock().unwrap();
+        let spacestatus = settings
+            .rules
+            .decide(member_count, forced.as_ref(), signals);
+        let mut member_names = member_known
             .values()
             .map(|u| u.username.clone())
-            .collect::<Vec<String>>()
-            .join(", ");
-
-        self.publish(&self.config.mqtt_spacestatus_topic, spacestatus)
-            .await;
-        self.publish(&self.config.mqtt_member_device_count_topic, device_count)
-            .await;
-        self.publish(&self.config.mqtt_member_present_topic, member_count)
-            .await;
-        self.publish(&self.config.mqtt_member_names_topic, &member_names)
-            .await;
+            .collect::<Vec<String>>();
+        member_names.sort();
+        let guest_count = settings.rules.guests(&guests);
+
+        let config = &settings.config;
+        let report = Report {
+            status: spacestatus,
+            members: member_count,
+            names: member_names,
+            devices: device_count,
+            guests: guest_count,
+            updated: chrono::Utc::now(),
+        };
+        let mut messages = status_messages(config, &report);
+        for infrastructure in infrastructure {
+            let state = if infrastructure.online {
+                "online"
+            } else {
+                "offline"
+            };
+            messages.push((
+                format!(
+                    "{}/{}",
+                    config.mqtt_infrastructure_topic, infrastructure.name
+                ),
+                state.to_string(),
+            ));
+        }
 
         tracing::info!(
-            "discovered {} devices, {} members",
+            "discovered {} devices, {} members, {} guests",
             device_count,
-            member_count
+            member_count,
+            guest_count
         );
-        Ok(())
+        Ok(Outcome { report, messages })
     }
 }
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,724 @@
+use super::Scanner;
+use crate::db::{self, AliveDevice, Device, Infrastructure, PrivacyLevel, StatusOverride};
+use crate::health::{Check, Health, ScannerState};
+use crate::status::SpaceStatus;
+use crate::testing;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Serves the two UniFi controller endpoints the scanner uses and records
+/// the credentials it logs in with.
+async fn unifi(stations: Value) -> (SocketAddr, Arc<Mutex<Option<Value>>>) {
+    let login = Arc::new(Mutex::new(None));
+    let recorded = login.clone();
+    let app = Router::new()
+        .route(
+            "/api/login",
+            post(move |Json(body): Json<Value>| async move {
+                *recorded.lock().unwrap() = Some(body);
+                Json(json!({ "meta": { "rc": "ok" }, "data": [] }))
+            }),
+        )
+        .route(
+            "/api/s/default/stat/sta",
+            get(move || async move { Json(stations) }),
+        );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    (addr, login)
+}
+
+/// Starts an MQTT broker in the background.
+fn broker() -> SocketAddr {
+    let addr = testing::free_addr();
+    let server = rumqttd::ServerSettings {
+        name: "v4".to_string(),
+        listen: addr,
+        tls: None,
+        next_connection_delay_ms: 1,
+        connections: rumqttd::ConnectionSettings {
+            connection_timeout_ms: 5000,
+            max_payload_size: 20480,
+            max_inflight_count: 100,
+            auth: None,
+            external_auth: None,
+            dynamic_filters: true,
+        },
+    };
+    let config = rumqttd::Config {
+        router: rumqttd::RouterConfig {
+            max_connections: 10,
+            max_outgoing_packet_count: 200,
+            max_segment_size: 1024 * 1024,
+            max_segment_count: 10,
+            ..Default::default()
+        },
+        v4: Some(HashMap::from([("v4".to_string(), server)])),
+        ..Default::default()
+    };
+    std::thread::spawn(move || rumqttd::Broker::new(config).start().unwrap());
+    addr
+}
+
+/// Subscribes to every topic and collects the last payload per topic until
+/// `count` topics have been published.
+async fn published(broker: SocketAddr, count: usize) -> HashMap<String, String> {
+    let mut options = MqttOptions::new("observer", broker.ip().to_string(), broker.port());
+    options.set_keep_alive(Duration::from_secs(5));
+    let (client, mut eventloop) = AsyncClient::new(options, 10);
+    client
+        .subscribe("sensor/#", QoS::AtLeastOnce)
+        .await
+        .unwrap();
+    let mut messages = HashMap::new();
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while messages.len() < count {
+            if let Ok(Event::Incoming(Packet::Publish(publish))) = eventloop.poll().await {
+                // an empty payload clears a retained topic, late subscribers
+                // never see it
+                if publish.payload.is_empty() {
+                    continue;
+                }
+                messages.insert(
+                    publish.topic.clone(),
+                    String::from_utf8_lossy(&publish.payload).to_string(),
+                );
+            }
+        }
+    })
+    .await
+    .expect("all topics published");
+    messages
+}
+
+fn device(mac: &str, nickname: &str, privacy: PrivacyLevel) -> Device {
+    Device::new(
+        mac.to_string(),
+        nickname.to_string(),
+        "device".to_string(),
+        privacy,
+    )
+}
+
+#[tokio::test]
+async fn scan_logs_devices_and_publishes_status() {
+    let (unifi, login) = unifi(json!({
+        "data": [
+            { "mac": "00:11:22:00:00:01", "ip": "10.0.0.1", "ipv6_addresses": ["2001:db8::1", "fe80::1"] },
+            { "mac": "00:11:22:00:00:02", "ip": "10.0.0.2" },
+            { "mac": "00:11:22:00:00:03", "ip": "10.0.0.3" },
+            { "mac": "00:11:22:00:00:04", "ip": "10.0.0.4" },
+            { "mac": "00:11:22:00:00:05", "ip": "192.168.1.5" },
+            { "mac": "00:11:22:00:00:06" },
+        ]
+    }))
+    .await;
+    let broker = broker();
+    let config = testing::config(&[
+        ("UNIFI_SCHEME", "http"),
+        ("UNIFI_HOSTNAME", &unifi.to_string()),
+        ("MQTT_PORT", &broker.port().to_string()),
+        ("ALLOWED_SUBNETS", "10.0.0.0/8, 2001:db8::/32"),
+    ]);
+    let state = testing::state(config.clone()).await;
+    let repo = state.repo.clone();
+    for device in [
+        device("00:11:22:00:00:01", "alice", PrivacyLevel::ShowUser),
+        device("00:11:22:00:00:02", "alice", PrivacyLevel::ShowAnonymous),
+        device("00:11:22:00:00:03", "bob", PrivacyLevel::HideUser),
+    ] {
+        repo.create_device(&device).await.unwrap();
+    }
+
+    let scanner = Scanner::new(
+        &config,
+        repo.clone(),
+        Default::default(),
+        state.health.clone(),
+    );
+    scanner.scan().await.unwrap();
+
+    let login = login.lock().unwrap().clone().unwrap();
+    assert_eq!(login["username"], "mac4nick");
+    assert_eq!(login["password"], "secret");
+
+    let messages = published(broker, 5).await;
+    assert_eq!(messages["sensor/space/status"], "open");
+    assert_eq!(messages["sensor/space/member/deviceCount"], "2");
+    assert_eq!(messages["sensor/space/member/present"], "1");
+    assert_eq!(messages["sensor/space/member/names"], "alice");
+    assert_eq!(messages["sensor/space/guest/count"], "1");
+
+    let report = scanner.subscribe().borrow().clone().unwrap();
+    assert_eq!(report.status, SpaceStatus::Open);
+    assert_eq!(report.names, vec!["alice"]);
+    assert_eq!(report.guests, 1);
+
+    // the observer above has seen the messages, so the scanner is connected
+    let readiness = state.health.readiness(Check::ok(), Duration::from_secs(60));
+    assert!(readiness.ready);
+    assert!(readiness.scan.unwrap().last_success.is_some());
+
+    let alice = repo.devices_for_user("alice").await.unwrap();
+    assert!(alice.iter().all(|d| d.present));
+    let laptop = alice
+        .iter()
+        .find(|d| d.macaddr == "00:11:22:00:00:01")
+        .unwrap();
+    assert_eq!(
+        laptop.addresses,
+        vec![
+            "10.0.0.1".parse::<std::net::IpAddr>().unwrap(),
+            "2001:db8::1".parse().unwrap()
+        ]
+    );
+    let unassigned = repo.unassigned().await.unwrap();
+    assert_eq!(unassigned.len(), 1);
+    assert_eq!(unassigned[0].macaddr, "00:11:22:00:00:04");
+}
+
+#[tokio::test]
+async fn scan_reports_closed_space() {
+    let (unifi, _) = unifi(json!({
+        "data": [{ "mac": "00:11:22:00:00:04", "ip": "10.0.0.4" }]
+    }))
+    .await;
+    let broker = broker();
+    let config = testing::config(&[
+        ("UNIFI_SCHEME", "http"),
+        ("UNIFI_HOSTNAME", &unifi.to_string()),
+        ("MQTT_PORT", &broker.port().to_string()),
+    ]);
+    let state = testing::state(config.clone()).await;
+
+    Scanner::new(
+        &config,
+        state.repo.clone(),
+        Default::default(),
+        Default::default(),
+    )
+    .scan()
+    .await
+    .unwrap();
+
+    // the empty list of names clears the retained message of that topic
+    let messages = published(broker, 4).await;
+    assert_eq!(messages["sensor/space/status"], "closed");
+    assert_eq!(messages["sensor/space/member/deviceCount"], "0");
+    assert_eq!(messages["sensor/space/member/present"], "0");
+    assert_eq!(messages["sensor/space/guest/count"], "1");
+}
+
+#[tokio::test]
+async fn scan_fails_without_controller() {
+    let config = testing::config(&[
+        ("UNIFI_SCHEME", "http"),
+        ("UNIFI_HOSTNAME", &testing::free_addr().to_string()),
+        ("MQTT_PORT", &testing::free_addr().port().to_string()),
+    ]);
+    let state = testing::state(config.clone()).await;
+    assert!(
+        Scanner::new(
+            &config,
+            state.repo.clone(),
+            state.metrics.clone(),
+            state.health.clone(),
+        )
+        .scan()
+        .await
+        .is_err()
+    );
+
+    let metrics = state.metrics.encode().unwrap();
+    assert!(metrics.contains("mac4nick_unifi_failures_total 1\n"));
+    assert!(metrics.contains("mac4nick_scan_duration_seconds_count 1\n"));
+
+    let readiness = state.health.readiness(Check::ok(), Duration::from_secs(60));
+    assert!(!readiness.ready);
+    assert!(!readiness.controller.unwrap().ok);
+    let scan = readiness.scan.unwrap();
+    assert!(!scan.check.ok);
+    assert!(scan.last_error.is_some());
+}
+
+#[tokio::test]
+async fn scan_keeps_members_present_within_the_window() {
+    let (unifi, _) = unifi(json!({ "data": [] })).await;
+    let broker = broker();
+    let config = testing::config(&[
+        ("UNIFI_SCHEME", "http"),
+        ("UNIFI_HOSTNAME", &unifi.to_string()),
+        ("MQTT_PORT", &broker.port().to_string()),
+        ("PRESENT_WINDOW", "600"),
+    ]);
+    let state = testing::state(config.clone()).await;
+    let repo = state.repo.clone();
+    repo.create_device(&device(
+        "00:11:22:00:00:01",
+        "alice",
+        PrivacyLevel::ShowUser,
+    ))
+    .await
+    .unwrap();
+    repo.log_all(&[AliveDevice::new("00:11:22:00:00:01", "10.0.0.1").unwrap()])
+        .await
+        .unwrap();
+
+    // the device left since the last scan, but the web interface still
+    // shows alice as present
+    Scanner::new(
+        &config,
+        repo.clone(),
+        Default::default(),
+        Default::default(),
+    )
+    .scan()
+    .await
+    .unwrap();
+
+    let messages = published(broker, 5).await;
+    assert_eq!(messages["sensor/space/status"], "open");
+    assert_eq!(messages["sensor/space/member/names"], "alice");
+    assert!(repo.devices_for_user("alice").await.unwrap()[0].present);
+}
+
+/// Publishes a retained message like a door switch would.
+async fn send(broker: SocketAddr, topic: &str, payload: &str) {
+    let mut options = MqttOptions::new("door", broker.ip().to_string(), broker.port());
+    options.set_keep_alive(Duration::from_secs(5));
+    let (client, mut eventloop) = AsyncClient::new(options, 10);
+    client
+        .publish(topic, QoS::AtLeastOnce, true, payload)
+        .await
+        .unwrap();
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while !matches!(
+            eventloop.poll().await,
+            Ok(Event::Incoming(Packet::PubAck(_)))
+        ) {}
+    })
+    .await
+    .expect("message published");
+}
+
+#[tokio::test]
+async fn scan_applies_status_rules() {
+    let (unifi, _) = unifi(json!({
+        "data": [
+            { "mac": "00:11:22:00:00:01", "ip": "10.0.0.1" },
+            { "mac": "00:11:22:00:00:02", "ip": "10.0.0.2" },
+            { "mac": "00:11:22:00:00:03", "ip": "10.0.0.3" },
+        ]
+    }))
+    .await;
+    let broker = broker();
+    let config = testing::config(&[
+        ("UNIFI_SCHEME", "http"),
+        ("UNIFI_HOSTNAME", &unifi.to_string()),
+        ("MQTT_PORT", &broker.port().to_string()),
+        ("STATUS_MIN_MEMBERS", "2"),
+        ("STATUS_IGNORE", "space, 00-11-22-00-00-03"),
+    ]);
+    let state = testing::state(config.clone()).await;
+    let repo = state.repo.clone();
+    for device in [
+        device("00:11:22:00:00:01", "alice", PrivacyLevel::ShowUser),
+        device("00:11:22:00:00:02", "Space", PrivacyLevel::ShowUser),
+        device("00:11:22:00:00:03", "bob", PrivacyLevel::ShowUser),
+    ] {
+        repo.create_device(&device).await.unwrap();
+    }
+
+    Scanner::new(
+        &config,
+        repo.clone(),
+        Default::default(),
+        Default::default(),
+    )
+    .scan()
+    .await
+    .unwrap();
+
+    // the space laptop and bob's always-on device do not count, so alice
+    // alone is not enough to open the space
+    let messages = published(broker, 5).await;
+    assert_eq!(messages["sensor/space/status"], "closed");
+    assert_eq!(messages["sensor/space/member/present"], "1");
+    assert_eq!(messages["sensor/space/member/deviceCount"], "1");
+    assert_eq!(messages["sensor/space/member/names"], "alice");
+}
+
+#[tokio::test]
+async fn scan_follows_forced_status_and_override_topic() {
+    let (unifi, _) = unifi(json!({ "data": [] })).await;
+    let broker = broker();
+    let config = testing::config(&[
+        ("UNIFI_SCHEME", "http"),
+        ("UNIFI_HOSTNAME", &unifi.to_string()),
+        ("MQTT_PORT", &broker.port().to_string()),
+        ("MQTT_STATUS_OVERRIDE_TOPIC", "space/override"),
+        ("MQTT_DOOR_TOPIC", "space/door"),
+    ]);
+    let state = testing::state(config.clone()).await;
+    send(broker, "space/door", "closed").await;
+    send(broker, "space/override", "open").await;
+
+    let scanner = Scanner::new(
+        &config,
+        state.repo.clone(),
+        Default::default(),
+        Default::default(),
+    );
+    tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            let signals = *scanner.signals.lock().unwrap();
+            if signals.overridden.is_some() && signals.door.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("signals received");
+
+    // the override topic wins over the door switch
+    scanner.scan().await.unwrap();
+    let messages = published(broker, 4).await;
+    assert_eq!(messages["sensor/space/status"], "open");
+
+    // a status forced by an admin wins over everything else
+    state
+        .repo
+        .force_status(
+            &StatusOverride::new(false, "alice".to_string()),
+            Duration::from_secs(60),
+        )
+        .await
+        .unwrap();
+    scanner.scan().await.unwrap();
+    // the retained status of the previous scan may arrive first
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while published(broker, 4).await["sensor/space/status"] != "closed" {}
+    })
+    .await
+    .expect("status forced closed");
+}
+
+#[tokio::test]
+async fn scan_publishes_infrastructure_separately() {
+    let (unifi, _) = unifi(json!({
+        "data": [{ "mac": "00:11:22:00:00:01", "ip": "10.0.0.1" }]
+    }))
+    .await;
+    let broker = broker();
+    let config = testing::config(&[
+        ("UNIFI_SCHEME", "http"),
+        ("UNIFI_HOSTNAME", &unifi.to_string()),
+        ("MQTT_PORT", &broker.port().to_string()),
+    ]);
+    let state = testing::state(config.clone()).await;
+    let repo = state.repo.clone();
+    for (mac, name) in [
+        ("00:11:22:00:00:01", "printer"),
+        ("00:11:22:00:00:02", "ap"),
+    ] {
+        repo.create_infrastructure(&Infrastructure::new(
+            mac.to_string(),
+            name.to_string(),
+            String::new(),
+        ))
+        .await
+        .unwrap();
+    }
+
+    Scanner::new(
+        &config,
+        repo.clone(),
+        Default::default(),
+        Default::default(),
+    )
+    .scan()
+    .await
+    .unwrap();
+
+    // the always-on printer neither opens the space nor shows up unassigned
+    let messages = published(broker, 6).await;
+    assert_eq!(messages["sensor/space/status"], "closed");
+    assert_eq!(messages["sensor/space/member/deviceCount"], "0");
+    assert_eq!(messages["sensor/space/guest/count"], "0");
+    assert_eq!(messages["sensor/space/infrastructure/printer"], "online");
+    assert_eq!(messages["sensor/space/infrastructure/ap"], "offline");
+    assert!(repo.unassigned().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn scan_estimates_guests() {
+    let (unifi, _) = unifi(json!({
+        "data": [
+            { "mac": "00:11:22:00:00:01", "ip": "10.0.0.1" },
+            { "mac": "00:11:22:00:00:02", "ip": "10.0.0.2" },
+            { "mac": "00:11:22:00:00:03", "ip": "10.0.0.3" },
+            { "mac": "00:11:22:00:00:04", "ip": "10.0.0.4" },
+            { "mac": "02:00:00:00:00:01", "ip": "10.0.0.5" },
+            { "mac": "06:00:00:00:00:01", "ip": "10.0.0.6" },
+        ]
+    }))
+    .await;
+    let broker = broker();
+    let config = testing::config(&[
+        ("UNIFI_SCHEME", "http"),
+        ("UNIFI_HOSTNAME", &unifi.to_string()),
+        ("MQTT_PORT", &broker.port().to_string()),
+        ("STATUS_IGNORE", "00:11:22:00:00:03"),
+    ]);
+    let state = testing::state(config.clone()).await;
+    let repo = state.repo.clone();
+    repo.create_device(&device(
+        "00:11:22:00:00:01",
+        "alice",
+        PrivacyLevel::ShowUser,
+    ))
+    .await
+    .unwrap();
+    repo.create_infrastructure(&Infrastructure::new(
+        "00:11:22:00:00:04".to_string(),
+        "printer".to_string(),
+        String::new(),
+    ))
+    .await
+    .unwrap();
+    // earlier randomized addresses of the phones still using 10.0.0.5 and
+    // 10.0.0.2
+    repo.log_all(&[
+        AliveDevice::new("0a:00:00:00:00:01", "10.0.0.5").unwrap(),
+        AliveDevice::new("0e:00:00:00:00:01", "10.0.0.2").unwrap(),
+    ])
+    .await
+    .unwrap();
+
+    Scanner::new(
+        &config,
+        repo.clone(),
+        Default::default(),
+        Default::default(),
+    )
+    .scan()
+    .await
+    .unwrap();
+
+    // alice is a member, the space laptop is ignored and the printer is
+    // infrastructure, leaving the guest at .2 and the phones at .5 and .6
+    let messages = published(broker, 6).await;
+    assert_eq!(messages["sensor/space/member/present"], "1");
+    assert_eq!(messages["sensor/space/guest/count"], "3");
+}
+
+#[tokio::test]
+async fn dry_run_neither_records_nor_publishes() {
+    let (unifi, _) = unifi(json!({
+        "data": [
+            { "mac": "00:11:22:00:00:01", "ip": "10.0.0.1" },
+            { "mac": "00:11:22:00:00:02", "ip": "10.0.0.2" },
+            { "mac": "00:11:22:00:00:03", "ip": "10.0.0.3" },
+        ]
+    }))
+    .await;
+    let config = testing::config(&[
+        ("UNIFI_SCHEME", "http"),
+        ("UNIFI_HOSTNAME", &unifi.to_string()),
+        ("MQTT_PORT", &testing::free_addr().port().to_string()),
+    ]);
+    let state = testing::state(config.clone()).await;
+    let repo = state.repo.clone();
+    repo.create_device(&device(
+        "00:11:22:00:00:01",
+        "alice",
+        PrivacyLevel::ShowUser,
+    ))
+    .await
+    .unwrap();
+    repo.create_infrastructure(&Infrastructure::new(
+        "00:11:22:00:00:03".to_string(),
+        "printer".to_string(),
+        String::new(),
+    ))
+    .await
+    .unwrap();
+
+    let health = Arc::new(Health::default());
+    let scanner = Scanner::offline(&config, repo.clone(), Default::default(), health.clone());
+    let messages: HashMap<String, String> = scanner.dry_run().await.unwrap().into_iter().collect();
+
+    // the devices seen right now count, although they are not recorded
+    assert_eq!(messages["sensor/space/status"], "open");
+    assert_eq!(messages["sensor/space/member/names"], "alice");
+    assert_eq!(messages["sensor/space/guest/count"], "1");
+    assert_eq!(messages["sensor/space/infrastructure/printer"], "online");
+    assert!(repo.unassigned().await.unwrap().is_empty());
+    assert!(!repo.devices_for_user("alice").await.unwrap()[0].present);
+    assert!(scanner.subscribe().borrow().is_none());
+    // nothing connects to the broker
+    let readiness = health.readiness(Check::ok(), Duration::from_secs(60));
+    assert!(!readiness.tasks.contains_key("mqtt"));
+}
+
+#[tokio::test]
+async fn a_single_scan_is_published_before_returning() {
+    let (unifi, _) = unifi(json!({
+        "data": [{ "mac": "00:11:22:00:00:01", "ip": "10.0.0.1" }]
+    }))
+    .await;
+    let broker = broker();
+    let config = testing::config(&[
+        ("UNIFI_SCHEME", "http"),
+        ("UNIFI_HOSTNAME", &unifi.to_string()),
+        ("MQTT_PORT", &broker.port().to_string()),
+    ]);
+
+    // like `scan --once`, the runtime and its connection end right after
+    tokio::task::spawn_blocking(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let repo = db::connect("memory://", Default::default(), config.windows())
+                .await
+                .unwrap();
+            repo.create_device(&device(
+                "00:11:22:00:00:01",
+                "alice",
+                PrivacyLevel::ShowUser,
+            ))
+            .await
+            .unwrap();
+            let scanner = Scanner::new(&config, repo, Default::default(), Default::default());
+            scanner.scan_once().await.unwrap();
+        });
+    })
+    .await
+    .unwrap();
+
+    let messages = published(broker, 5).await;
+    assert_eq!(messages["sensor/space/status"], "open");
+    assert_eq!(messages["sensor/space/member/names"], "alice");
+}
+
+#[tokio::test]
+async fn only_the_lease_holder_scans() {
+    let config = testing::config(&[]);
+    let state = testing::state(config.clone()).await;
+    let scanner = |holder: &str, health| {
+        let mut scanner = Scanner::new(&config, state.repo.clone(), Default::default(), health);
+        scanner.holder = holder.to_string();
+        scanner
+    };
+    let active = scanner("a", Default::default());
+    let standby_health: Arc<crate::health::Health> = Default::default();
+    let standby = scanner("b", standby_health.clone());
+
+    assert!(active.lead().await);
+    assert!(!standby.lead().await);
+    let readiness = standby_health.readiness(Check::ok(), Duration::from_secs(60));
+    assert_eq!(readiness.scanner, ScannerState::Standby);
+    assert!(readiness.scan.is_none());
+
+    // the lease runs out as if the active instance had died
+    state.repo.acquire_lease("a", Duration::ZERO).await.unwrap();
+    assert!(standby.lead().await);
+    assert!(!active.lead().await);
+    let readiness = standby_health.readiness(Check::ok(), Duration::from_secs(60));
+    assert_eq!(readiness.scanner, ScannerState::Active);
+}
+
+#[tokio::test]
+async fn instances_on_the_same_host_hold_their_own_lease() {
+    let config = testing::config(&[]);
+    let state = testing::state(config.clone()).await;
+    let scanner = || {
+        Scanner::offline(
+            &config,
+            state.repo.clone(),
+            Default::default(),
+            Default::default(),
+        )
+    };
+    let (first, second) = (scanner(), scanner());
+
+    assert_ne!(first.holder, second.holder);
+    assert!(first.lead().await);
+    assert!(!second.lead().await);
+}
+
+#[tokio::test]
+async fn stopping_hands_over_the_lease() {
+    let broker = broker();
+    let config = testing::config(&[("MQTT_PORT", &broker.port().to_string())]);
+    let state = testing::state(config.clone()).await;
+    let scanner = Scanner::new(
+        &config,
+        state.repo.clone(),
+        Default::default(),
+        state.health.clone(),
+    );
+    assert!(scanner.lead().await);
+
+    scanner.stop().await;
+    // the space status, the member and guest counts and the scanner
+    let messages = published(broker, 5).await;
+    assert_eq!(messages["sensor/space/scanner"], "offline");
+    assert_eq!(messages["sensor/space/status"], "closed");
+    assert_eq!(messages["sensor/space/member/present"], "0");
+    assert!(
+        state
+            .repo
+            .acquire_lease("standby", Duration::from_secs(60))
+            .await
+            .unwrap()
+    );
+    let readiness = state.health.readiness(Check::ok(), Duration::from_secs(60));
+    assert_eq!(readiness.scanner, ScannerState::Disabled);
+}
+
+#[tokio::test]
+async fn reconfigured_settings_apply_to_the_next_scan() {
+    let (unifi, _) = unifi(json!({
+        "data": [
+            { "mac": "00:11:22:00:00:01", "ip": "10.0.0.1" },
+            { "mac": "00:11:22:00:00:04", "ip": "192.168.1.4" },
+        ]
+    }))
+    .await;
+    let broker = broker();
+    let settings = [
+        ("UNIFI_SCHEME", "http"),
+        ("UNIFI_HOSTNAME", &unifi.to_string()),
+        ("MQTT_PORT", &broker.port().to_string()),
+        ("ALLOWED_SUBNETS", "10.0.0.0/8"),
+    ];
+    let config = testing::config(&settings);
+    let state = testing::state(config.clone()).await;
+    let scanner = Scanner::new(
+        &config,
+        state.repo.clone(),
+        Default::default(),
+        Default::default(),
+    );
+    scanner.scan().await.unwrap();
+    assert_eq!(scanner.subscribe().borrow().as_ref().unwrap().guests, 1);
+
+    let mut reloaded = settings.to_vec();
+    reloaded.push(("ALLOWED_SUBNETS", "10.0.0.0/8, 192.168.0.0/16"));
+    reloaded.push(("MQTT_GUEST_COUNT_TOPIC", "sensor/space/guests"));
+    scanner.reconfigure(&testing::config(&reloaded));
+    scanner.scan().await.unwrap();
+    assert_eq!(scanner.subscribe().borrow().as_ref().unwrap().guests, 2);
+    // the names are empty, both guest count topics are retained
+    let messages = published(broker, 5).await;
+    assert_eq!(messages["sensor/space/guest/count"], "1");
+    assert_eq!(messages["sensor/space/guests"], "2");
+}
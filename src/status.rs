@@ -0,0 +1,161 @@
+//! Rules deciding whether the space is open.
+
+use crate::{Config, db, mac};
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpaceStatus {
+    Open,
+    Closed,
+}
+
+impl fmt::Display for SpaceStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpaceStatus::Open => write!(f, "open"),
+            SpaceStatus::Closed => write!(f, "closed"),
+        }
+    }
+}
+
+impl FromStr for SpaceStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "open" | "1" | "true" | "on" => Ok(SpaceStatus::Open),
+            "closed" | "0" | "false" | "off" => Ok(SpaceStatus::Closed),
+            _ => Err(anyhow!("unknown space status \"{}\"", s)),
+        }
+    }
+}
+
+impl From<&db::StatusOverride> for SpaceStatus {
+    fn from(forced: &db::StatusOverride) -> Self {
+        if forced.open {
+            SpaceStatus::Open
+        } else {
+            SpaceStatus::Closed
+        }
+    }
+}
+
+/// Latest values received on the optional override and door switch topics.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Signals {
+    pub overridden: Option<SpaceStatus>,
+    pub door: Option<SpaceStatus>,
+}
+
+/// Outcome of the latest scan as it is published over MQTT and served by
+/// the status API.
+#[derive(Clone, Debug, Serialize)]
+pub struct Report {
+    pub status: SpaceStatus,
+    pub members: usize,
+    pub names: Vec<String>,
+    pub devices: u64,
+    pub guests: usize,
+    pub updated: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Rules {
+    min_members: usize,
+    /// Normalized MAC addresses and lowercase nicknames.
+    ignored: Vec<String>,
+}
+
+impl Rules {
+    pub fn new(config: &Config) -> Self {
+        let ignored = config
+            .status_ignore
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| mac::normalize(entry).unwrap_or_else(|_| entry.to_lowercase()))
+            .collect();
+        Self {
+            min_members: config.status_min_members,
+            ignored,
+        }
+    }
+
+    /// Whether the device should not count towards the presence of members.
+    pub fn ignores(&self, device: &db::Device) -> bool {
+        self.ignored
+            .iter()
+            .any(|entry| *entry == device.macaddr || *entry == device.nickname.to_lowercase())
+    }
+
+    /// Estimates the number of guests from the addresses unregistered devices
+    /// used. Randomized MACs change while the device keeps its address, so
+    /// they count as the same guest as any other device sharing an address.
+    pub fn guests(&self, sightings: &[db::AliveDevice]) -> usize {
+        let sightings: Vec<&db::AliveDevice> = sightings
+            .iter()
+            .filter(|sighting| !self.ignored.contains(&sighting.macaddr))
+            .collect();
+        let mut macaddrs: Vec<&str> = sightings.iter().map(|s| s.macaddr.as_str()).collect();
+        macaddrs.sort_unstable();
+        macaddrs.dedup();
+        let index = |macaddr: &str| macaddrs.binary_search(&macaddr).unwrap();
+
+        let mut by_ip: HashMap<IpAddr, Vec<&db::AliveDevice>> = HashMap::new();
+        for sighting in &sightings {
+            by_ip.entry(sighting.ip()).or_default().push(sighting);
+        }
+
+        // union-find over the devices, each remaining set is one guest
+        let mut parent: Vec<usize> = (0..macaddrs.len()).collect();
+        fn root(parent: &mut [usize], mut i: usize) -> usize {
+            while parent[i] != i {
+                parent[i] = parent[parent[i]];
+                i = parent[i];
+            }
+            i
+        }
+        for shared in by_ip.values() {
+            let anchor = shared
+                .iter()
+                .find(|s| !s.randomized())
+                .unwrap_or(&shared[0]);
+            let anchor = root(&mut parent, index(&anchor.macaddr));
+            for sighting in shared.iter().filter(|s| s.randomized()) {
+                let other = root(&mut parent, index(&sighting.macaddr));
+                parent[other] = anchor;
+            }
+        }
+        (0..macaddrs.len())
+            .filter(|&i| root(&mut parent, i) == i)
+            .count()
+    }
+
+    /// A status forced by an admin wins over the override topic, which wins
+    /// over the door switch, which wins over the members present.
+    pub fn decide(
+        &self,
+        members: usize,
+        forced: Option<&db::StatusOverride>,
+        signals: Signals,
+    ) -> SpaceStatus {
+        if let Some(forced) = forced {
+            return forced.into();
+        }
+        if let Some(status) = signals.overridden.or(signals.door) {
+            return status;
+        }
+        if members > 0 && members >= self.min_members {
+            SpaceStatus::Open
+        } else {
+            SpaceStatus::Closed
+        }
+    }
+}
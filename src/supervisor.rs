@@ -0,0 +1,115 @@
+//! Background tasks that are restarted when they panic, and the signals that
+//! shut mac4nick down.
+
+use crate::health::Health;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+
+/// Exponentially growing delay between two attempts.
+#[derive(Debug)]
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Self {
+            min,
+            max,
+            current: min,
+        }
+    }
+
+    /// Returns the delay before the next attempt and doubles the one after.
+    pub fn next(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.current = self.min;
+    }
+}
+
+fn panic_message(panic: Box<dyn std::any::Any + Send>) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// Runs the task built by `task` and starts it again with backoff whenever
+/// it panics, until it returns. The task is aborted when the returned
+/// future is dropped.
+pub async fn supervise<F, T>(name: &'static str, health: Arc<Health>, mut task: F)
+where
+    F: FnMut() -> T,
+    T: Future<Output = ()> + Send + 'static,
+{
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+    loop {
+        let mut tasks = JoinSet::new();
+        tasks.spawn(task());
+        health.task_started(name);
+        let started = Instant::now();
+        let error = match tasks.join_next().await {
+            Some(Ok(())) | None => {
+                health.task_stopped(name);
+                return;
+            }
+            Some(Err(err)) if err.is_panic() => panic_message(err.into_panic()),
+            Some(Err(err)) => err.to_string(),
+        };
+
+        // a task that ran for a while before crashing starts over quickly
+        if started.elapsed() > backoff.max {
+            backoff.reset();
+        }
+        let delay = backoff.next();
+        tracing::error!(
+            "task {} crashed, restarting in {:?}: {}",
+            name,
+            delay,
+            error
+        );
+        health.task_crashed(name);
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Completes on SIGTERM or Ctrl+C.
+pub async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            tracing::error!("unable to listen for ctrl+c: {}", err);
+            std::future::pending::<()>().await;
+        }
+    };
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(err) => {
+                tracing::error!("unable to listen for SIGTERM: {}", err);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
+    tracing::info!("shutting down");
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,49 @@
+use super::*;
+use crate::health::Check;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[test]
+fn backoff_doubles_up_to_the_maximum() {
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+    let delays: Vec<u64> = (0..5).map(|_| backoff.next().as_secs()).collect();
+    assert_eq!(delays, vec![1, 2, 4, 5, 5]);
+    backoff.reset();
+    assert_eq!(backoff.next(), Duration::from_secs(1));
+}
+
+#[tokio::test]
+async fn crashed_tasks_are_restarted() {
+    let health = Arc::new(Health::default());
+    let runs = Arc::new(AtomicUsize::new(0));
+    let counter = runs.clone();
+    supervise("flaky", health.clone(), move || {
+        let counter = counter.clone();
+        async move {
+            if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                panic!("first run fails");
+            }
+        }
+    })
+    .await;
+    assert_eq!(runs.load(Ordering::SeqCst), 2);
+    // a task that returned is no longer reported
+    let readiness = health.readiness(Check::ok(), Duration::from_secs(60));
+    assert!(readiness.tasks.is_empty());
+}
+
+#[tokio::test]
+async fn crashed_tasks_make_the_instance_unready() {
+    let health = Arc::new(Health::default());
+    let job = tokio::spawn(supervise("broken", health.clone(), || async {
+        panic!("always fails")
+    }));
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let readiness = health.readiness(Check::ok(), Duration::from_secs(60));
+    assert!(!readiness.ready);
+    let task = &readiness.tasks["broken"];
+    assert!(!task.running);
+    assert_eq!(task.restarts, 1);
+    assert_eq!(task.last_panic.as_deref(), Some("task panicked"));
+    job.abort();
+}
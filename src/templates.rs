@@ -1,5 +1,5 @@
-use crate::db;
 use crate::AppMessage;
+use crate::db;
 use askama::Template;
 
 #[derive(Template, Default)]
@@ -7,22 +7,103 @@ use askama::Template;
 pub struct IndexTemplate {
     nickname: String,
     my: Vec<db::Device>,
-    unassinged: Vec<db::AliveDevice>,
+    unassigned: Vec<db::AliveDevice>,
+    claims: Vec<db::Claim>,
+    claim_mode: bool,
+    current: Option<db::AliveDevice>,
+    current_registered: bool,
+    current_hint: String,
+    is_admin: bool,
+    forced: Option<db::StatusOverride>,
+    infrastructure: Vec<db::Infrastructure>,
+    departed: Vec<db::Device>,
     messages: Vec<AppMessage>,
+    csrf: String,
+    logout: bool,
 }
 
 impl IndexTemplate {
     pub fn new(
         nickname: String,
         my: Vec<db::Device>,
-        unassinged: Vec<db::AliveDevice>,
+        unassigned: Vec<db::AliveDevice>,
         messages: Vec<AppMessage>,
     ) -> Self {
         Self {
             nickname,
             my,
-            unassinged,
+            unassigned,
             messages,
+            ..Default::default()
         }
     }
+
+    /// Sets the token every form has to send along.
+    pub fn with_csrf(mut self, csrf: String) -> Self {
+        self.csrf = csrf;
+        self
+    }
+
+    /// Offers to log out, when mac4nick handles the login itself.
+    pub fn with_logout(mut self, logout: bool) -> Self {
+        self.logout = logout;
+        self
+    }
+
+    /// Shows the claim flow with the pending claims instead of the list of
+    /// unassigned devices.
+    pub fn with_claims(mut self, claims: Vec<db::Claim>, claim_mode: bool) -> Self {
+        self.claims = claims;
+        self.claim_mode = claim_mode;
+        self
+    }
+
+    /// Sets the device the page is viewed on, `hint` prefills its description.
+    pub fn with_current(
+        mut self,
+        current: Option<db::AliveDevice>,
+        registered: bool,
+        hint: String,
+    ) -> Self {
+        self.current = current;
+        self.current_registered = registered;
+        self.current_hint = hint;
+        self
+    }
+
+    /// Shows admins the controls to force the space status and to manage
+    /// the infrastructure.
+    pub fn with_admin(
+        mut self,
+        is_admin: bool,
+        forced: Option<db::StatusOverride>,
+        infrastructure: Vec<db::Infrastructure>,
+    ) -> Self {
+        self.is_admin = is_admin;
+        self.forced = forced;
+        self.infrastructure = infrastructure;
+        self
+    }
+
+    /// Lists admins the devices of members who left the members group.
+    pub fn with_departed(mut self, departed: Vec<db::Device>) -> Self {
+        self.departed = departed;
+        self
+    }
+
+    /// Whether the given address belongs to the device this page is viewed on.
+    fn is_current(&self, macaddr: &str) -> bool {
+        self.current
+            .as_ref()
+            .is_some_and(|current| current.macaddr == macaddr)
+    }
+
+    /// The device this page is viewed on, if nobody has registered it yet.
+    fn claimable(&self) -> Option<&db::AliveDevice> {
+        self.current.as_ref().filter(|_| !self.current_registered)
+    }
+
+    fn has_randomized(&self) -> bool {
+        self.my.iter().any(|d| d.randomized()) || self.unassigned.iter().any(|d| d.randomized())
+    }
 }